@@ -5,7 +5,9 @@ use anyhow::Result;
 use criterion::*;
 use glob::{glob_with, MatchOptions};
 use img_parts::jpeg::Jpeg;
-use thermal::{dji::RJpeg, ThermalImage};
+#[cfg(feature = "dji")]
+use thermal::dji::RJpeg;
+use thermal::{temperature::apply_lut, ThermalImage};
 
 pub struct Samples<T>(Vec<T>);
 impl<T> Samples<T> {
@@ -45,6 +47,7 @@ fn temperature(c: &mut Criterion) {
         })
     });
 
+    #[cfg(feature = "dji")]
     c.bench_function("dji_parse", |b| {
         let samples = get_samples("DJI_SAMPLES").expect("samples");
         b.iter(|| {
@@ -64,10 +67,42 @@ fn temperature(c: &mut Criterion) {
     });
 }
 
+/// Compare the ways of converting a whole image to
+/// temperatures, as a guardrail when optimizing the
+/// per-pixel math.
+fn conversion(c: &mut Criterion) {
+    let samples = get_samples("FLIR_SAMPLES").expect("samples");
+    let image = ThermalImage::try_from_rjpeg_path(&samples[0]).unwrap();
+    let settings = &image.settings;
+
+    let mut group = c.benchmark_group("temperature_conversion");
+    group.throughput(Throughput::Elements(image.image.len() as u64));
+
+    group.bench_function("temperatures", |b| {
+        b.iter(|| settings.temperatures(1.0, &image.image))
+    });
+
+    group.bench_function("temperatures_par", |b| {
+        b.iter(|| settings.temperatures_par(1.0, &image.image))
+    });
+
+    group.bench_function("lut", |b| {
+        b.iter(|| apply_lut(&settings.build_lut(1.0), &image.image))
+    });
+
+    let lut = settings.build_lut(1.0);
+    group.bench_function("lut_prebuilt", |b| b.iter(|| apply_lut(&lut, &image.image)));
+
+    group.bench_function("planck_only", |b| {
+        b.iter(|| image.image.mapv(settings.planck_only_transform()))
+    });
+    group.finish();
+}
+
 criterion_group! {
     name = parsing;
     config = Criterion::default().sample_size(10);
-    targets = temperature
+    targets = temperature, conversion
 }
 
 criterion_main!(parsing);
@@ -0,0 +1,57 @@
+//! Survey the FLIR record types present across a set of
+//! R-JPEGs, to find out what a new camera writes.
+//!
+//! Usage: `cargo run --example flir_records -- 'dataset/**/*.jpg'`
+//!
+//! Prints the number of records of each `(type, sub-type,
+//! version)`, and the largest record length seen.
+use std::{collections::BTreeMap, env, fs, path::Path};
+
+use anyhow::Result;
+use glob::{glob_with, MatchOptions};
+use img_parts::jpeg::Jpeg;
+use thermal::flir::FlirSegment;
+
+fn parse_segment(path: &Path) -> Result<FlirSegment> {
+    let image = Jpeg::from_bytes(fs::read(path)?.into())?;
+    FlirSegment::try_from_jpeg(&image)
+}
+
+fn main() -> Result<()> {
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+
+    let mut counts: BTreeMap<(u16, u16, u32), (usize, usize)> = BTreeMap::new();
+    let (mut files, mut failures) = (0, 0);
+    for pattern in env::args().skip(1) {
+        for path in glob_with(&pattern, options)? {
+            let path = path?;
+            files += 1;
+            match parse_segment(&path) {
+                Ok(segment) => {
+                    for (ty, sub_type, version, length) in segment.record_summary() {
+                        let entry = counts.entry((ty, sub_type, version)).or_default();
+                        entry.0 += 1;
+                        entry.1 = entry.1.max(length);
+                    }
+                }
+                Err(e) => {
+                    eprintln!("{}: {:#}", path.display(), e);
+                    failures += 1;
+                }
+            }
+        }
+    }
+
+    println!("type\tsub-type\tversion\tcount\tmax length");
+    for ((ty, sub_type, version), (count, max_length)) in counts {
+        println!(
+            "{:#06x}\t{}\t{}\t{}\t{}",
+            ty, sub_type, version, count, max_length
+        );
+    }
+    eprintln!("Surveyed {} files ({} failed)", files, failures);
+    Ok(())
+}
@@ -0,0 +1,40 @@
+#!/usr/bin/env python3
+
+# Reference values for the `raw_to_temp` tests in
+# `src/temperature.rs`: a line by line transcription of
+# `raw2temp` from the Thermimage R library
+# (https://github.com/gtatters/Thermimage/blob/master/R/raw2temp.R),
+# kept independent of the crate code.
+
+from math import exp, sqrt, log
+def raw2temp(raw, E=1, OD=1, RTemp=20, ATemp=None, IRWTemp=None, IRT=1, RH=50,
+             PR1=21106.77, PB=1501, PF=1, PO=-7340, PR2=0.012545258,
+             ATA1=0.006569, ATA2=0.01262, ATB1=-0.002276, ATB2=-0.00667, ATX=1.9):
+    if ATemp is None: ATemp = RTemp
+    if IRWTemp is None: IRWTemp = RTemp
+    emiss_wind = 1 - IRT
+    refl_wind = 0
+    h2o = (RH/100)*exp(1.5587+0.06939*(ATemp)-0.00027816*(ATemp)**2+0.00000068455*(ATemp)**3)
+    tau1 = ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
+    tau2 = ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
+    raw_refl1 = PR1/(PR2*(exp(PB/(RTemp+273.15))-PF))-PO
+    raw_refl1_attn = (1-E)/E*raw_refl1
+    raw_atm1 = PR1/(PR2*(exp(PB/(ATemp+273.15))-PF))-PO
+    raw_atm1_attn = (1-tau1)/E/tau1*raw_atm1
+    raw_wind = PR1/(PR2*(exp(PB/(IRWTemp+273.15))-PF))-PO
+    raw_wind_attn = emiss_wind/E/tau1/IRT*raw_wind
+    raw_refl2 = PR1/(PR2*(exp(PB/(RTemp+273.15))-PF))-PO
+    raw_refl2_attn = refl_wind/E/tau1/IRT*raw_refl2
+    raw_atm2 = PR1/(PR2*(exp(PB/(ATemp+273.15))-PF))-PO
+    raw_atm2_attn = (1-tau2)/E/tau1/IRT/tau2*raw_atm2
+    raw_obj = (raw/E/tau1/IRT/tau2-raw_atm1_attn-raw_atm2_attn-raw_wind_attn-raw_refl1_attn-raw_refl2_attn)
+    return PB/log(PR1/(PR2*(raw_obj+PO))+PF)-273.15
+
+if __name__ == '__main__':
+    raws = [12000, 15000, 17000, 18109, 20000, 25000]
+    print('defaults, OD=1:', [round(raw2temp(r), 6) for r in raws])
+
+    kw = dict(E=0.95, OD=10, RTemp=-5, ATemp=25, IRWTemp=30, IRT=0.9, RH=70,
+              PR1=17096.453, PB=1428, PF=1, PO=-321, PR2=0.046642166)
+    raws = [2000, 5000, 8000, 12000, 16000]
+    print('custom, OD=10:', [round(raw2temp(r, **kw), 6) for r in raws])
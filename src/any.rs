@@ -0,0 +1,314 @@
+//! Load thermal images from any of the supported cameras.
+//!
+//! [`load_any`] parses an image without knowing the camera
+//! upfront: it tries to parse it as a FLIR R-JPEG, and then
+//! (with the `dji` feature) as a DJI R-JPEG.
+//!
+//! ```rust
+//! # fn test_compile() -> anyhow::Result<()> {
+//! use thermal::any::{load_any, GenericImage};
+//!
+//! match load_any("image.jpg")? {
+//!     GenericImage::Flir(image) => { /* ... */ }
+//!     #[cfg(feature = "dji")]
+//!     GenericImage::Dji(image) => { /* ... */ }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+use std::{convert::TryFrom, path::Path};
+
+use anyhow::{ensure, Context, Result};
+use ndarray::Array2;
+
+#[cfg(feature = "dji")]
+use crate::dji::RJpeg;
+use crate::{
+    image::{Dimensions, GpsCoordinates},
+    ThermalImage,
+};
+
+/// A parsed thermal image from one of the supported
+/// cameras. DJI images are only supported with the `dji`
+/// feature.
+// There is one value per image, so the size difference
+// between the variants does not matter.
+#[allow(clippy::large_enum_variant)]
+pub enum GenericImage {
+    Flir(ThermalImage),
+    #[cfg(feature = "dji")]
+    Dji(RJpeg),
+}
+
+impl From<ThermalImage> for GenericImage {
+    fn from(image: ThermalImage) -> Self {
+        GenericImage::Flir(image)
+    }
+}
+
+impl GenericImage {
+    /// The FLIR image, if this is one.
+    pub fn as_flir(&self) -> Option<&ThermalImage> {
+        match self {
+            GenericImage::Flir(image) => Some(image),
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(_) => None,
+        }
+    }
+}
+
+#[cfg(feature = "dji")]
+impl From<RJpeg> for GenericImage {
+    fn from(image: RJpeg) -> Self {
+        GenericImage::Dji(image)
+    }
+}
+
+/// Operations common to the thermal images from all the
+/// supported cameras, to write camera-agnostic code.
+pub trait RadiometricImage {
+    /// Temperatures in celicius as a `(height, width)`
+    /// array. The `distance` (in meters), if given, overrides
+    /// the one recorded in the image.
+    ///
+    /// DJI images are always converted with their recorded
+    /// measurement params, and ignore `distance`; use
+    /// `RJpeg::set_measurement_params` to change them.
+    fn temperatures(&self, distance: Option<f64>) -> Result<Array2<f64>>;
+
+    /// Temperatures in celicius of the pixels at `indices`
+    /// into the row-major `(height, width)` array; see
+    /// [`temperatures`][RadiometricImage::temperatures] for
+    /// the `distance`. Useful for approximate stats over a
+    /// sample of the pixels: FLIR images only convert the
+    /// given pixels, while DJI images convert all of them.
+    fn temperatures_at(&self, distance: Option<f64>, indices: &[usize]) -> Result<Vec<f64>> {
+        pick_pixels(&self.temperatures(distance)?, indices)
+    }
+
+    /// Dimensions of the image.
+    fn dimensions(&self) -> Result<Dimensions>;
+
+    /// Position of the camera, if recorded in (and parsed
+    /// from) the image. DJI images are not parsed for it.
+    fn gps(&self) -> Option<GpsCoordinates> {
+        None
+    }
+
+    /// Transmission through the atmosphere at the `distance`
+    /// (or the recorded one); see
+    /// [`ThermalSettings::atmospheric_transmission`][crate::temperature::ThermalSettings::atmospheric_transmission].
+    /// Not available for DJI images.
+    fn atmospheric_transmission(&self, _distance: Option<f64>) -> Option<f64> {
+        None
+    }
+}
+
+impl RadiometricImage for ThermalImage {
+    fn temperatures(&self, distance: Option<f64>) -> Result<Array2<f64>> {
+        let distance = self.effective_distance(distance);
+        Ok(self.settings.temperatures(distance, &self.image))
+    }
+
+    fn temperatures_at(&self, distance: Option<f64>, indices: &[usize]) -> Result<Vec<f64>> {
+        let distance = self.effective_distance(distance);
+        let temp_t = self.settings.temperature_transform(distance);
+        let raw = pick_pixels(&self.image, indices)?;
+        Ok(raw.into_iter().map(temp_t).collect())
+    }
+
+    fn dimensions(&self) -> Result<Dimensions> {
+        Ok(ThermalImage::dimensions(self))
+    }
+
+    fn gps(&self) -> Option<GpsCoordinates> {
+        self.gps
+    }
+
+    fn atmospheric_transmission(&self, distance: Option<f64>) -> Option<f64> {
+        let distance = self.effective_distance(distance);
+        Some(self.settings.atmospheric_transmission(distance))
+    }
+}
+
+#[cfg(feature = "dji")]
+impl RadiometricImage for RJpeg {
+    /// The `distance` is ignored; see
+    /// [`RJpeg::temperatures_f64`].
+    fn temperatures(&self, _distance: Option<f64>) -> Result<Array2<f64>> {
+        self.temperatures_f64()
+    }
+
+    fn dimensions(&self) -> Result<Dimensions> {
+        RJpeg::dimensions(self)
+    }
+}
+
+impl RadiometricImage for GenericImage {
+    fn temperatures(&self, distance: Option<f64>) -> Result<Array2<f64>> {
+        match self {
+            GenericImage::Flir(image) => image.temperatures(distance),
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(image) => RadiometricImage::temperatures(image, distance),
+        }
+    }
+
+    fn temperatures_at(&self, distance: Option<f64>, indices: &[usize]) -> Result<Vec<f64>> {
+        match self {
+            GenericImage::Flir(image) => image.temperatures_at(distance, indices),
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(image) => image.temperatures_at(distance, indices),
+        }
+    }
+
+    fn dimensions(&self) -> Result<Dimensions> {
+        match self {
+            GenericImage::Flir(image) => Ok(image.dimensions()),
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(image) => RadiometricImage::dimensions(image),
+        }
+    }
+
+    fn gps(&self) -> Option<GpsCoordinates> {
+        match self {
+            GenericImage::Flir(image) => image.gps,
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(image) => RadiometricImage::gps(image),
+        }
+    }
+
+    fn atmospheric_transmission(&self, distance: Option<f64>) -> Option<f64> {
+        match self {
+            GenericImage::Flir(image) => image.atmospheric_transmission(distance),
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(image) => image.atmospheric_transmission(distance),
+        }
+    }
+}
+
+/// The values at `indices` into the row-major `array`.
+fn pick_pixels(array: &Array2<f64>, indices: &[usize]) -> Result<Vec<f64>> {
+    let (height, width) = array.dim();
+    indices
+        .iter()
+        .map(|&idx| {
+            ensure!(
+                idx < width * height,
+                "pixel index out of bounds: {} >= {}",
+                idx,
+                width * height
+            );
+            Ok(array[(idx / width, idx % width)])
+        })
+        .collect()
+}
+
+/// Temperatures of a thermal image from any of the
+/// supported cameras, to write camera-agnostic analysis.
+#[derive(Debug, Clone)]
+pub struct TemperatureImage {
+    /// Temperatures in celicius as a `(height, width)`
+    /// array.
+    pub temperatures: Array2<f64>,
+    /// Position of the camera, if recorded.
+    pub gps: Option<GpsCoordinates>,
+}
+
+impl TemperatureImage {
+    /// Compute the temperatures of `image`; see
+    /// [`RadiometricImage::temperatures`] for the handling of
+    /// the `distance`.
+    pub fn from_image<I: RadiometricImage + ?Sized>(
+        image: &I,
+        distance: Option<f64>,
+    ) -> Result<Self> {
+        Ok(TemperatureImage {
+            temperatures: image.temperatures(distance)?,
+            gps: image.gps(),
+        })
+    }
+
+    pub fn dimensions(&self) -> Dimensions {
+        Dimensions::from_shape(self.temperatures.dim())
+    }
+}
+
+impl TryFrom<&ThermalImage> for TemperatureImage {
+    type Error = anyhow::Error;
+
+    fn try_from(image: &ThermalImage) -> Result<Self> {
+        Self::from_image(image, None)
+    }
+}
+
+#[cfg(feature = "dji")]
+impl TryFrom<&RJpeg> for TemperatureImage {
+    type Error = anyhow::Error;
+
+    fn try_from(image: &RJpeg) -> Result<Self> {
+        Self::from_image(image, None)
+    }
+}
+
+impl TryFrom<&GenericImage> for TemperatureImage {
+    type Error = anyhow::Error;
+
+    fn try_from(image: &GenericImage) -> Result<Self> {
+        Self::from_image(image, None)
+    }
+}
+
+/// Parse the thermal image at `path`: first as a FLIR
+/// R-JPEG, then (with the `dji` feature) as a DJI R-JPEG.
+/// The error is that of the last attempt.
+///
+/// With the `mmap` feature, FLIR images are parsed using
+/// `ThermalImage::try_from_rjpeg_mmap`.
+pub fn load_any<P: AsRef<Path>>(path: P) -> Result<GenericImage> {
+    let path = path.as_ref();
+
+    #[cfg(feature = "mmap")]
+    let image = ThermalImage::try_from_rjpeg_mmap(path).map(GenericImage::from);
+    #[cfg(not(feature = "mmap"))]
+    let image = ThermalImage::try_from_rjpeg_path(path).map(GenericImage::from);
+
+    #[cfg(feature = "dji")]
+    let image = image
+        .or_else(|_| RJpeg::try_from_path(path).map(GenericImage::from))
+        .context("could not parse thermal image: tried FLIR, DJI");
+    #[cfg(not(feature = "dji"))]
+    let image = image.context("could not parse thermal image: tried FLIR");
+
+    image
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{GenericImage, RadiometricImage, TemperatureImage};
+    use crate::image::tests::sample_image;
+    use std::convert::TryFrom;
+
+    #[test]
+    fn temperature_image() {
+        let image = sample_image();
+        let temps = TemperatureImage::try_from(&image).unwrap();
+        assert_eq!(
+            temps.temperatures,
+            RadiometricImage::temperatures(&image, None).unwrap()
+        );
+        assert_eq!(temps.dimensions(), image.dimensions());
+        assert_eq!(temps.gps.unwrap().latitude, 12.5);
+
+        let at_distance = TemperatureImage::from_image(&image, Some(50.)).unwrap();
+        assert_eq!(
+            at_distance.temperatures,
+            RadiometricImage::temperatures(&image, Some(50.)).unwrap()
+        );
+        assert_ne!(at_distance.temperatures, temps.temperatures);
+
+        let generic = GenericImage::from(image);
+        let from_generic = TemperatureImage::try_from(&generic).unwrap();
+        assert_eq!(from_generic.temperatures, temps.temperatures);
+        assert_eq!(from_generic.dimensions(), temps.dimensions());
+    }
+}
@@ -0,0 +1,86 @@
+use anyhow::{bail, Error, Result};
+use clap::value_t_or_exit;
+use std::{path::PathBuf, str::FromStr};
+use thermal::{arg, args_parser, cli::CommonArgs, opt};
+
+pub struct Args {
+    pub common: CommonArgs,
+    pub path: PathBuf,
+    pub output: PathBuf,
+    pub format: Format,
+    pub distance: Option<f64>,
+}
+
+/// Output format of the frames.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Tiff,
+    Npy,
+}
+
+impl Format {
+    pub fn extension(self) -> &'static str {
+        match self {
+            Format::Tiff => "tif",
+            Format::Npy => "npy",
+        }
+    }
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "tiff" => Format::Tiff,
+            "npy" => Format::Npy,
+            _ => bail!("unknown format: {}", s),
+        })
+    }
+}
+
+impl Args {
+    pub fn from_cmd_line() -> Result<Args> {
+        let matches =
+            args_parser!("thermal-seq")
+                .about("Convert the frames of a FLIR radiometric sequence (.seq) to temperatures.")
+                .arg(
+                    opt!("output")
+                        .short("o")
+                        .required(true)
+                        .help("Directory to write the frames to (created if missing)"),
+                )
+                .arg(
+                    opt!("format")
+                        .short("f")
+                        .possible_values(&["tiff", "npy"])
+                        .default_value("tiff")
+                        .help(
+                            "Output format of the temperatures (in celsius) of each frame: 32-bit \
+                         float TIFF, or npy array",
+                        ),
+                )
+                .arg(opt!("distance").short("d").help(
+                    "Distance to use for calculation.  Default is the recorded distance, or 1.0",
+                ))
+                .args(&CommonArgs::args())
+                .arg(arg!("path").required(true).help("Path to the .seq file"))
+                .get_matches();
+
+        let common = CommonArgs::from_matches(&matches);
+        let path = value_t_or_exit!(matches.value_of("path"), PathBuf);
+        let output = value_t_or_exit!(matches.value_of("output"), PathBuf);
+        let format = value_t_or_exit!(matches, "format", Format);
+        let distance = matches
+            .is_present("distance")
+            .then(|| value_t_or_exit!(matches.value_of("distance"), f64));
+
+        Ok(Args {
+            common,
+            path,
+            output,
+            format,
+            distance,
+        })
+    }
+}
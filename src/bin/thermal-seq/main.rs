@@ -0,0 +1,86 @@
+mod args;
+
+use anyhow::Result;
+use args::{Args, Format};
+use indicatif::{ParallelProgressIterator, ProgressBar, ProgressDrawTarget};
+use ndarray::Array2;
+use ndarray_npy::write_npy;
+use std::{
+    fs::{create_dir_all, File},
+    io::BufWriter,
+    path::Path,
+};
+use thermal::{
+    cli::{write_float_tiff, BatchResults, SanityWarnings},
+    seq::SeqFile,
+};
+
+fn main() -> Result<()> {
+    let args = Args::from_cmd_line()?;
+    let code = args.common.thread_pool()?.install(|| run(args))?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Returns the exit status; see [`BatchResults::exit_code`].
+fn run(args: Args) -> Result<i32> {
+    use rayon::prelude::*;
+
+    let Args {
+        common,
+        path,
+        output,
+        format,
+        distance,
+    } = args;
+
+    let seq = SeqFile::try_from_path(&path)?;
+    match seq.frame(0) {
+        Ok(frame) => {
+            let (ht, wid) = frame.image.dim();
+            eprintln!("{} frames of {}x{}", seq.len(), wid, ht);
+        }
+        Err(_) => eprintln!("{} frames", seq.len()),
+    }
+    create_dir_all(&output)?;
+
+    let bar = if common.quiet || ProgressDrawTarget::stderr().is_hidden() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(seq.len() as u64)
+    };
+    let warnings = SanityWarnings::default();
+    let results = (0..seq.len())
+        .into_par_iter()
+        .progress_with(bar)
+        .map(|idx| {
+            let name = format!("frame_{:05}.{}", idx + 1, format.extension());
+            let res = seq.frame(idx).and_then(|frame| {
+                warnings.check(&name, &frame.settings);
+                let distance = frame.effective_distance(distance);
+                let temps = frame.settings.temperatures(distance, &frame.image);
+                write_frame(&temps, &output.join(&name), format)
+            });
+            (name, res)
+        })
+        .collect();
+
+    let batch = BatchResults::new(results);
+    batch.report(common.error_json.as_deref())?;
+    eprintln!(
+        "Wrote {} frames to {}",
+        batch.values.len(),
+        output.display()
+    );
+    Ok(batch.exit_code())
+}
+
+fn write_frame(temps: &Array2<f64>, path: &Path, format: Format) -> Result<()> {
+    match format {
+        Format::Tiff => write_float_tiff(temps, BufWriter::new(File::create(path)?))?,
+        Format::Npy => write_npy(path, temps)?,
+    }
+    Ok(())
+}
@@ -1,51 +1,164 @@
-use anyhow::Result;
+use anyhow::{bail, Error, Result};
 use clap::value_t_or_exit;
-use thermal::{arg, args_parser, opt};
+use std::{path::PathBuf, str::FromStr};
+use thermal::{
+    arg, args_parser,
+    cli::{expand_paths, CommonArgs, DistanceTable},
+    opt,
+    temperature::TemperatureUnit,
+};
 
 pub struct Args {
+    pub common: CommonArgs,
     pub paths: Vec<String>,
-    pub distance: f64,
+    pub distance: Option<f64>,
+    pub distances: Option<DistanceTable>,
     pub is_json: bool,
+    pub format: Format,
+    pub unit: TemperatureUnit,
+    pub output: Option<PathBuf>,
+    pub cache_dir: Option<PathBuf>,
+    pub par_pixels: bool,
+    /// Number of pixels to sample per image, if sampling.
+    pub sample: Option<usize>,
+}
+
+/// Output format of the stats.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Format {
+    Json,
+    Csv,
+    GeoJson,
+}
+
+impl FromStr for Format {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s {
+            "json" => Format::Json,
+            "csv" => Format::Csv,
+            "geojson" => Format::GeoJson,
+            _ => bail!("unknown format: {}", s),
+        })
+    }
 }
 
 impl Args {
     pub fn from_cmd_line() -> Result<Args> {
-        let matches = args_parser!("thermal-stats")
-            .about("Compute temperature stats from image.")
-            .arg(
-                opt!("json")
-                    .short("j")
-                    .takes_value(false)
-                    .help("Paths are jsons created using exiftool (default: paths are rjpegs)"),
-            )
-            .arg(
-                opt!("distance")
-                    .short("d")
-                    .help("Distance to use for calculation.  Default is 1.0"),
-            )
-            .arg(
-                arg!("paths")
-                    .required(true)
-                    .multiple(true)
-                    .help("Image / json paths"),
-            )
-            .get_matches();
-
-        let paths = matches
-            .values_of("paths")
-            .unwrap()
-            .map(|f| f.into())
-            .collect();
+        let matches =
+            args_parser!("thermal-stats")
+                .about("Compute temperature stats from image.")
+                .arg(
+                    opt!("json")
+                        .short("j")
+                        .takes_value(false)
+                        .help("Paths are jsons created using exiftool (default: paths are rjpegs)"),
+                )
+                .arg(opt!("distance").short("d").help(
+                    "Distance to use for calculation.  Default is the recorded distance, or 1.0",
+                ))
+                .arg(opt!("distance csv").help(
+                    "CSV of `filename,distance` rows with per-image distances.  Images not \
+                     listed use the --distance, or the default",
+                ))
+                .arg(
+                    opt!("format")
+                        .short("f")
+                        .possible_values(&["json", "csv", "geojson"])
+                        .default_value("json")
+                        .help(
+                            "Output format.  `geojson` writes a point per image located by \
+                             its GPS position; images without one are skipped",
+                        ),
+                )
+                .arg(
+                    opt!("unit")
+                        .short("u")
+                        .possible_values(&["c", "f", "k"])
+                        .default_value("c")
+                        .help("Unit of the temperatures: celsius, fahrenheit or kelvin"),
+                )
+                .arg(
+                    opt!("output")
+                        .short("o")
+                        .help("Write the stats to this file (default: stdout)"),
+                )
+                .arg(opt!("cache dir").help(
+                    "Directory to cache the stats of each image in, keyed by the file contents \
+                     (default: $XDG_CACHE_HOME/thermal-stats or ~/.cache/thermal-stats).  Not \
+                     used with --json",
+                ))
+                .arg(
+                    opt!("no cache")
+                        .takes_value(false)
+                        .conflicts_with("cache dir")
+                        .help("Do not read or write the stats cache"),
+                )
+                .arg(opt!("par pixels").takes_value(false).help(
+                    "Also compute the stats of each image in parallel.  Faster for a few large \
+                     images",
+                ))
+                .arg(opt!("sample").help(
+                    "Compute approximate stats from this many pseudo-random pixels of each \
+                     image (the same pixels in every run).  Much faster for large images, \
+                     while the mean and standard deviation are usually within a few percent; \
+                     the min and max may miss extreme pixels.  Not cached",
+                ))
+                .args(&CommonArgs::args())
+                .arg(
+                    arg!("paths")
+                        .required(true)
+                        .multiple(true)
+                        .help("Image / json paths, directories or glob patterns"),
+                )
+                .get_matches();
+
         let distance = matches
             .is_present("distance")
-            .then(|| value_t_or_exit!(matches.value_of("distance"), f64))
-            .unwrap_or(1.0);
+            .then(|| value_t_or_exit!(matches.value_of("distance"), f64));
+        let distances = matches
+            .value_of("distance csv")
+            .map(DistanceTable::from_csv_path)
+            .transpose()?;
+        let common = CommonArgs::from_matches(&matches);
         let is_json = matches.is_present("json");
+        let par_pixels = matches.is_present("par pixels");
+        let sample = matches
+            .is_present("sample")
+            .then(|| value_t_or_exit!(matches.value_of("sample"), usize));
+        if sample == Some(0) {
+            bail!("--sample must be positive");
+        }
+        let paths = expand_paths(matches.values_of("paths").unwrap(), is_json)?;
+        let format = value_t_or_exit!(matches, "format", Format);
+        let unit = value_t_or_exit!(matches, "unit", TemperatureUnit);
+        let output = matches
+            .is_present("output")
+            .then(|| value_t_or_exit!(matches.value_of("output"), PathBuf));
+
+        // Sampled stats differ from the full ones.
+        let cache_dir = if matches.is_present("no cache") || sample.is_some() {
+            None
+        } else {
+            matches
+                .value_of("cache dir")
+                .map(PathBuf::from)
+                .or_else(crate::cache::StatsCache::default_dir)
+        };
 
         Ok(Args {
+            common,
             paths,
             distance,
+            distances,
             is_json,
+            format,
+            unit,
+            output,
+            cache_dir,
+            par_pixels,
+            sample,
         })
     }
 }
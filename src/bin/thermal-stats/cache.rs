@@ -0,0 +1,123 @@
+//! On-disk cache of the stats of each image, keyed by the
+//! contents of the image file.
+//!
+//! The key is the BLAKE3 hash of the file, along with the
+//! distance and unit used. Each entry also records the full
+//! key, the version of this crate and the
+//! [`SCHEMA_VERSION`], which are checked when reading it: a
+//! stale entry is a miss. The sanity warnings
+//! of the image are stored with the stats, so that they are
+//! reported on hits too.
+use anyhow::Result;
+use serde_derive::*;
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+use thermal::temperature::TemperatureUnit;
+
+use crate::ImageStats;
+
+/// Version of the format of the entries, including the
+/// [`ImageStats`]. Bump it whenever either changes, so that
+/// entries written before are misses instead of being
+/// served without the new fields.
+///
+/// - 2: added `atmospheric_transmission` to the stats.
+const SCHEMA_VERSION: u32 = 2;
+
+pub struct StatsCache {
+    dir: PathBuf,
+}
+
+/// Identifies the stats of an image file.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CacheKey {
+    hash: String,
+    distance: Option<f64>,
+    unit: TemperatureUnit,
+}
+
+#[derive(Serialize, Deserialize)]
+struct Entry {
+    version: String,
+    schema: u32,
+    key: CacheKey,
+    stats: ImageStats,
+    warnings: Vec<String>,
+}
+
+impl StatsCache {
+    pub fn new(dir: PathBuf) -> Result<Self> {
+        fs::create_dir_all(&dir)?;
+        Ok(StatsCache { dir })
+    }
+
+    /// `$XDG_CACHE_HOME/thermal-stats`, or
+    /// `$HOME/.cache/thermal-stats`.
+    pub fn default_dir() -> Option<PathBuf> {
+        env::var_os("XDG_CACHE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".cache")))
+            .map(|dir| dir.join("thermal-stats"))
+    }
+
+    /// Compute the key of the image at `path` by reading
+    /// it.
+    pub fn key(path: &str, distance: Option<f64>, unit: TemperatureUnit) -> Result<CacheKey> {
+        let data = fs::read(path)?;
+        Ok(CacheKey {
+            hash: blake3::hash(&data).to_hex().to_string(),
+            distance,
+            unit,
+        })
+    }
+
+    fn entry_path(&self, key: &CacheKey) -> PathBuf {
+        let distance = key
+            .distance
+            .map(|d| format!("{:016x}", d.to_bits()))
+            .unwrap_or_else(|| "recorded".into());
+        self.dir
+            .join(format!("{}-{}-{:?}.json", key.hash, distance, key.unit))
+    }
+
+    /// The cached stats for `key`, if any, along with the
+    /// sanity warnings of the image. The stats are reported
+    /// under `path`, which may differ from the path of the
+    /// file they were computed from.
+    pub fn get(&self, key: &CacheKey, path: &str) -> Option<(ImageStats, Vec<String>)> {
+        let data = fs::read(self.entry_path(key)).ok()?;
+        let entry: Entry = serde_json::from_slice(&data).ok()?;
+        if entry.version != env!("CARGO_PKG_VERSION")
+            || entry.schema != SCHEMA_VERSION
+            || &entry.key != key
+        {
+            return None;
+        }
+        let stats = ImageStats {
+            path: path.into(),
+            ..entry.stats
+        };
+        Some((stats, entry.warnings))
+    }
+
+    /// Store the stats for `key`, and the sanity `warnings`
+    /// of the image. The entry is written to a temporary file
+    /// and renamed, so concurrent runs never read a partial
+    /// entry.
+    pub fn put(&self, key: &CacheKey, stats: &ImageStats, warnings: &[String]) -> Result<()> {
+        let path = self.entry_path(key);
+        let tmp_path = path.with_extension(format!("tmp{}", std::process::id()));
+        let entry = serde_json::json!({
+            "version": env!("CARGO_PKG_VERSION"),
+            "schema": SCHEMA_VERSION,
+            "key": key,
+            "stats": stats,
+            "warnings": warnings,
+        });
+        fs::write(&tmp_path, serde_json::to_vec(&entry)?)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(())
+    }
+}
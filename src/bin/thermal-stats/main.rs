@@ -1,127 +1,331 @@
 mod args;
+mod cache;
 
 use anyhow::Result;
-use args::Args;
-use rayon::iter::{IntoParallelIterator, ParallelIterator};
+use args::{Args, Format};
+use cache::StatsCache;
 use serde_derive::*;
+use std::{
+    collections::HashMap,
+    fs::File,
+    io::{BufWriter, Write},
+};
 
-use thermal::cli::{process_paths_par, GenericImage};
-use thermal::dji::RJpeg;
-use thermal::{image::ThermalImage, stats::Stats};
+use thermal::{
+    any::{RadiometricImage, TemperatureImage},
+    cli::{process_paths_par, BatchResults, DistanceTable, SanityWarnings},
+    image::{Dimensions, GpsCoordinates},
+    stats::Stats,
+    temperature::TemperatureUnit,
+};
 
 fn main() -> Result<()> {
     let args = Args::from_cmd_line()?;
+    let code = args.common.thread_pool()?.install(|| run(args))?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
 
+/// Returns the exit status; see [`BatchResults::exit_code`].
+fn run(args: Args) -> Result<i32> {
     use rayon::prelude::*;
 
     let Args {
+        common,
         paths,
         distance,
+        distances,
         is_json,
+        format,
+        unit,
+        output,
+        cache_dir,
+        par_pixels,
+        sample,
     } = args;
 
-    let (stats, cumulative) = process_paths_par(paths, is_json)
+    // A json file may hold many images: only cache images.
+    let cache = match cache_dir {
+        Some(dir) if !is_json => Some(StatsCache::new(dir)?),
+        _ => None,
+    };
+    let distance_for = |path: &str| DistanceTable::resolve(distances.as_ref(), path, distance);
+    let order: HashMap<String, usize> = paths
+        .iter()
+        .enumerate()
+        .map(|(idx, path)| (path.clone(), idx))
+        .collect();
+
+    // Only parse the images missing from the cache.
+    let warnings = SanityWarnings::default();
+    let mut results = vec![];
+    let mut keys = HashMap::new();
+    let paths = match &cache {
+        None => paths,
+        Some(cache) => {
+            let lookups: Vec<_> = paths
+                .into_par_iter()
+                .map(|path| {
+                    let key = StatsCache::key(&path, distance_for(&path), unit).ok();
+                    let hit = key.as_ref().and_then(|key| cache.get(key, &path));
+                    (path, key, hit)
+                })
+                .collect();
+            let mut misses = vec![];
+            for (path, key, hit) in lookups {
+                match (hit, key) {
+                    (Some((stats, found)), _) => {
+                        for warning in &found {
+                            warnings.warn(&path, warning);
+                        }
+                        results.push((path, Ok(stats)));
+                    }
+                    (None, key) => {
+                        if let Some(key) = key {
+                            keys.insert(path.clone(), key);
+                        }
+                        misses.push(path);
+                    }
+                }
+            }
+            misses
+        }
+    };
+
+    let computed: Vec<_> = process_paths_par(paths, is_json, common.quiet)
         .into_par_iter()
-        .map(|try_img| -> Result<_> {
-            let img = try_img?;
-            Ok(ImageStats::from_thermal_image(
-                &img.image,
-                distance,
-                img.filename,
-            ))
+        .map(|(path, try_img)| {
+            let res = try_img.and_then(|img| {
+                let found = warnings.check_input(&img);
+                let distance = distance_for(&img.filename);
+                let stats = ImageStats::from_thermal_image(
+                    &img.image,
+                    distance,
+                    unit,
+                    par_pixels,
+                    sample,
+                    img.filename,
+                )?;
+                Ok((stats, found))
+            });
+            if let (Some(cache), Some(key), Ok((stats, found))) = (&cache, keys.get(&path), &res) {
+                if let Err(e) = cache.put(key, stats, found) {
+                    eprintln!("Could not cache the stats of {}: {:#}", path, e);
+                }
+            }
+            (path, res.map(|(stats, _)| stats))
         })
-        .try_fold(
-            || (vec![], Stats::default()),
-            |mut acc, try_img| -> Result<_> {
-                let item = try_img?;
-                acc.0.push(item);
-                acc.1 += &acc.0.last().unwrap().stats;
-                Ok(acc)
-            },
-        )
-        .try_reduce(
-            || (vec![], Stats::default()),
-            |mut acc1, acc2| -> Result<_> {
-                acc1.0.extend(acc2.0);
-                acc1.1 += &acc2.1;
-                Ok(acc1)
-            },
-        )?;
+        .collect();
+    results.extend(computed);
+    results.sort_by_key(|(path, _)| order.get(path).copied());
+    let batch = BatchResults::new(results);
+    batch.report(common.error_json.as_deref())?;
+    if batch.all_failed() {
+        eprintln!("Could not process any of the files");
+        return Ok(batch.exit_code());
+    }
+    let code = batch.exit_code();
+    let stats = batch.values;
+
+    let mut cumulative = Stats::default();
+    for img in &stats {
+        cumulative += &img.stats;
+    }
 
     use serde_derive::*;
     #[derive(Debug, Serialize)]
     struct OutputJson {
+        unit: TemperatureUnit,
         image_stats: Vec<ImageStats>,
         cumulative: Stats,
     }
 
+    let stdout = std::io::stdout();
+    let mut out: Box<dyn Write> = match output {
+        Some(path) => Box::new(BufWriter::new(File::create(path)?)),
+        None => Box::new(stdout.lock()),
+    };
+    match format {
+        Format::Json => serde_json::to_writer(
+            &mut out,
+            &OutputJson {
+                unit,
+                image_stats: stats,
+                cumulative,
+            },
+        )?,
+        Format::Csv => write_csv(&mut out, &stats, &cumulative)?,
+        Format::GeoJson => write_geojson(&mut out, &stats)?,
+    }
+    out.flush()?;
+
+    Ok(code)
+}
+
+/// Write one row per image, followed by a row with the
+/// cumulative stats (with path `cumulative`).
+fn write_csv<W: Write>(out: W, stats: &[ImageStats], cumulative: &Stats) -> Result<()> {
+    #[derive(Serialize)]
+    struct CsvRow<'a> {
+        path: &'a str,
+        width: Option<usize>,
+        height: Option<usize>,
+        min: f64,
+        max: f64,
+        mean: f64,
+        stddev: f64,
+        count: f64,
+    }
+    impl<'a> CsvRow<'a> {
+        fn new(path: &'a str, dims: Option<(usize, usize)>, stats: &Stats) -> Self {
+            CsvRow {
+                path,
+                width: dims.map(|d| d.0),
+                height: dims.map(|d| d.1),
+                min: stats.min(),
+                max: stats.max(),
+                mean: stats.mean(),
+                stddev: stats.std_deviation(),
+                count: stats.count(),
+            }
+        }
+    }
+
+    let mut writer = csv::Writer::from_writer(out);
+    for img in stats {
+        writer.serialize(CsvRow::new(
+            &img.path,
+            Some((img.width, img.height)),
+            &img.stats,
+        ))?;
+    }
+    writer.serialize(CsvRow::new("cumulative", None, cumulative))?;
+    writer.flush()?;
+    Ok(())
+}
+
+/// Write a GeoJSON `FeatureCollection` with a `Point`
+/// feature per image at its GPS position. Images without a
+/// GPS position are skipped, with a warning.
+fn write_geojson<W: Write>(out: W, stats: &[ImageStats]) -> Result<()> {
+    let features: Vec<_> = stats
+        .iter()
+        .filter_map(|img| {
+            let gps = img.gps?;
+            Some(serde_json::json!({
+                "type": "Feature",
+                "geometry": {
+                    "type": "Point",
+                    "coordinates": [gps.longitude, gps.latitude, gps.altitude],
+                },
+                "properties": {
+                    "path": img.path,
+                    "min": img.stats.min(),
+                    "max": img.stats.max(),
+                    "mean": img.stats.mean(),
+                },
+            }))
+        })
+        .collect();
+
+    let skipped = stats.len() - features.len();
+    if skipped > 0 {
+        eprintln!("Skipped {} images without a GPS position", skipped);
+    }
+
     serde_json::to_writer(
-        std::io::stdout().lock(),
-        &OutputJson {
-            image_stats: stats,
-            cumulative,
-        },
+        out,
+        &serde_json::json!({
+            "type": "FeatureCollection",
+            "features": features,
+        }),
     )?;
-
     Ok(())
 }
 
-#[derive(Serialize, Debug)]
+#[derive(Serialize, Deserialize, Debug)]
 pub struct ImageStats {
     path: String,
     width: usize,
     height: usize,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gps: Option<GpsCoordinates>,
+    /// Transmission through the atmosphere: low values mean
+    /// unreliable temperatures.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    atmospheric_transmission: Option<f64>,
     pub(crate) stats: Stats,
 }
 
 impl ImageStats {
-    pub fn from_thermal_image(thermal: &GenericImage, distance: f64, path: String) -> Self {
-        use itertools::Either;
-        match thermal {
-            Either::Left(ti) => Self::from_flir_image(ti, distance, path),
-            Either::Right(dji) => Self::from_dji_image(dji, distance, path),
-        }
-    }
+    pub fn from_thermal_image<I: RadiometricImage>(
+        thermal: &I,
+        distance: Option<f64>,
+        unit: TemperatureUnit,
+        par_pixels: bool,
+        sample: Option<usize>,
+        path: String,
+    ) -> Result<Self> {
+        let Dimensions { width, height } = thermal.dimensions()?;
+        let num_pixels = width * height;
 
-    pub fn from_dji_image(rjpeg: &RJpeg, _distance: f64, path: String) -> Self {
-        let values = rjpeg.temperatures().unwrap();
-        let (ht, wid) = values.dim();
-        let stats = values
-            .into_par_iter()
-            .fold(Stats::default, |mut acc, val| {
-                acc += *val as f64;
-                acc
-            })
-            .reduce(Stats::default, |mut acc, val| {
-                acc += &val;
-                acc
-            });
-
-        ImageStats {
-            width: wid,
-            height: ht,
-            path,
-            stats,
-        }
-    }
-
-    pub fn from_flir_image(thermal: &ThermalImage, distance: f64, path: String) -> Self {
-        let temp_t = thermal.settings.temperature_transform(distance);
-        let (ht, wid) = thermal.image.dim();
-
-        let mut stats = Stats::default();
-        for row in 0..ht {
-            for col in 0..wid {
-                let raw = thermal.image[(row, col)] as f64;
-                let temp = temp_t(raw);
-                stats += temp;
+        let stats = match sample {
+            Some(n) if n < num_pixels => {
+                // Each sampled pixel stands for `weight`
+                // pixels, so that the count is that of the
+                // image.
+                let weight = num_pixels as f64 / n as f64;
+                let mut stats = Stats::default();
+                for temp in thermal.temperatures_at(distance, &sample_indices(num_pixels, n))? {
+                    stats += (unit.convert(temp), weight);
+                }
+                stats
             }
-        }
-        ImageStats {
-            width: wid,
-            height: ht,
+            _ => {
+                let TemperatureImage {
+                    temperatures: mut temps,
+                    ..
+                } = TemperatureImage::from_image(thermal, distance)?;
+                if par_pixels {
+                    temps.par_mapv_inplace(|temp| unit.convert(temp));
+                    Stats::from_array_par(&temps)
+                } else {
+                    let mut stats = Stats::default();
+                    for &temp in temps.iter() {
+                        stats += unit.convert(temp);
+                    }
+                    stats
+                }
+            }
+        };
+        Ok(ImageStats {
+            width,
+            height,
+            gps: thermal.gps(),
+            atmospheric_transmission: thermal.atmospheric_transmission(distance),
             path,
             stats,
-        }
+        })
     }
 }
+
+/// `n` pseudo-random indices in `0..len` (with
+/// replacement), using a fixed seed: the same pixels are
+/// sampled in every run, and in every image of the same
+/// size.
+fn sample_indices(len: usize, n: usize) -> Vec<usize> {
+    // SplitMix64
+    let mut state: u64 = 0x5eed;
+    (0..n)
+        .map(|_| {
+            state = state.wrapping_add(0x9e37_79b9_7f4a_7c15);
+            let mut z = state;
+            z = (z ^ (z >> 30)).wrapping_mul(0xbf58_476d_1ce4_e5b9);
+            z = (z ^ (z >> 27)).wrapping_mul(0x94d0_49bb_1331_11eb);
+            z ^= z >> 31;
+            (z % len as u64) as usize
+        })
+        .collect()
+}
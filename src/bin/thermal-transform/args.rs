@@ -1,79 +1,249 @@
-use anyhow::Result;
+use anyhow::{ensure, Result};
 use clap::value_t_or_exit;
 use std::path::PathBuf;
-use thermal::{arg, args_parser, opt};
+use thermal::{
+    arg, args_parser,
+    cli::{expand_paths, CommonArgs, DistanceTable},
+    opt,
+    temperature::ThermalSettings,
+};
+
+use crate::{
+    palette::Palette,
+    proc::{OutputLayout, SettingsOverrides},
+};
 
 pub struct Args {
+    pub common: CommonArgs,
     pub paths: Vec<String>,
     pub is_json: bool,
-    pub output: PathBuf,
-    pub min: f64,
-    pub max: f64,
-    pub distance: f64,
+    pub output: OutputLayout,
+    pub min: Option<f64>,
+    pub max: Option<f64>,
+    pub auto: Option<AutoRange>,
+    pub distance: Option<f64>,
+    pub distances: Option<DistanceTable>,
     pub copy_exif: bool,
+    pub geotiff: bool,
+    pub palette: Option<Palette>,
+    pub npy: bool,
+    pub float: bool,
+    pub overrides: SettingsOverrides,
+}
+
+/// Strategy to compute the transform range when `min` /
+/// `max` are not given. Without one, the raw value range
+/// recorded by the camera in each image is used, falling
+/// back to the percentiles (as with `Image`) if not
+/// recorded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AutoRange {
+    /// Use the 1st / 99th percentile temperatures of each
+    /// image.
+    Image,
+    /// Use the same range for all the images: the smallest
+    /// 1st percentile and the largest 99th percentile
+    /// across images.
+    Global,
 }
 
 impl Args {
     pub fn from_cmd_line() -> Result<Args> {
-        let matches = args_parser!("thermal-stats")
-            .setting(clap::AppSettings::AllowLeadingHyphen)
-            .about("Compute temperature stats from image.")
-            .arg(
-                opt!("json")
-                    .short("j")
-                    .takes_value(false)
-                    .help("Paths are jsons created using exiftool (default: paths are rjpegs)"),
-            )
-            .arg(
-                opt!("output")
-                    .required(true)
-                    .help("Min value for transform"),
-            )
-            .arg(opt!("min").required(true).help("Min value for transform"))
-            .arg(opt!("max").required(true).help("Max value for transform"))
-            .arg(
-                opt!("copy exif")
-                    .takes_value(false)
-                    .short("x")
-                    .help("Copy exif from source file to the target (requires exiv2)"),
-            )
-            .arg(
-                opt!("distance")
-                    .short("d")
-                    .help("Distance to use for calculation.  Default is 1.0"),
-            )
-            .arg(
-                arg!("paths")
-                    .required(true)
-                    .multiple(true)
-                    .help("Image / json paths"),
-            )
-            .get_matches();
+        let matches =
+            args_parser!("thermal-stats")
+                .setting(clap::AppSettings::AllowLeadingHyphen)
+                .about("Compute temperature stats from image.")
+                .arg(
+                    opt!("json")
+                        .short("j")
+                        .takes_value(false)
+                        .help("Paths are jsons created using exiftool (default: paths are rjpegs)"),
+                )
+                .arg(
+                    opt!("output")
+                        .required_unless("suffix")
+                        .conflicts_with("suffix")
+                        .help("Output directory"),
+                )
+                .arg(
+                    opt!("keep dirs")
+                        .takes_value(false)
+                        .requires("output")
+                        .help(
+                        "Write each output under the output directory at the path of its input, \
+                     instead of directly in it.  Avoids collisions between same-named files \
+                     in different directories",
+                    ),
+                )
+                .arg(opt!("suffix").help(
+                    "Write the outputs next to the inputs instead of in an output directory, \
+                     appending the suffix to the file names, eg. `_thermal` writes \
+                     `name_thermal.tif` for `name.jpg`",
+                ))
+                .arg(opt!("min").help(
+                    "Min value for transform.  Default is from the raw value range recorded \
+                     by the camera in each image, or the 1st percentile temperature if not \
+                     recorded (see `--auto`)",
+                ))
+                .arg(opt!("max").help(
+                    "Max value for transform.  Default is from the raw value range recorded \
+                     by the camera in each image, or the 99th percentile temperature if not \
+                     recorded (see `--auto`)",
+                ))
+                .arg(
+                    opt!("auto")
+                        .min_values(0)
+                        .require_equals(true)
+                        .possible_values(&["image", "global"])
+                        .help(
+                            "Compute omitted min / max from 1st / 99th percentile temperatures \
+                         of each image (instead of the recorded raw value range), or across \
+                         all images with `--auto=global`.  The value must be given with `=`: \
+                         in `--auto global`, `global` is taken as an input path",
+                        ),
+                )
+                .arg(
+                    opt!("copy exif")
+                        .takes_value(false)
+                        .short("x")
+                        .help("Copy exif and xmp from source file to the target tiff"),
+                )
+                .arg(opt!("geotiff").takes_value(false).help(
+                    "Write GeoTIFF tags using the GPS position and field of view \
+                             recorded in the image, assuming a nadir shot.  Images without \
+                             them are written as plain TIFFs",
+                ))
+                .arg(
+                    opt!("palette")
+                        .possible_values(Palette::NAMES)
+                        .help("Also write a false-color PNG using the palette"),
+                )
+                .arg(opt!("float").takes_value(false).help(
+                    "Write the temperatures (in celsius) to a 32-bit float TIFF, instead of \
+                     scaling them to 16-bit.  These are read by GDAL / QGIS, ImageJ / Fiji \
+                     and tifffile, but not by most image viewers",
+                ))
+                .arg(
+                    opt!("npy")
+                        .takes_value(false)
+                        .help("Also write the temperatures as a .npy array, with a sidecar json"),
+                )
+                .arg(opt!("distance").short("d").help(
+                    "Distance to use for calculation.  Default is the recorded distance, or 1.0",
+                ))
+                .arg(opt!("distance csv").help(
+                    "CSV of `filename,distance` rows with per-image distances.  Images not \
+                     listed use the --distance, or the default",
+                ))
+                .arg(opt!("emissivity").help(
+                    "Emissivity to use instead of the one recorded in each image.  Applies to \
+                     all the images",
+                ))
+                .arg(opt!("humidity").help(
+                    "Relative humidity (in percent) to use instead of the one recorded in each \
+                     image.  Applies to all the images",
+                ))
+                .arg(opt!("reflected temp").help(
+                    "Reflected apparent temperature (in celsius) to use instead of the one \
+                     recorded in each image.  Applies to all the images",
+                ))
+                .args(&CommonArgs::args())
+                .arg(
+                    arg!("paths")
+                        .required(true)
+                        .multiple(true)
+                        .help("Image / json paths, directories or glob patterns"),
+                )
+                .get_matches();
 
-        let paths = matches
-            .values_of("paths")
-            .unwrap()
-            .map(|f| f.into())
-            .collect();
-        let output = value_t_or_exit!(matches, "output", PathBuf);
-        let min = value_t_or_exit!(matches, "min", f64);
-        let max = value_t_or_exit!(matches, "max", f64);
+        let output = match matches.value_of("suffix") {
+            Some(suffix) => {
+                ensure!(!suffix.is_empty(), "suffix must not be empty");
+                OutputLayout::Suffix(suffix.to_string())
+            }
+            None => {
+                let dir = value_t_or_exit!(matches, "output", PathBuf);
+                if matches.is_present("keep dirs") {
+                    OutputLayout::KeepDirs(dir)
+                } else {
+                    OutputLayout::Flat(dir)
+                }
+            }
+        };
+        let min = matches
+            .is_present("min")
+            .then(|| value_t_or_exit!(matches.value_of("min"), f64));
+        let max = matches
+            .is_present("max")
+            .then(|| value_t_or_exit!(matches.value_of("max"), f64));
+        if let Some((min, max)) = min.zip(max) {
+            ensure!(min < max, "min ({}) must be less than max ({})", min, max);
+        }
+        let auto = matches
+            .is_present("auto")
+            .then(|| match matches.value_of("auto") {
+                Some("global") => AutoRange::Global,
+                _ => AutoRange::Image,
+            });
         let distance = matches
             .is_present("distance")
-            .then(|| value_t_or_exit!(matches.value_of("distance"), f64))
-            .unwrap_or(1.0);
+            .then(|| value_t_or_exit!(matches.value_of("distance"), f64));
+        let distances = matches
+            .value_of("distance csv")
+            .map(DistanceTable::from_csv_path)
+            .transpose()?;
 
         let copy_exif = matches.is_present("copy exif");
+        let common = CommonArgs::from_matches(&matches);
         let is_json = matches.is_present("json");
+        let paths = expand_paths(matches.values_of("paths").unwrap(), is_json)?;
+        let geotiff = matches.is_present("geotiff");
+        let npy = matches.is_present("npy");
+        let float = matches.is_present("float");
+        let palette = matches
+            .is_present("palette")
+            .then(|| value_t_or_exit!(matches.value_of("palette"), Palette));
+
+        let float_opt = |name: &str| {
+            matches
+                .is_present(name)
+                .then(|| value_t_or_exit!(matches.value_of(name), f64))
+        };
+        let overrides = SettingsOverrides {
+            emissivity: float_opt("emissivity"),
+            humidity: float_opt("humidity"),
+            reflected_temperature: float_opt("reflected temp"),
+        };
+        // Validate the overrides upfront, instead of failing
+        // every image.
+        let mut check = ThermalSettings::builder();
+        if let Some(emissivity) = overrides.emissivity {
+            check = check.emissivity(emissivity);
+        }
+        if let Some(humidity) = overrides.humidity {
+            check = check.relative_humidity(humidity);
+        }
+        if let Some(temp) = overrides.reflected_temperature {
+            check = check.reflected_apparent_temperature(temp);
+        }
+        check.build()?;
 
         Ok(Args {
+            common,
             paths,
             output,
             distance,
+            distances,
             min,
             max,
+            auto,
             copy_exif,
             is_json,
+            geotiff,
+            palette,
+            npy,
+            float,
+            overrides,
         })
     }
 }
@@ -0,0 +1,389 @@
+use anyhow::{anyhow, bail, ensure, Context, Result};
+use byteordered::{ByteOrdered, Endianness};
+use img_parts::jpeg::{markers, Jpeg};
+use std::{
+    convert::TryFrom,
+    fs::{read, write},
+    path::Path,
+};
+
+const EXIF_PREFIX: &[u8] = b"Exif\0\0";
+const XMP_PREFIX: &[u8] = b"http://ns.adobe.com/xap/1.0/\0";
+
+/// Descriptive tags copied from the first IFD of the EXIF
+/// data. The other tags there describe the layout of the
+/// JPEG, or the thumbnail, and not the output.
+const COPIED_TAGS: &[u16] = &[
+    270,   // ImageDescription
+    271,   // Make
+    272,   // Model
+    305,   // Software
+    306,   // DateTime
+    315,   // Artist
+    33432, // Copyright
+];
+const EXIF_IFD: u16 = 34665;
+const GPS_IFD: u16 = 34853;
+const XMP: u16 = 700;
+/// Tags of the EXIF IFD that are not copied: the maker
+/// note contains offsets into the source, and the
+/// interoperability IFD only applies to the JPEG.
+const SKIPPED_EXIF_TAGS: &[u16] = &[37500, 40965];
+
+/// Field types of the XMP packet and sub-IFD pointers.
+const BYTE: u16 = 1;
+const LONG: u16 = 4;
+
+/// A field of a TIFF IFD.
+struct Field {
+    tag: u16,
+    ty: u16,
+    count: u32,
+    value: Value,
+}
+
+enum Value {
+    /// The value (or offset) as stored in the entry, in the
+    /// byte-order of the output.
+    Inline([u8; 4]),
+    /// The value in the byte-order of the output.
+    Data(Vec<u8>),
+    /// The fields of a sub-IFD.
+    Ifd(Vec<Field>),
+}
+
+/// Copy the EXIF and XMP metadata from the JPEG at `path`
+/// to the TIFF at `output_path`.
+///
+/// The descriptive tags of the EXIF data (make, model,
+/// etc.), and the EXIF and GPS IFDs are added to the first
+/// IFD of the TIFF, and the XMP packet as the `XMP` tag.
+/// Tags already in the TIFF are kept.
+pub fn copy_exif_and_xmp<P: AsRef<Path>>(path: P, output_path: &Path) -> Result<()> {
+    let jpeg = Jpeg::from_bytes(read(path)?.into())?;
+    let mut tiff = read(output_path)?;
+    copy_metadata(&jpeg, &mut tiff)?;
+    write(output_path, tiff)?;
+    Ok(())
+}
+
+fn copy_metadata(jpeg: &Jpeg, tiff: &mut Vec<u8>) -> Result<()> {
+    let endianness = tiff_endianness(tiff).context("unsupported output tiff")?;
+    let mut fields = vec![];
+    for segment in jpeg.segments() {
+        if segment.marker() != markers::APP1 {
+            continue;
+        }
+        let contents = &segment.contents()[..];
+        if let Some(exif) = contents.strip_prefix(EXIF_PREFIX) {
+            fields.extend(
+                exif_fields(exif, endianness).context("failed to copy exif from input image")?,
+            );
+        } else if let Some(xmp) = contents.strip_prefix(XMP_PREFIX) {
+            fields.push(Field {
+                tag: XMP,
+                ty: BYTE,
+                count: u32::try_from(xmp.len())?,
+                value: Value::Data(xmp.to_vec()),
+            });
+        }
+    }
+    if !fields.is_empty() {
+        add_fields(tiff, endianness, fields)?;
+    }
+    Ok(())
+}
+
+/// The fields to copy from the EXIF data (a TIFF header
+/// and IFDs), converted to the `target` byte-order.
+fn exif_fields(exif: &[u8], target: Endianness) -> Result<Vec<Field>> {
+    let endianness = tiff_endianness(exif)?;
+    let offset = read_u32(exif, 4, endianness)?;
+    let mut fields = vec![];
+    for field in read_ifd(exif, offset, endianness, target)? {
+        let value = match (field.tag, field.value) {
+            (EXIF_IFD, Value::Inline(offset)) => {
+                let mut ifd = read_ifd(exif, pointer(offset, target), endianness, target)?;
+                ifd.retain(|f| !SKIPPED_EXIF_TAGS.contains(&f.tag));
+                Value::Ifd(ifd)
+            }
+            (GPS_IFD, Value::Inline(offset)) => {
+                Value::Ifd(read_ifd(exif, pointer(offset, target), endianness, target)?)
+            }
+            (tag, value) if COPIED_TAGS.contains(&tag) => value,
+            _ => continue,
+        };
+        fields.push(Field { value, ..field });
+    }
+    Ok(fields)
+}
+
+/// The byte-order of a (classic) TIFF header.
+fn tiff_endianness(data: &[u8]) -> Result<Endianness> {
+    let endianness = match data.get(..2) {
+        Some(b"II") => Endianness::Little,
+        Some(b"MM") => Endianness::Big,
+        _ => bail!("invalid tiff byte-order marker"),
+    };
+    ensure!(read_u16(data, 2, endianness)? == 42, "not a (classic) tiff");
+    Ok(endianness)
+}
+
+/// Read the IFD at `offset` in `data`. The values are
+/// converted from `endianness` to `target`.
+fn read_ifd(
+    data: &[u8],
+    offset: u32,
+    endianness: Endianness,
+    target: Endianness,
+) -> Result<Vec<Field>> {
+    let offset = offset as usize;
+    let count = read_u16(data, offset, endianness)? as usize;
+    let mut fields = Vec::with_capacity(count);
+    for entry in (0..count).map(|idx| offset + 2 + 12 * idx) {
+        let tag = read_u16(data, entry, endianness)?;
+        let ty = read_u16(data, entry + 2, endianness)?;
+        let count = read_u32(data, entry + 4, endianness)?;
+        let unit = match ty {
+            1 | 2 | 6 | 7 => 1,
+            3 | 8 => 2,
+            4 | 9 | 11 | 13 => 4,
+            // Rationals are pairs of 32-bit values.
+            5 | 10 => 4,
+            12 => 8,
+            // Unknown types cannot be converted.
+            _ => continue,
+        };
+        let len = match ty {
+            5 | 10 => 8,
+            _ => unit,
+        } * count as usize;
+        let value_offset = if len <= 4 {
+            entry + 8
+        } else {
+            read_u32(data, entry + 8, endianness)? as usize
+        };
+        let mut value = data
+            .get(value_offset..value_offset.saturating_add(len))
+            .ok_or_else(|| anyhow!("tiff field {} out of bounds", tag))?
+            .to_vec();
+        if endianness != target {
+            value.chunks_exact_mut(unit).for_each(|v| v.reverse());
+        }
+        let value = if len <= 4 {
+            let mut inline = [0; 4];
+            inline[..len].copy_from_slice(&value);
+            Value::Inline(inline)
+        } else {
+            Value::Data(value)
+        };
+        fields.push(Field {
+            tag,
+            ty,
+            count,
+            value,
+        });
+    }
+    Ok(fields)
+}
+
+/// Add `fields` to the first IFD of `tiff`, except those
+/// with a tag already present.
+///
+/// The data is only appended: a new first IFD is written
+/// at the end with the existing entries and the new fields,
+/// and the header is updated to point to it.
+fn add_fields(tiff: &mut Vec<u8>, endianness: Endianness, fields: Vec<Field>) -> Result<()> {
+    let offset = read_u32(tiff, 4, endianness)?;
+    let count = read_u16(tiff, offset as usize, endianness)? as usize;
+    let next = read_u32(tiff, offset as usize + 2 + 12 * count, endianness)?;
+    let mut ifd = read_ifd(tiff, offset, endianness, endianness)?;
+    // Entries of unknown types are skipped by `read_ifd`.
+    ensure!(ifd.len() == count, "unsupported field type in output tiff");
+
+    for field in fields {
+        if ifd.iter().all(|f| f.tag != field.tag) {
+            ifd.push(field);
+        }
+    }
+    let offset = write_ifd(tiff, endianness, ifd, next)?;
+    ByteOrdered::runtime(&mut tiff[4..8], endianness).write_u32(offset)?;
+    Ok(())
+}
+
+/// Append the IFD, and the values it refers to, to `tiff`.
+/// Returns the offset of the IFD.
+fn write_ifd(
+    tiff: &mut Vec<u8>,
+    endianness: Endianness,
+    mut fields: Vec<Field>,
+    next: u32,
+) -> Result<u32> {
+    fields.sort_by_key(|f| f.tag);
+    let mut entries = Vec::with_capacity(fields.len());
+    for field in fields {
+        let (ty, count, value) = match field.value {
+            Value::Inline(value) => (field.ty, field.count, value),
+            Value::Data(data) => {
+                let offset = append(tiff, &data)?;
+                (field.ty, field.count, to_bytes(offset, endianness))
+            }
+            Value::Ifd(fields) => {
+                let offset = write_ifd(tiff, endianness, fields, 0)?;
+                (LONG, 1, to_bytes(offset, endianness))
+            }
+        };
+        entries.push((field.tag, ty, count, value));
+    }
+
+    let mut ifd = ByteOrdered::runtime(vec![], endianness);
+    ifd.write_u16(u16::try_from(entries.len())?)?;
+    for (tag, ty, count, value) in entries {
+        ifd.write_u16(tag)?;
+        ifd.write_u16(ty)?;
+        ifd.write_u32(count)?;
+        ifd.inner_mut().extend_from_slice(&value);
+    }
+    ifd.write_u32(next)?;
+    append(tiff, &ifd.into_inner())
+}
+
+/// Append `data` at a word boundary; returns its offset.
+fn append(tiff: &mut Vec<u8>, data: &[u8]) -> Result<u32> {
+    if tiff.len() % 2 == 1 {
+        tiff.push(0);
+    }
+    let offset = u32::try_from(tiff.len()).context("output tiff too large")?;
+    tiff.extend_from_slice(data);
+    Ok(offset)
+}
+
+fn pointer(value: [u8; 4], endianness: Endianness) -> u32 {
+    match endianness {
+        Endianness::Little => u32::from_le_bytes(value),
+        Endianness::Big => u32::from_be_bytes(value),
+    }
+}
+
+fn to_bytes(value: u32, endianness: Endianness) -> [u8; 4] {
+    match endianness {
+        Endianness::Little => value.to_le_bytes(),
+        Endianness::Big => value.to_be_bytes(),
+    }
+}
+
+fn read_u16(data: &[u8], offset: usize, endianness: Endianness) -> Result<u16> {
+    let bytes = data.get(offset..).unwrap_or_default();
+    ByteOrdered::runtime(bytes, endianness)
+        .read_u16()
+        .with_context(|| format!("tiff truncated at {}", offset))
+}
+
+fn read_u32(data: &[u8], offset: usize, endianness: Endianness) -> Result<u32> {
+    let bytes = data.get(offset..).unwrap_or_default();
+    ByteOrdered::runtime(bytes, endianness)
+        .read_u32()
+        .with_context(|| format!("tiff truncated at {}", offset))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Cursor;
+    use tiff::{
+        decoder::{Decoder, DecodingResult},
+        encoder::{colortype::Gray16, TiffEncoder},
+        tags::Tag,
+    };
+
+    /// Big-endian EXIF data with a make, an image width
+    /// (not copied), and an EXIF IFD with an exposure time
+    /// and a maker note.
+    fn exif() -> Vec<u8> {
+        let mut exif = b"MM\0\x2a\0\0\0\x08".to_vec();
+        // IFD0 at 8: 3 entries, values from 50.
+        exif.extend_from_slice(&[0, 3]);
+        exif.extend_from_slice(&[0x01, 0x00, 0, 3, 0, 0, 0, 1, 0, 0x80, 0, 0]);
+        exif.extend_from_slice(&[0x01, 0x0f, 0, 2, 0, 0, 0, 5, 0, 0, 0, 50]);
+        exif.extend_from_slice(&[0x87, 0x69, 0, 4, 0, 0, 0, 1, 0, 0, 0, 56]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif.extend_from_slice(b"FLIR\0\0");
+        // EXIF IFD at 56: 2 entries, values from 86.
+        exif.extend_from_slice(&[0, 2]);
+        exif.extend_from_slice(&[0x82, 0x9a, 0, 5, 0, 0, 0, 1, 0, 0, 0, 86]);
+        exif.extend_from_slice(&[0x92, 0x7c, 0, 7, 0, 0, 0, 4, 1, 2, 3, 4]);
+        exif.extend_from_slice(&[0, 0, 0, 0]);
+        exif.extend_from_slice(&[0, 0, 0, 1, 0, 0, 0, 30]);
+        exif
+    }
+
+    fn jpeg(app1: &[&[u8]]) -> Jpeg {
+        let mut jpeg = vec![0xff, 0xd8];
+        for contents in app1 {
+            jpeg.extend_from_slice(&[0xff, 0xe1]);
+            jpeg.extend_from_slice(&(contents.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(contents);
+        }
+        jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        Jpeg::from_bytes(jpeg.into()).unwrap()
+    }
+
+    #[test]
+    fn copy_exif_and_xmp() {
+        let exif = [EXIF_PREFIX, &exif()].concat();
+        let xmp = [XMP_PREFIX, b"<x:xmpmeta/>"].concat();
+        let jpeg = jpeg(&[&exif, &xmp]);
+
+        let mut tiff = Cursor::new(vec![]);
+        TiffEncoder::new(&mut tiff)
+            .unwrap()
+            .write_image::<Gray16>(2, 1, &[1, 2])
+            .unwrap();
+        let mut tiff = tiff.into_inner();
+        copy_metadata(&jpeg, &mut tiff).unwrap();
+
+        let mut decoder = Decoder::new(Cursor::new(&tiff)).unwrap();
+        assert_eq!(decoder.dimensions().unwrap(), (2, 1));
+        match decoder.read_image().unwrap() {
+            DecodingResult::U16(data) => assert_eq!(data, [1, 2]),
+            _ => panic!("unexpected image type"),
+        }
+        assert_eq!(decoder.get_tag_ascii_string(Tag::Make).unwrap(), "FLIR");
+
+        // The decoder does not read `BYTE` arrays of more than
+        // 4 bytes.
+        let endianness = tiff_endianness(&tiff).unwrap();
+        let offset = read_u32(&tiff, 4, endianness).unwrap();
+        let ifd = read_ifd(&tiff, offset, endianness, endianness).unwrap();
+        let data = |ifd: &[Field], tag| match &ifd.iter().find(|f| f.tag == tag).unwrap().value {
+            Value::Data(data) => data.clone(),
+            _ => panic!("field {} should not be inline", tag),
+        };
+        assert_eq!(data(&ifd, XMP), b"<x:xmpmeta/>");
+
+        let offset = decoder.get_tag_u32(Tag::Unknown(EXIF_IFD)).unwrap();
+        let ifd = read_ifd(&tiff, offset, endianness, endianness).unwrap();
+        assert_eq!(ifd.len(), 1);
+        let mut exposure = ByteOrdered::runtime(vec![], endianness);
+        exposure.write_u32(1).unwrap();
+        exposure.write_u32(30).unwrap();
+        assert_eq!(data(&ifd, 33434), exposure.into_inner());
+    }
+
+    #[test]
+    fn truncated_exif() {
+        let mut exif = [EXIF_PREFIX, &exif()].concat();
+        exif.truncate(exif.len() - 4);
+        let jpeg = jpeg(&[&exif]);
+
+        let mut tiff = Cursor::new(vec![]);
+        TiffEncoder::new(&mut tiff)
+            .unwrap()
+            .write_image::<Gray16>(1, 1, &[1])
+            .unwrap();
+        let mut tiff = tiff.into_inner();
+        let original = tiff.clone();
+        assert!(copy_metadata(&jpeg, &mut tiff).is_err());
+        assert_eq!(tiff, original);
+    }
+}
@@ -1,53 +1,153 @@
 mod args;
+mod exif;
+mod palette;
 mod proc;
 
-use anyhow::Result;
-use thermal::cli::process_paths_par;
+use anyhow::{anyhow, Result};
+use thermal::cli::{process_paths_par, BatchResults, DistanceTable, SanityWarnings, ThermalInput};
 
 use crate::{
-    args::Args,
-    proc::{copy_exif_and_xmp, transform_image_tiff, TransformArgs},
+    args::{Args, AutoRange},
+    exif::copy_exif_and_xmp,
+    proc::{
+        export_npy, percentile_range, recorded_range, transform_image_png, transform_image_tiff,
+        TransformArgs,
+    },
 };
 
 fn main() -> Result<()> {
     let args = Args::from_cmd_line()?;
-    let t_args = TransformArgs::from_args(&args);
+    let code = args.common.thread_pool()?.install(|| run(args))?;
+    if code != 0 {
+        std::process::exit(code);
+    }
+    Ok(())
+}
+
+/// Returns the exit status; see [`BatchResults::exit_code`].
+fn run(mut args: Args) -> Result<i32> {
+    use rayon::prelude::*;
+
+    // Explicit min / max always take precedence over the
+    // computed range.
+    let (min, max) = (args.min, args.max);
+    let resolve = move |range: (f64, f64)| (min.unwrap_or(range.0), max.unwrap_or(range.1));
+    let (distances, distance) = (args.distances.take(), args.distance);
+    let overrides = args.overrides;
+    let distance_for = move |inp: &ThermalInput| {
+        DistanceTable::resolve(distances.as_ref(), &inp.filename, distance)
+    };
+    let global_range = match args.auto {
+        // Unless both are given, the range is computed per
+        // image (see `image_range`).
+        None => min.zip(max),
+        Some(AutoRange::Image) => None,
+        Some(AutoRange::Global) => {
+            let range = process_paths_par(args.paths.clone(), args.is_json, args.common.quiet)
+                .into_par_iter()
+                // Files that fail are reported when transforming.
+                .filter_map(|(_, p)| {
+                    p.and_then(|mut p| {
+                        overrides.apply(&mut p)?;
+                        percentile_range(&p, distance_for(&p))
+                    })
+                    .ok()
+                })
+                .reduce(
+                    || (f64::INFINITY, f64::NEG_INFINITY),
+                    |a, b| (a.0.min(b.0), a.1.max(b.1)),
+                );
+            if range.0 > range.1 {
+                return Err(anyhow!("could not compute a global range: no images"));
+            }
+            Some(resolve(range))
+        }
+    };
+    let t_args = TransformArgs::from_args(&args, global_range.unwrap_or((0., 1.)));
+
+    let auto = args.auto;
+    let image_range = |inp: &ThermalInput, distance: Option<f64>| match auto {
+        // Percentiles were not requested: prefer the range
+        // recorded by the camera.
+        None => recorded_range(inp, distance)
+            .map(Ok)
+            .unwrap_or_else(|| percentile_range(inp, distance)),
+        Some(_) => percentile_range(inp, distance),
+    };
+
     let Args {
+        common,
         paths,
         is_json,
         copy_exif,
+        palette,
+        npy,
+        float,
         ..
     } = args;
+    // Float TIFFs are not scaled: the range is only needed
+    // for the PNG.
+    let needs_range = !float || palette.is_some();
 
-    use rayon::prelude::*;
-    let count = process_paths_par(paths, is_json)
+    let transform = |inp: &ThermalInput| -> Result<()> {
+        let distance = distance_for(inp);
+        let t_args = t_args.with_distance(distance);
+        let t_args = match global_range {
+            None if needs_range => t_args.with_range(resolve(image_range(inp, distance)?)),
+            _ => t_args,
+        };
+        let t_args = &t_args;
+
+        let out_path = transform_image_tiff(inp, t_args)?;
+        if copy_exif {
+            copy_exif_and_xmp(&inp.filename, &out_path)?;
+        }
+        if let Some(palette) = palette {
+            transform_image_png(inp, t_args, Some(palette))?;
+        }
+        if npy {
+            export_npy(inp, t_args)?;
+        }
+        Ok(())
+    };
+
+    let warnings = SanityWarnings::default();
+    let results = process_paths_par(paths, is_json, common.quiet)
         .into_par_iter()
-        .map(|p| -> Result<()> {
-            let inp = p?;
-            let out_path = transform_image_tiff(&inp, &t_args)?;
-            if copy_exif {
-                copy_exif_and_xmp(&inp.filename, &out_path)?;
-            }
-            Ok(())
+        .map(|(path, p)| {
+            let res = p.and_then(|mut inp| {
+                overrides.apply(&mut inp)?;
+                warnings.check_input(&inp);
+                transform(&inp)
+            });
+            (path, res)
         })
-        .try_fold(
-            || 0usize,
-            |acc, res| -> Result<_> {
-                res?;
-                Ok(acc + 1)
-            },
-        )
-        .try_reduce(|| 0, |a, b| Ok(a + b))?;
-
-    eprintln!("Processed {} images", count);
-    eprintln!(
-        "Transform equation: V = {} + {} C",
-        t_args.coeffs[0], t_args.coeffs[1]
-    );
+        .collect();
+    let batch = BatchResults::new(results);
+    batch.report(common.error_json.as_deref())?;
+    let code = batch.exit_code();
+    if batch.all_failed() {
+        eprintln!("Could not process any of the files");
+        return Ok(code);
+    }
+
+    eprintln!("Processed {} images", batch.values.len());
+    if float {
+        eprintln!("TIFF values are temperatures in celsius");
+        if palette.is_none() {
+            return Ok(code);
+        }
+    }
+    if global_range.is_none() {
+        eprintln!("Transform range computed per image");
+        return Ok(code);
+    }
+    let coeffs = t_args.coeffs();
+    eprintln!("Transform equation: V = {} + {} C", coeffs[0], coeffs[1]);
     eprintln!(
         "Inverse equation: C = {} + {} V",
-        -t_args.coeffs[0] / t_args.coeffs[1],
-        1. / t_args.coeffs[1]
+        -coeffs[0] / coeffs[1],
+        1. / coeffs[1]
     );
-    Ok(())
+    Ok(code)
 }
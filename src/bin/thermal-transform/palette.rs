@@ -0,0 +1,76 @@
+use anyhow::{bail, Error, Result};
+use std::str::FromStr;
+
+/// False-color palettes for PNG output.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Palette {
+    Iron,
+    Rainbow,
+    WhiteHot,
+    BlackHot,
+}
+
+impl Palette {
+    pub const NAMES: &'static [&'static str] = &["iron", "rainbow", "white-hot", "black-hot"];
+
+    /// Control points of the palette, equally spaced from
+    /// the coldest to the hottest value.
+    fn control_points(&self) -> &'static [[u8; 3]] {
+        match self {
+            Palette::Iron => &[
+                [0, 0, 0],
+                [30, 0, 120],
+                [140, 0, 155],
+                [220, 60, 40],
+                [255, 150, 0],
+                [255, 220, 50],
+                [255, 255, 255],
+            ],
+            Palette::Rainbow => &[
+                [0, 0, 0],
+                [0, 0, 255],
+                [0, 255, 255],
+                [0, 255, 0],
+                [255, 255, 0],
+                [255, 0, 0],
+                [255, 255, 255],
+            ],
+            Palette::WhiteHot => &[[0, 0, 0], [255, 255, 255]],
+            Palette::BlackHot => &[[255, 255, 255], [0, 0, 0]],
+        }
+    }
+
+    /// Build a 256 entry lookup table by linearly
+    /// interpolating the control points.
+    pub fn lookup_table(&self) -> [[u8; 3]; 256] {
+        let points = self.control_points();
+        let segments = (points.len() - 1) as f64;
+
+        let mut lut = [[0; 3]; 256];
+        for (idx, color) in lut.iter_mut().enumerate() {
+            let pos = idx as f64 / 255. * segments;
+            let seg = (pos.floor() as usize).min(points.len() - 2);
+            let frac = pos - seg as f64;
+            for ch in 0..3 {
+                let start = points[seg][ch] as f64;
+                let end = points[seg + 1][ch] as f64;
+                color[ch] = (start + (end - start) * frac).round() as u8;
+            }
+        }
+        lut
+    }
+}
+
+impl FromStr for Palette {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "iron" => Palette::Iron,
+            "rainbow" => Palette::Rainbow,
+            "white-hot" => Palette::WhiteHot,
+            "black-hot" => Palette::BlackHot,
+            _ => bail!("unknown palette: {}", s),
+        })
+    }
+}
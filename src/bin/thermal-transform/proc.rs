@@ -1,71 +1,249 @@
 use super::Args;
-use anyhow::{ensure, Result};
+use crate::palette::Palette;
+use anyhow::{anyhow, Result};
 use byteordered::ByteOrdered;
-use image::tiff::TiffEncoder;
-use img_parts::jpeg::Jpeg;
-use itertools::{iproduct, Either};
+use ndarray::Array2;
+use ndarray_npy::write_npy;
+use serde_derive::*;
 use std::{
-    fs::{read, File},
-    io::{BufWriter, Cursor, Seek, Write},
-    path::{Path, PathBuf},
-    process::Command,
+    collections::{hash_map::Entry, HashMap},
+    fs::{create_dir_all, File},
+    io::{BufWriter, Seek, Write},
+    path::{Component, Path, PathBuf},
+    sync::{Arc, Mutex},
 };
-use thermal::{cli::ThermalInput, dji::RJpeg, image::ThermalImage};
+#[cfg(feature = "dji")]
+use thermal::dji::RJpeg;
+use thermal::{
+    any::{GenericImage, RadiometricImage},
+    cli::{write_float_tiff, ThermalInput},
+    image::{gray16_coeffs, GpsCoordinates, ThermalImage},
+    stats::percentiles,
+};
+use tiff::{
+    encoder::{
+        colortype::{ColorType, Gray16, Gray32Float},
+        TiffEncoder, TiffValue,
+    },
+    tags::Tag,
+};
+
+/// Where to write the outputs of each image.
+#[derive(Clone, Debug)]
+pub enum OutputLayout {
+    /// In the directory, named by the file stem of the
+    /// input. Inputs with the same name (in different
+    /// directories) collide.
+    Flat(PathBuf),
+    /// Under the directory, at the path of the input: `..`
+    /// and the root of absolute paths are dropped.
+    KeepDirs(PathBuf),
+    /// Next to the input, with the suffix appended to the
+    /// file stem, eg. `name_thermal.tif`.
+    Suffix(String),
+}
 
+#[derive(Clone)]
 pub struct TransformArgs {
-    pub distance: f64,
-    pub coeffs: [f64; 2],
-    pub output: PathBuf,
+    pub distance: Option<f64>,
+    /// Temperatures in this range are mapped to the full
+    /// `u16` range; see [`coeffs`][TransformArgs::coeffs].
+    pub range: (f64, f64),
+    pub output: OutputLayout,
+    pub geotiff: bool,
+    pub float: bool,
+    /// Output paths written so far, and the input each was
+    /// written for; shared across clones.
+    claimed: Arc<Mutex<HashMap<PathBuf, String>>>,
 }
 
-impl TransformArgs {
-    pub fn from_args(args: &Args) -> Self {
-        let factor = u16::MAX as f64 / (args.max - args.min);
-        let coeffs = [-args.min * factor, factor];
+/// Parameters to use instead of those recorded in the
+/// images. These apply to all the images of the batch.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct SettingsOverrides {
+    pub emissivity: Option<f64>,
+    /// Relative humidity as a percentage.
+    pub humidity: Option<f64>,
+    /// Reflected apparent temperature in celicius.
+    pub reflected_temperature: Option<f64>,
+}
 
+impl SettingsOverrides {
+    /// Replace the parameters of the image, before it is
+    /// converted.
+    pub fn apply(&self, thermal: &mut ThermalInput) -> Result<()> {
+        match &mut thermal.image {
+            GenericImage::Flir(img) => {
+                let mut settings = img.settings.clone();
+                if let Some(emissivity) = self.emissivity {
+                    settings = settings.with_emissivity(emissivity)?;
+                }
+                if let Some(humidity) = self.humidity {
+                    settings = settings.with_relative_humidity(humidity)?;
+                }
+                if let Some(temp) = self.reflected_temperature {
+                    settings = settings.with_reflected_temperature(temp)?;
+                }
+                img.settings = settings;
+            }
+            #[cfg(feature = "dji")]
+            GenericImage::Dji(img) => {
+                let mut params = img.measurement_params()?;
+                if let Some(emissivity) = self.emissivity {
+                    params.emissivity = emissivity as f32;
+                }
+                if let Some(humidity) = self.humidity {
+                    params.humidity = humidity as f32;
+                }
+                if let Some(temp) = self.reflected_temperature {
+                    params.reflection = temp as f32;
+                }
+                img.set_measurement_params(&params)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+impl TransformArgs {
+    /// Construct the transform mapping temperatures in
+    /// `[min, max]` to the full `u16` range.
+    pub fn from_args(args: &Args, (min, max): (f64, f64)) -> Self {
         TransformArgs {
             distance: args.distance,
-            coeffs,
+            range: (min, max),
             output: args.output.clone(),
+            geotiff: args.geotiff,
+            float: args.float,
+            claimed: Default::default(),
         }
     }
 
-    pub fn transform(&self, val: f64) -> u16 {
-        let tval = self.coeffs[0] + self.coeffs[1] * val;
-        tval.max(0.).min(u16::MAX as f64) as u16
+    /// Same transform, but for a different temperature
+    /// range.
+    pub fn with_range(&self, (min, max): (f64, f64)) -> Self {
+        TransformArgs {
+            range: (min, max),
+            ..self.clone()
+        }
+    }
+
+    /// Same transform, but for a different distance.
+    pub fn with_distance(&self, distance: Option<f64>) -> Self {
+        TransformArgs {
+            distance,
+            ..self.clone()
+        }
     }
 
-    pub fn output_stem_for<P: AsRef<Path>>(&self, path: P) -> PathBuf {
-        self.output.join(path.as_ref().file_stem().unwrap())
+    /// Coefficients of the transform; the same mapping as
+    /// [`ThermalImage::to_gray16`].
+    pub fn coeffs(&self) -> [f64; 2] {
+        gray16_coeffs(self.range.0, self.range.1)
     }
+
+    /// Path to write the output with extension `ext` of the
+    /// input `path` to. The extension is appended to the
+    /// name (so that a dot in the stem or the suffix is
+    /// kept). Warns if another input of the batch was written
+    /// to the same path.
+    pub fn output_path_for(&self, path: &str, ext: &str) -> Result<PathBuf> {
+        let input = Path::new(path);
+        let stem = input
+            .file_stem()
+            .ok_or_else(|| anyhow!("no file name in path: {}", path))?;
+        let mut name = stem.to_os_string();
+        if let OutputLayout::Suffix(suffix) = &self.output {
+            name.push(suffix);
+        }
+        name.push(".");
+        name.push(ext);
+        let out = match &self.output {
+            OutputLayout::Flat(dir) => dir.join(name),
+            OutputLayout::KeepDirs(dir) => {
+                let rel: PathBuf = input
+                    .parent()
+                    .into_iter()
+                    .flat_map(Path::components)
+                    .filter(|c| matches!(c, Component::Normal(_)))
+                    .collect();
+                let dir = dir.join(rel);
+                create_dir_all(&dir)?;
+                dir.join(name)
+            }
+            OutputLayout::Suffix(_) => input.with_file_name(name),
+        };
+
+        match self.claimed.lock().unwrap().entry(out.clone()) {
+            Entry::Occupied(e) if e.get() != path => eprintln!(
+                "Warning: {} and {} are both written to {}: one overwrites the other",
+                e.get(),
+                path,
+                out.display()
+            ),
+            Entry::Occupied(_) => {}
+            Entry::Vacant(e) => {
+                e.insert(path.to_string());
+            }
+        }
+        Ok(out)
+    }
+}
+
+/// Temperature range from the 1st to the 99th percentile of
+/// the temperatures in the image. If these are equal (eg.
+/// a mostly uniform image), the range from the minimum to
+/// the maximum is used instead; and if the image is
+/// entirely uniform, a unit range centered on its
+/// temperature.
+pub fn percentile_range(thermal: &ThermalInput, distance: Option<f64>) -> Result<(f64, f64)> {
+    let temps = thermal.image.temperatures(distance)?;
+    let pcts =
+        percentiles(temps, &[0., 1., 99., 100.]).ok_or_else(|| anyhow!("no valid temperatures"))?;
+    Ok(match pcts[..] {
+        [_, p1, p99, _] if p1 < p99 => (p1, p99),
+        [min, _, _, max] if min < max => (min, max),
+        _ => (pcts[0] - 0.5, pcts[0] + 0.5),
+    })
+}
+
+/// Temperature range corresponding to the raw value range
+/// recorded by the camera, if any (and not empty). DJI
+/// images do not record one.
+pub fn recorded_range(thermal: &ThermalInput, distance: Option<f64>) -> Option<(f64, f64)> {
+    let img = thermal.image.as_flir()?;
+    let (min, max) = img.raw_value_range().filter(|(min, max)| min < max)?;
+    let distance = img.effective_distance(distance);
+    let temp_t = img.settings.temperature_transform(distance);
+    Some((temp_t(min as f64), temp_t(max as f64)))
 }
 
-fn image_to_u16_iterator<'a>(
-    thermal: &'a ThermalImage,
-    args: &'a TransformArgs,
-) -> Result<impl Iterator<Item = (usize, usize, u16)> + 'a> {
-    let temp_t = thermal.settings.temperature_transform(args.distance);
-    let (ht, wid) = thermal.image.dim();
-    Ok(iproduct!(0..ht, 0..wid).map(move |(row, col)| {
-        let tval = args.transform(temp_t(thermal.image[(row, col)] as f64));
-        (row, col, tval)
-    }))
+fn flir_to_gray16(thermal: &ThermalImage, args: &TransformArgs) -> Vec<u16> {
+    let (min, max) = args.range;
+    thermal.to_gray16(args.distance, min, max).into_raw()
 }
 
 pub fn transform_image_tiff(thermal: &ThermalInput, args: &TransformArgs) -> Result<PathBuf> {
-    let output_path = args
-        .output_stem_for(&thermal.filename)
-        .with_extension("tif");
+    let output_path = args.output_path_for(&thermal.filename, "tif")?;
 
     let image_writer = BufWriter::new(File::create(&output_path)?);
+    if args.float {
+        transform_float_tiff(thermal, args, image_writer)?;
+        return Ok(output_path);
+    }
     match &thermal.image {
-        Either::Left(img) => transform_flir_tiff(img, args, image_writer),
-        Either::Right(img) => transform_dji_tiff(img, args, image_writer),
+        GenericImage::Flir(img) => match georeference(img, args) {
+            Some(georef) => transform_flir_geotiff(img, georef, args, image_writer),
+            None => transform_flir_tiff(img, args, image_writer),
+        },
+        #[cfg(feature = "dji")]
+        GenericImage::Dji(img) => transform_dji_tiff(img, args, image_writer),
     }?;
 
     Ok(output_path)
 }
 
+#[cfg(feature = "dji")]
 pub fn transform_dji_tiff<W: Write + Seek>(
     image: &RJpeg,
     args: &TransformArgs,
@@ -73,16 +251,12 @@ pub fn transform_dji_tiff<W: Write + Seek>(
 ) -> Result<()> {
     let values = image.temperatures()?;
     let (ht, wid) = values.dim();
-    let mut image_buffer = {
-        let vec = Vec::with_capacity(2 * ht * wid);
-        let cursor = Cursor::new(vec);
-        ByteOrdered::native(cursor)
-    };
-    for val in values.into_iter() {
-        image_buffer.write_u16(args.transform(val as f64)).unwrap();
-    }
-    let data = image_buffer.into_inner().into_inner();
-    TiffEncoder::new(sink).encode(&data, wid as u32, ht as u32, image::ColorType::L16)?;
+    let coeffs = args.coeffs();
+    let data: Vec<u16> = values
+        .iter()
+        .map(|&val| thermal::image::scale_to_u16(coeffs, val as f64))
+        .collect();
+    TiffEncoder::new(sink)?.write_image::<Gray16>(wid as u32, ht as u32, &data)?;
     Ok(())
 }
 
@@ -92,70 +266,263 @@ pub fn transform_flir_tiff<W: Write + Seek>(
     sink: W,
 ) -> Result<()> {
     let (ht, wid) = image.image.dim();
-    let mut image_buffer = {
-        let vec = Vec::with_capacity(2 * ht * wid);
-        let cursor = Cursor::new(vec);
-        ByteOrdered::native(cursor)
-    };
-    for (_, _, val) in image_to_u16_iterator(image, args)? {
-        image_buffer.write_u16(val)?;
+    let data = flir_to_gray16(image, args);
+    TiffEncoder::new(sink)?.write_image::<Gray16>(wid as u32, ht as u32, &data)?;
+    Ok(())
+}
+
+/// Write the temperatures (in celicius, unscaled) as a
+/// 32-bit float TIFF, with GeoTIFF tags if requested (see
+/// [`write_geotiff`]).
+pub fn transform_float_tiff<W: Write + Seek>(
+    thermal: &ThermalInput,
+    args: &TransformArgs,
+    sink: W,
+) -> Result<()> {
+    let temps = thermal.image.temperatures(args.distance)?;
+    let georef = thermal
+        .image
+        .as_flir()
+        .and_then(|img| georeference(img, args));
+    match georef {
+        Some(georef) => {
+            let (ht, wid) = temps.dim();
+            let data: Vec<f32> = temps.iter().map(|&t| t as f32).collect();
+            write_geotiff::<Gray32Float, _>(&data, (wid, ht), georef, sink)
+        }
+        None => write_float_tiff(&temps, sink),
     }
+}
 
-    let data = image_buffer.into_inner().into_inner();
-    TiffEncoder::new(sink).encode(&data, wid as u32, ht as u32, image::ColorType::L16)?;
+/// Write a 16-bit TIFF with GeoTIFF tags; see
+/// [`write_geotiff`].
+pub fn transform_flir_geotiff<W: Write + Seek>(
+    image: &ThermalImage,
+    georef: Georeference,
+    args: &TransformArgs,
+    sink: W,
+) -> Result<()> {
+    let (ht, wid) = image.image.dim();
+    let data = flir_to_gray16(image, args);
+    write_geotiff::<Gray16, _>(&data, (wid, ht), georef, sink)
+}
+
+/// Position of the camera, and the size of a pixel in
+/// degrees of `[longitude, latitude]`.
+pub type Georeference = (GpsCoordinates, [f64; 2]);
+
+/// Meters per degree of latitude (and of longitude at the
+/// equator).
+const METERS_PER_DEGREE: f64 = 111_320.;
+
+/// Locate the image if `--geotiff` is set, and both the GPS
+/// position and the field of view are recorded. The image
+/// is assumed to be a nadir shot from the (effective)
+/// distance above the ground, with square pixels.
+fn georeference(image: &ThermalImage, args: &TransformArgs) -> Option<Georeference> {
+    let gps = image.gps.filter(|_| args.geotiff)?;
+    let fov = image.field_of_view()?;
+    let (_, wid) = image.image.dim();
+    let distance = image.effective_distance(args.distance);
+    let pixel_size = 2. * distance * (fov.to_radians() / 2.).tan() / wid as f64;
+    let lat_scale = pixel_size / METERS_PER_DEGREE;
+    let lon_scale = lat_scale / gps.latitude.to_radians().cos();
+    Some((gps, [lon_scale, lat_scale]))
+}
+
+/// Write a TIFF with GeoTIFF tags locating the image in
+/// WGS84 (EPSG:4326).
+///
+/// The recorded GPS position is that of the camera, and is
+/// tied to the center of the image (i.e. assumes a nadir
+/// shot); see [`georeference`] for the pixel scale.
+fn write_geotiff<C: ColorType, W: Write + Seek>(
+    data: &[C::Inner],
+    (wid, ht): (usize, usize),
+    (gps, [lon_scale, lat_scale]): Georeference,
+    sink: W,
+) -> Result<()>
+where
+    [C::Inner]: TiffValue,
+{
+    let mut encoder = TiffEncoder::new(sink)?;
+    let mut tiff = encoder.new_image::<C>(wid as u32, ht as u32)?;
+
+    let tiepoint = [
+        wid as f64 / 2.,
+        ht as f64 / 2.,
+        0.,
+        gps.longitude,
+        gps.latitude,
+        gps.altitude,
+    ];
+    tiff.encoder()
+        .write_tag(Tag::ModelTiepointTag, &tiepoint[..])?;
+    let scale = [lon_scale, lat_scale, 0.];
+    tiff.encoder()
+        .write_tag(Tag::ModelPixelScaleTag, &scale[..])?;
 
+    // GeoKeyDirectory: header (version 1.1.0, 3 keys),
+    // followed by (key, location, count, value) entries.
+    let geo_keys: [u16; 16] = [
+        1, 1, 0, 3, //
+        1024, 0, 1, 2, // GTModelType = Geographic
+        1025, 0, 1, 1, // GTRasterType = PixelIsArea
+        2048, 0, 1, 4326, // GeographicType = WGS84
+    ];
+    tiff.encoder()
+        .write_tag(Tag::GeoKeyDirectoryTag, &geo_keys[..])?;
+
+    tiff.write_data(data)?;
     Ok(())
 }
 
-#[allow(dead_code)]
-pub fn transform_image_png(path: &Path, args: &TransformArgs) -> Result<PathBuf> {
-    let image = Jpeg::from_bytes(read(path)?.into())?;
-    let thermal = ThermalImage::try_from_rjpeg(&image)?;
+/// Write the transformed image as a PNG. Without a palette,
+/// this is a 16-bit grayscale image with the same values as
+/// the TIFF output. With a palette, the 8 most significant
+/// bits of the value index the palette and the output is
+/// an 8-bit RGB image.
+pub fn transform_image_png(
+    thermal: &ThermalInput,
+    args: &TransformArgs,
+    palette: Option<Palette>,
+) -> Result<PathBuf> {
+    let values = match &thermal.image {
+        GenericImage::Flir(img) => {
+            let (ht, wid) = img.image.dim();
+            Array2::from_shape_vec((ht, wid), flir_to_gray16(img, args))?
+        }
+        #[cfg(feature = "dji")]
+        GenericImage::Dji(img) => {
+            let coeffs = args.coeffs();
+            img.temperatures()?
+                .mapv(|val| thermal::image::scale_to_u16(coeffs, val as f64))
+        }
+    };
+    let (ht, wid) = values.dim();
 
-    let outpath = args.output_stem_for(path).with_extension("png");
+    let outpath = args.output_path_for(&thermal.filename, "png")?;
     let image_writer = BufWriter::new(File::create(&outpath)?);
-    let mut png_writer = {
-        let (ht, wid) = thermal.image.dim();
-        let mut encoder = png::Encoder::new(image_writer, wid as u32, ht as u32);
-        encoder.set_color(png::ColorType::Grayscale);
-        encoder.set_depth(png::BitDepth::Sixteen);
-        encoder.write_header()?
+    let mut encoder = png::Encoder::new(image_writer, wid as u32, ht as u32);
+    match palette {
+        None => {
+            encoder.set_color(png::ColorType::Grayscale);
+            encoder.set_depth(png::BitDepth::Sixteen);
+            let mut png_writer = encoder.write_header()?;
+            let mut png_streamer = ByteOrdered::be(png_writer.stream_writer());
+            for val in values.iter() {
+                png_streamer.write_u16(*val)?;
+            }
+            png_streamer.into_inner().finish()?;
+        }
+        Some(palette) => {
+            let lut = palette.lookup_table();
+            encoder.set_color(png::ColorType::RGB);
+            encoder.set_depth(png::BitDepth::Eight);
+            let mut png_writer = encoder.write_header()?;
+            let data: Vec<u8> = values
+                .iter()
+                .flat_map(|val| lut[(val >> 8) as usize].iter().copied())
+                .collect();
+            png_writer.write_image_data(&data)?;
+        }
+    }
+
+    Ok(outpath)
+}
+
+/// Sidecar metadata written next to the `.npy` array.
+#[derive(Serialize)]
+struct NpySidecar<'a> {
+    source: &'a str,
+    distance: f64,
+    shape: (usize, usize),
+}
+
+/// Write the per-pixel temperatures (in Celsius) as a
+/// `(height, width)` shaped `f64` array in the `.npy`
+/// format, along with a json sidecar recording the
+/// distance used.
+pub fn export_npy(thermal: &ThermalInput, args: &TransformArgs) -> Result<PathBuf> {
+    let (temps, distance) = match &thermal.image {
+        GenericImage::Flir(img) => {
+            let distance = img.effective_distance(args.distance);
+            (img.settings.temperatures(distance, &img.image), distance)
+        }
+        #[cfg(feature = "dji")]
+        GenericImage::Dji(img) => {
+            let distance = img.distance()?;
+            (img.temperatures()?.mapv(|val| val as f64), distance)
+        }
     };
-    let mut png_streamer = ByteOrdered::be(png_writer.stream_writer());
 
-    for (_, _, val) in image_to_u16_iterator(&thermal, args)? {
-        png_streamer.write_u16(val)?;
-    }
-    png_streamer.into_inner().finish()?;
+    let outpath = args.output_path_for(&thermal.filename, "npy")?;
+    write_npy(&outpath, &temps)?;
+
+    let sidecar = NpySidecar {
+        source: &thermal.filename,
+        distance,
+        shape: temps.dim(),
+    };
+    serde_json::to_writer(
+        BufWriter::new(File::create(
+            args.output_path_for(&thermal.filename, "json")?,
+        )?),
+        &sidecar,
+    )?;
 
     Ok(outpath)
 }
 
-pub fn copy_exif_and_xmp<P: AsRef<Path>>(path: P, output_path: &Path) -> Result<()> {
-    let path = path.as_ref();
-    ensure!(
-        Command::new("sh")
-            .arg("-c")
-            .arg(&format!(
-                "exiv2 -ea- {:?} | exiv2 -ia- {:?}",
-                path, output_path,
-            ))
-            .status()?
-            .success(),
-        "failed to copy exif from input image"
-    );
-
-    ensure!(
-        Command::new("sh")
-            .arg("-c")
-            .arg(&format!(
-                "exiv2 -eX- {:?} | exiv2 -iX- {:?}",
-                path, output_path,
-            ))
-            .status()?
-            .success(),
-        "failed to copy xmp from input image"
-    );
+#[cfg(test)]
+mod tests {
+    use super::{OutputLayout, TransformArgs};
+    use std::path::{Path, PathBuf};
 
-    Ok(())
+    fn transform_args(output: OutputLayout) -> TransformArgs {
+        TransformArgs {
+            distance: None,
+            range: (0., 1.),
+            output,
+            geotiff: false,
+            float: false,
+            claimed: Default::default(),
+        }
+    }
+
+    #[test]
+    fn output_paths() {
+        let claimed = |args: &TransformArgs| {
+            let mut paths: Vec<_> = args.claimed.lock().unwrap().keys().cloned().collect();
+            paths.sort();
+            paths
+        };
+
+        let args = transform_args(OutputLayout::Flat("out".into()));
+        let paths = ["a/img.b.jpg", "a/img.c.jpg"];
+        for path in &paths {
+            args.output_path_for(path, "tif").unwrap();
+        }
+        assert_eq!(
+            claimed(&args),
+            vec![PathBuf::from("out/img.b.tif"), "out/img.c.tif".into()]
+        );
+        // Another extension of the same input is not a
+        // collision.
+        let png = args.output_path_for(paths[0], "png").unwrap();
+        assert_eq!(png, Path::new("out/img.b.png"));
+        assert_eq!(args.claimed.lock().unwrap()[&png], paths[0]);
+
+        let args = transform_args(OutputLayout::Suffix("_v1.2".into()));
+        let path = args.output_path_for("flight/img.jpg", "tif").unwrap();
+        assert_eq!(path, Path::new("flight/img_v1.2.tif"));
+
+        let dir = std::env::temp_dir().join(format!("thermal-out-{}", std::process::id()));
+        let args = transform_args(OutputLayout::KeepDirs(dir.clone()));
+        let path = args.output_path_for("../flight/img.b.jpg", "npy");
+        let created = dir.join("flight").is_dir();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(path.unwrap(), dir.join("flight/img.b.npy"));
+        assert!(created);
+    }
 }
@@ -5,24 +5,28 @@
 //! library.
 
 use std::{
+    collections::{HashMap, HashSet},
     convert::{TryFrom, TryInto},
     fs::File,
-    io::{BufReader, Read},
-    path::Path,
+    io::{self, BufRead, BufReader, BufWriter, Read, Seek, Write},
+    path::{Path, PathBuf},
+    sync::Mutex,
 };
 
-use anyhow::{Context, Error, Result};
-pub use clap::{App, Arg};
-use indicatif::{ProgressBar, ProgressStyle};
+use anyhow::{bail, Context, Error, Result};
+pub use clap::{App, Arg, ArgMatches};
+use flate2::read::GzDecoder;
+use glob::{glob_with, MatchOptions, Pattern};
+use indicatif::{ProgressBar, ProgressDrawTarget, ProgressStyle};
 pub use inflector::Inflector;
-use rayon::iter::{once, Either, IntoParallelIterator, ParallelIterator};
+use ndarray::Array2;
+pub use rayon::iter::Either;
+use rayon::iter::{once, IntoParallelIterator, ParallelBridge, ParallelIterator};
 use serde_derive::*;
-use serde_json::Deserializer;
 
-#[cfg(feature = "dji")]
-use crate::dji::RJpeg;
-
-use crate::{ThermalExiftoolJson, ThermalImage};
+use crate::{
+    any::load_any, exif::stream_json_values, temperature::ThermalSettings, ThermalExiftoolJson,
+};
 
 #[macro_export]
 macro_rules! args_parser {
@@ -51,7 +55,205 @@ macro_rules! opt {
     }};
 }
 
-pub type GenericImage = Either<ThermalImage, RJpeg>;
+/// Options shared by all the binaries.
+#[derive(Debug, Clone)]
+pub struct CommonArgs {
+    /// Number of threads to process the images with
+    /// (default: rayon's default).
+    pub threads: Option<usize>,
+    /// Do not show the progress bar.
+    pub quiet: bool,
+    /// Write the failed files to this path; see
+    /// [`BatchResults::report`].
+    pub error_json: Option<PathBuf>,
+}
+
+impl CommonArgs {
+    /// The arguments to add to the parser of a binary (eg.
+    /// using `App::args`).
+    pub fn args() -> [Arg<'static, 'static>; 3] {
+        [
+            Arg::with_name("threads")
+                .long("threads")
+                .short("t")
+                .value_name("THREADS")
+                .help("Number of threads to use (default: number of CPUs)"),
+            Arg::with_name("quiet")
+                .long("quiet")
+                .short("q")
+                .help("Do not show the progress bar"),
+            Arg::with_name("error json")
+                .long("error-json")
+                .value_name("ERROR_JSON")
+                .help(
+                    "Write the files that failed as a JSON array of `{path, error}` to this \
+                     file.  The exit status is 2 if some files failed, and 3 if all of them did",
+                ),
+        ]
+    }
+
+    pub fn from_matches(matches: &ArgMatches) -> Self {
+        let threads = matches
+            .is_present("threads")
+            .then(|| clap::value_t_or_exit!(matches.value_of("threads"), usize));
+        let quiet = matches.is_present("quiet");
+        let error_json = matches.value_of("error json").map(PathBuf::from);
+        CommonArgs {
+            threads,
+            quiet,
+            error_json,
+        }
+    }
+
+    /// Build the thread pool to run the processing in (via
+    /// `ThreadPool::install`).
+    pub fn thread_pool(&self) -> Result<rayon::ThreadPool> {
+        let mut builder = rayon::ThreadPoolBuilder::new();
+        if let Some(threads) = self.threads {
+            builder = builder.num_threads(threads);
+        }
+        Ok(builder.build()?)
+    }
+}
+
+/// Expand the `paths` given on the command line. Each
+/// entry may be
+///
+/// - a file, which is used as is;
+/// - a directory, which is searched recursively for `.jpg`
+///   files (or `.json` and `.json.gz` files if `is_json` is
+///   set); or
+/// - a glob pattern such as `dataset/**/*.jpg`.
+///
+/// Glob matching is case-insensitive, so that `*.jpg` also
+/// matches `*.JPG`. This avoids relying on the shell to
+/// expand large datasets.
+pub fn expand_paths<I, S>(paths: I, is_json: bool) -> Result<Vec<String>>
+where
+    I: IntoIterator<Item = S>,
+    S: AsRef<str>,
+{
+    let options = MatchOptions {
+        case_sensitive: false,
+        ..Default::default()
+    };
+    let extensions: &[&str] = if is_json {
+        &["json", "json.gz"]
+    } else {
+        &["jpg"]
+    };
+
+    let mut expanded = vec![];
+    for path in paths {
+        let path = path.as_ref();
+        if Path::new(path).is_file() {
+            expanded.push(path.into());
+            continue;
+        }
+
+        let patterns = if Path::new(path).is_dir() {
+            extensions
+                .iter()
+                .map(|ext| format!("{}/**/*.{}", Pattern::escape(path), ext))
+                .collect()
+        } else {
+            vec![path.into()]
+        };
+        let start = expanded.len();
+        for pattern in patterns {
+            for entry in glob_with(&pattern, options)
+                .with_context(|| format!("invalid path pattern: {}", path))?
+            {
+                let entry = entry?;
+                if entry.is_file() {
+                    expanded.push(entry.to_string_lossy().into_owned());
+                }
+            }
+        }
+        if expanded.len() == start {
+            bail!("no files found for path: {}", path);
+        }
+    }
+    Ok(expanded)
+}
+
+/// Per-image object distances, read from a CSV of
+/// `filename,distance` rows (eg. derived from a flight log).
+/// A header row is allowed, and skipped.
+#[derive(Debug, Clone, Default)]
+pub struct DistanceTable {
+    by_path: HashMap<String, f64>,
+    by_name: HashMap<String, f64>,
+}
+
+impl DistanceTable {
+    pub fn from_csv_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let path = path.as_ref();
+        Self::from_csv_reader(
+            File::open(path).with_context(|| format!("could not open {}", path.display()))?,
+        )
+        .with_context(|| format!("could not read distances from {}", path.display()))
+    }
+
+    pub fn from_csv_reader<R: Read>(rdr: R) -> Result<Self> {
+        let mut reader = csv::ReaderBuilder::new()
+            .has_headers(false)
+            .trim(csv::Trim::All)
+            .from_reader(rdr);
+
+        let mut table = DistanceTable::default();
+        for (idx, record) in reader.records().enumerate() {
+            let record = record?;
+            let (filename, distance) = match (record.get(0), record.get(1)) {
+                (Some(filename), Some(distance)) => (filename, distance),
+                _ => bail!("line {}: expected `filename,distance`", idx + 1),
+            };
+            let distance: f64 = match distance.parse() {
+                Ok(distance) => distance,
+                Err(_) if idx == 0 => continue,
+                Err(e) => bail!("line {}: invalid distance: {}", idx + 1, e),
+            };
+            table.by_path.insert(filename.into(), distance);
+        }
+
+        // File names repeat across flights (eg.
+        // `DJI_0001.jpg`): those of more than one path are
+        // ambiguous, and are only matched by path.
+        let mut by_name = HashMap::new();
+        for (path, &distance) in &table.by_path {
+            if let Some(name) = Path::new(path).file_name() {
+                by_name
+                    .entry(name.to_string_lossy().into_owned())
+                    .and_modify(|d| *d = None)
+                    .or_insert(Some(distance));
+            }
+        }
+        table.by_name = by_name
+            .into_iter()
+            .filter_map(|(name, distance)| Some((name, distance?)))
+            .collect();
+        Ok(table)
+    }
+
+    /// The distance for the image at `path`. Looks for the
+    /// path as is, and then for just its file name, unless
+    /// more than one path in the table has that name.
+    pub fn distance_for(&self, path: &str) -> Option<f64> {
+        self.by_path.get(path).copied().or_else(|| {
+            let name = Path::new(path).file_name()?.to_string_lossy();
+            self.by_name.get(name.as_ref()).copied()
+        })
+    }
+
+    /// Distance for the image at `path` from the table if
+    /// given, falling back to `distance`.
+    pub fn resolve(table: Option<&Self>, path: &str, distance: Option<f64>) -> Option<f64> {
+        table.and_then(|t| t.distance_for(path)).or(distance)
+    }
+}
+
+pub use crate::any::GenericImage;
+
 pub struct ThermalInput {
     pub filename: String,
     pub image: GenericImage,
@@ -60,22 +262,11 @@ pub struct ThermalInput {
 #[allow(dead_code)]
 impl ThermalInput {
     fn try_from_image_path(filename: String) -> Result<Self> {
-        let image = ThermalImage::try_from_rjpeg_path(&filename)
-            .map(Either::Left)
-            .or_else::<Error, _>(|_| Ok(Either::Right(RJpeg::try_from_path(Path::new(&filename))?)))
-            .context("could not parse thermal image: tried FLIR, DJI")?;
+        let image = load_any(&filename)?;
         Ok(ThermalInput { filename, image })
     }
-    fn try_from_exiftool_json<R: Read>(rdr: R) -> Result<Vec<Result<Self>>> {
-        Ok(serde_json::from_reader::<R, Vec<JsonFormat>>(rdr)?
-            .into_iter()
-            .map(|j| j.try_into())
-            .collect())
-    }
     fn stream_from_exiftool_json<R: Read>(rdr: R) -> impl Iterator<Item = Result<Self>> {
-        Deserializer::from_reader(rdr)
-            .into_iter::<JsonFormat>()
-            .map(|j| -> Result<_> { j?.try_into() })
+        stream_json_values::<JsonFormat, _>(rdr).map(|j| -> Result<_> { j?.try_into() })
     }
 }
 
@@ -93,16 +284,40 @@ impl TryFrom<JsonFormat> for ThermalInput {
     fn try_from(j: JsonFormat) -> Result<Self> {
         Ok(Self {
             filename: j.filename,
-            image: Either::Left(j.image.try_into()?),
+            image: GenericImage::Flir(j.image.try_into()?),
         })
     }
 }
 
+/// Open an exiftool json for reading. Gzip compressed files
+/// (eg. `.json.gz`) are detected by their magic bytes, and
+/// decompressed on the fly.
+fn open_json<P: AsRef<Path>>(path: P) -> io::Result<Box<dyn Read + Send>> {
+    let mut rdr = BufReader::new(File::open(path)?);
+    if rdr.fill_buf()?.starts_with(&[0x1f, 0x8b]) {
+        Ok(Box::new(BufReader::new(GzDecoder::new(rdr))))
+    } else {
+        Ok(Box::new(rdr))
+    }
+}
+
+/// Parse the `paths` in parallel, showing a progress bar
+/// on stderr unless `quiet` is set or stderr is not a
+/// terminal.
+///
+/// Each item is paired with the path it was parsed from (the
+/// json file, for exiftool jsons), so that failures can be
+/// reported per file.
 pub fn process_paths_par(
     paths: Vec<String>,
     is_json: bool,
-) -> impl IntoParallelIterator<Item = Result<ThermalInput>> {
-    let bar = ProgressBar::new(paths.len() as u64);
+    quiet: bool,
+) -> impl IntoParallelIterator<Item = (String, Result<ThermalInput>)> {
+    let bar = if quiet || ProgressDrawTarget::stderr().is_hidden() {
+        ProgressBar::hidden()
+    } else {
+        ProgressBar::new(paths.len() as u64)
+    };
     bar.set_style(
         ProgressStyle::default_bar()
             .template("[{elapsed_precise}] {wide_bar:cyan/blue} {pos:>7}/{len:7}"),
@@ -113,22 +328,293 @@ pub fn process_paths_par(
         .into_par_iter()
         .map(move |p| {
             if is_json {
-                let vec = File::open(p)
-                    .map_err(|e| e.into())
-                    .and_then(|f| ThermalInput::try_from_exiftool_json(BufReader::new(f)));
-                match vec {
-                    Ok(vec) => {
-                        if vec.len() > 1 {
-                            bar.inc_length(vec.len() as u64 - 1);
+                match open_json(&p) {
+                    Ok(rdr) => {
+                        // The number of images in the file is
+                        // not known upfront: the path is
+                        // already counted once, so extend
+                        // the bar for every further image,
+                        // and count an empty file as done.
+                        let bar = bar.clone();
+                        let mut stream = ThermalInput::stream_from_exiftool_json(rdr).peekable();
+                        if stream.peek().is_none() {
+                            bar.inc(1);
                         }
-                        Either::Left(vec.into_par_iter())
+                        let stream = stream.enumerate().map(move |(idx, inp)| {
+                            if idx > 0 {
+                                bar.inc_length(1);
+                            }
+                            (p.clone(), inp)
+                        });
+                        Either::Left(stream.par_bridge())
                     }
-                    Err(e) => Either::Right(once(Err(e))),
+                    Err(e) => Either::Right(once((p, Err(e.into())))),
                 }
             } else {
-                Either::Right(once(ThermalInput::try_from_image_path(p)))
+                let inp = ThermalInput::try_from_image_path(p.clone());
+                Either::Right(once((p, inp)))
             }
         })
         .flatten()
         .inspect(move |_| bar_dup.inc(1))
 }
+
+/// Write temperatures (unscaled) as a 32-bit float TIFF.
+pub fn write_float_tiff<W: Write + Seek>(temps: &Array2<f64>, sink: W) -> Result<()> {
+    use tiff::encoder::{colortype::Gray32Float, TiffEncoder};
+
+    let (ht, wid) = temps.dim();
+    let data: Vec<f32> = temps.iter().map(|&t| t as f32).collect();
+    TiffEncoder::new(sink)?.write_image::<Gray32Float>(wid as u32, ht as u32, &data)?;
+    Ok(())
+}
+
+/// Prints the warnings of
+/// [`ThermalSettings::sanity_check`] to stderr, once per
+/// unique issue across the batch, along with the path it
+/// was first seen in.
+#[derive(Default)]
+pub struct SanityWarnings {
+    seen: Mutex<HashSet<String>>,
+}
+
+impl SanityWarnings {
+    /// Check the settings, and return the warnings found
+    /// (including those already reported).
+    pub fn check(&self, path: &str, settings: &ThermalSettings) -> Vec<String> {
+        let warnings: Vec<_> = settings
+            .sanity_check()
+            .iter()
+            .map(ToString::to_string)
+            .collect();
+        for warning in &warnings {
+            self.warn(path, warning);
+        }
+        warnings
+    }
+
+    /// Report `warning` for the file at `path`, unless it was
+    /// reported already (eg. to replay the warnings of a
+    /// cached result).
+    pub fn warn(&self, path: &str, warning: &str) {
+        let mut seen = self.seen.lock().unwrap();
+        if !seen.contains(warning) {
+            eprintln!("Warning: {} (in {})", warning, path);
+            seen.insert(warning.to_string());
+        }
+    }
+
+    /// Check the settings of a FLIR image; DJI images are
+    /// not checked.
+    pub fn check_input(&self, input: &ThermalInput) -> Vec<String> {
+        match input.image.as_flir() {
+            Some(img) => self.check(&input.filename, &img.settings),
+            None => vec![],
+        }
+    }
+}
+
+/// Exit status when some of the files failed; see
+/// [`BatchResults::exit_code`].
+pub const EXIT_SOME_FAILED: i32 = 2;
+/// Exit status when all of the files failed.
+pub const EXIT_ALL_FAILED: i32 = 3;
+
+/// The successfully processed items of a batch, and the
+/// failures along with their paths.
+pub struct BatchResults<T> {
+    pub values: Vec<T>,
+    pub failures: Vec<(String, Error)>,
+}
+
+impl<T> BatchResults<T> {
+    pub fn new(results: Vec<(String, Result<T>)>) -> Self {
+        let mut values = Vec::with_capacity(results.len());
+        let mut failures = vec![];
+        for (path, res) in results {
+            match res {
+                Ok(val) => values.push(val),
+                Err(e) => failures.push((path, e)),
+            }
+        }
+        BatchResults { values, failures }
+    }
+
+    /// Whether there were items, and all of them failed.
+    pub fn all_failed(&self) -> bool {
+        self.values.is_empty() && !self.failures.is_empty()
+    }
+
+    /// Exit status of the batch: `0` if no file failed,
+    /// [`EXIT_SOME_FAILED`] if some did, and
+    /// [`EXIT_ALL_FAILED`] if all of them did. Other errors
+    /// (eg. invalid arguments) exit with `1`.
+    pub fn exit_code(&self) -> i32 {
+        if self.failures.is_empty() {
+            0
+        } else if self.values.is_empty() {
+            EXIT_ALL_FAILED
+        } else {
+            EXIT_SOME_FAILED
+        }
+    }
+
+    /// Print a summary of the failures to stderr, and if
+    /// `error_json` is given, write them to it as a JSON
+    /// array of `{"path": .., "error": ..}` objects. The file
+    /// is written (as `[]`) even if nothing failed.
+    pub fn report(&self, error_json: Option<&Path>) -> Result<()> {
+        if !self.failures.is_empty() {
+            let total = self.values.len() + self.failures.len();
+            eprintln!(
+                "Failed to process {} of {} files:",
+                self.failures.len(),
+                total
+            );
+            for (path, e) in &self.failures {
+                eprintln!("  {}: {:#}", path, e);
+            }
+        }
+
+        if let Some(error_json) = error_json {
+            #[derive(Serialize)]
+            struct Failure<'a> {
+                path: &'a str,
+                error: String,
+            }
+            let failures: Vec<_> = self
+                .failures
+                .iter()
+                .map(|(path, e)| Failure {
+                    path,
+                    error: format!("{:#}", e),
+                })
+                .collect();
+            let file = File::create(error_json)
+                .with_context(|| format!("creating {}", error_json.display()))?;
+            serde_json::to_writer_pretty(BufWriter::new(file), &failures)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        expand_paths, open_json, BatchResults, DistanceTable, EXIT_ALL_FAILED, EXIT_SOME_FAILED,
+    };
+    use anyhow::anyhow;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn distance_table() {
+        let csv = "filename,distance\nflight/DJI_0001.jpg, 42.5\nDJI_0002.jpg,40\n\
+                   flight-2/DJI_0001.jpg,30\n";
+        let table = DistanceTable::from_csv_reader(csv.as_bytes()).unwrap();
+
+        assert_eq!(table.distance_for("flight/DJI_0001.jpg"), Some(42.5));
+        assert_eq!(table.distance_for("flight-2/DJI_0001.jpg"), Some(30.));
+        // Ambiguous: the name is in more than one row.
+        assert_eq!(table.distance_for("other/DJI_0001.jpg"), None);
+        assert_eq!(table.distance_for("data/DJI_0002.jpg"), Some(40.));
+        assert_eq!(table.distance_for("DJI_0003.jpg"), None);
+        assert_eq!(
+            DistanceTable::resolve(Some(&table), "DJI_0003.jpg", Some(5.)),
+            Some(5.)
+        );
+
+        let csv = "a.jpg,10\nb.jpg,far\n";
+        assert!(DistanceTable::from_csv_reader(csv.as_bytes()).is_err());
+    }
+
+    fn batch(outcomes: &[(&str, bool)]) -> BatchResults<u32> {
+        let results = outcomes
+            .iter()
+            .enumerate()
+            .map(|(idx, (path, ok))| {
+                let res = if *ok {
+                    Ok(idx as u32)
+                } else {
+                    Err(anyhow!("bad image").context("parsing"))
+                };
+                (path.to_string(), res)
+            })
+            .collect();
+        BatchResults::new(results)
+    }
+
+    #[test]
+    fn batch_exit_codes() {
+        let all_ok = batch(&[("a.jpg", true), ("b.jpg", true)]);
+        assert_eq!(all_ok.values, vec![0, 1]);
+        assert!(!all_ok.all_failed());
+        assert_eq!(all_ok.exit_code(), 0);
+
+        let partial = batch(&[("a.jpg", true), ("b.jpg", false), ("c.jpg", true)]);
+        assert_eq!(partial.values, vec![0, 2]);
+        assert_eq!(partial.failures.len(), 1);
+        assert!(!partial.all_failed());
+        assert_eq!(partial.exit_code(), EXIT_SOME_FAILED);
+
+        let all_failed = batch(&[("a.jpg", false), ("b.jpg", false)]);
+        assert!(all_failed.all_failed());
+        assert_eq!(all_failed.exit_code(), EXIT_ALL_FAILED);
+
+        let empty = batch(&[]);
+        assert!(!empty.all_failed());
+        assert_eq!(empty.exit_code(), 0);
+    }
+
+    #[test]
+    fn batch_error_json() {
+        let path = std::env::temp_dir().join(format!("thermal-errors-{}.json", std::process::id()));
+        let read_report = |batch: BatchResults<u32>| {
+            let res = batch
+                .report(Some(&path))
+                .and_then(|_| Ok(std::fs::read(&path)?));
+            std::fs::remove_file(&path).ok();
+            serde_json::from_slice::<serde_json::Value>(&res.unwrap()).unwrap()
+        };
+
+        let report = read_report(batch(&[("a.jpg", true), ("b.jpg", false)]));
+        assert_eq!(
+            report,
+            serde_json::json!([{"path": "b.jpg", "error": "parsing: bad image"}])
+        );
+        let report = read_report(batch(&[("a.jpg", true)]));
+        assert_eq!(report, serde_json::json!([]));
+    }
+
+    #[test]
+    fn nested_json_directories() {
+        let root = std::env::temp_dir().join(format!("thermal-jsons-{}", std::process::id()));
+        std::fs::create_dir_all(root.join("flight-1/batch")).unwrap();
+        for name in &["flight-1/batch/a.json", "flight-1/b.JSON.gz", "c.jpg"] {
+            std::fs::write(root.join(name), b"[]").unwrap();
+        }
+
+        let res = expand_paths(&[root.to_string_lossy()], true);
+        std::fs::remove_dir_all(&root).unwrap();
+        let mut paths = res.unwrap();
+        paths.sort();
+        assert_eq!(paths.len(), 2);
+        assert!(paths[0].ends_with("b.JSON.gz"));
+        assert!(paths[1].ends_with("a.json"));
+    }
+
+    #[test]
+    fn gzipped_json() {
+        use flate2::{write::GzEncoder, Compression};
+
+        let path = std::env::temp_dir().join(format!("thermal-{}.json.gz", std::process::id()));
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(b"[{}]").unwrap();
+        std::fs::write(&path, encoder.finish().unwrap()).unwrap();
+
+        let mut contents = String::new();
+        let res = open_json(&path).and_then(|mut rdr| rdr.read_to_string(&mut contents));
+        std::fs::remove_file(&path).unwrap();
+        res.unwrap();
+        assert_eq!(contents, "[{}]");
+    }
+}
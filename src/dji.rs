@@ -1,5 +1,5 @@
-use anyhow::{bail, Error, Result};
-use ndarray::Array2;
+use anyhow::{bail, ensure, Error, Result};
+use ndarray::{Array2, ArrayView2};
 use std::{
     convert::TryFrom,
     mem::{size_of, MaybeUninit},
@@ -8,13 +8,26 @@ use std::{
 
 use dji_thermal_sys::*;
 
+use crate::image::Dimensions;
+
 #[derive(Debug)]
 pub struct RJpeg {
     handle: DIRP_HANDLE,
 }
 
+// The handle is owned by the `RJpeg` (it is neither `Clone`
+// nor `Copy`), so it is only used by one thread at a time,
+// and never after it is destroyed in `Drop`.
 unsafe impl Send for RJpeg {}
 
+impl Drop for RJpeg {
+    fn drop(&mut self) {
+        unsafe {
+            dirp_destroy(self.handle);
+        }
+    }
+}
+
 impl RJpeg {
     pub fn try_from_path(path: &Path) -> Result<Self> {
         let data = std::fs::read(path)?;
@@ -44,7 +57,53 @@ impl RJpeg {
         Ok(unsafe { params.assume_init() })
     }
 
-    pub fn dimensions(&self) -> Result<(i32, i32)> {
+    /// Replace the measurement params used to compute the
+    /// temperatures, eg. to recompute with a corrected
+    /// distance.
+    pub fn set_measurement_params(&mut self, params: &MeasurementParams) -> Result<()> {
+        let ret = unsafe { dirp_set_measurement_params(self.handle, params) };
+        if ret != 0 {
+            bail!("could not set measurement params!");
+        }
+        Ok(())
+    }
+
+    /// Distance to the target (in meters).
+    pub fn distance(&self) -> Result<f64> {
+        Ok(self.measurement_params()?.distance as f64)
+    }
+
+    /// Emissivity of the target.
+    pub fn emissivity(&self) -> Result<f64> {
+        Ok(self.measurement_params()?.emissivity as f64)
+    }
+
+    /// Relative humidity (in percent).
+    pub fn humidity(&self) -> Result<f64> {
+        Ok(self.measurement_params()?.humidity as f64)
+    }
+
+    /// Reflected apparent temperature (in Celsius).
+    pub fn reflected_temperature(&self) -> Result<f64> {
+        Ok(self.measurement_params()?.reflection as f64)
+    }
+
+    /// Dimensions of the image.
+    pub fn dimensions(&self) -> Result<Dimensions> {
+        #[allow(deprecated)]
+        let (width, height) = self.width_height()?;
+        ensure!(width >= 0 && height >= 0, "invalid rjpeg dimensions!");
+        Ok(Dimensions {
+            width: width as usize,
+            height: height as usize,
+        })
+    }
+
+    /// Dimensions of the image as `(width, height)`, as
+    /// reported by the SDK. Note the order is the reverse of
+    /// that of the arrays (`(height, width)`).
+    #[deprecated(note = "use `dimensions`, whose fields are named")]
+    pub fn width_height(&self) -> Result<(i32, i32)> {
         let mut resolution = MaybeUninit::uninit();
         let ret = unsafe { dirp_get_rjpeg_resolution(self.handle, resolution.as_mut_ptr()) };
         if ret != 0 {
@@ -56,26 +115,78 @@ impl RJpeg {
     }
 
     pub fn temperatures(&self) -> Result<Array2<f32>> {
-        let (width, height) = self.dimensions()?;
-        let num_values = width * height;
-
-        let mut values = Vec::with_capacity(num_values as usize);
-        let ret = unsafe {
-            dirp_measure_ex(
-                self.handle,
-                values.as_mut_ptr(),
-                num_values * size_of::<f32>() as i32,
-            )
-        };
+        let mut values = vec![];
+        let dim = self.measure_into(&mut values)?;
+        Ok(Array2::from_shape_vec(dim, values)?)
+    }
+
+    /// Temperatures in celicius as `f64`s, as for the other
+    /// cameras (see
+    /// [`RadiometricImage`][crate::any::RadiometricImage]).
+    /// The SDK always uses the measurement params; use
+    /// [`set_measurement_params`][RJpeg::set_measurement_params]
+    /// to change the distance.
+    pub fn temperatures_f64(&self) -> Result<Array2<f64>> {
+        Ok(self.temperatures()?.mapv(f64::from))
+    }
+
+    /// Compute the temperatures into `buf`, and return them
+    /// as a `(height, width)` view into it. The allocation of
+    /// `buf` is reused (and only grown if needed), so this
+    /// avoids an allocation per frame when processing many
+    /// images of the same size.
+    pub fn temperatures_into<'a>(&self, buf: &'a mut Vec<f32>) -> Result<ArrayView2<'a, f32>> {
+        let dim = self.measure_into(buf)?;
+        Ok(ArrayView2::from_shape(dim, &buf[..])?)
+    }
+
+    /// Render the image with the pseudo-color palette set in
+    /// the image, as a `(height, width)` array of RGB colors.
+    /// This is the rendering of the DJI SDK (`dirp_process`),
+    /// and matches that of DJI's own tools.
+    pub fn pseudo_color(&self) -> Result<Array2<[u8; 3]>> {
+        let dims = self.dimensions()?;
+        let num_bytes = dims.len() * 3;
+
+        let mut data: Vec<u8> = Vec::with_capacity(num_bytes);
+        let ret =
+            unsafe { dirp_process(self.handle, data.as_mut_ptr(), i32::try_from(num_bytes)?) };
+        if ret != 0 {
+            bail!("could not render rjpeg pseudo color image!");
+        }
+        // Safety: `data` has capacity for `num_bytes`, and on
+        // success `dirp_process` has initialized all of them.
+        unsafe {
+            data.set_len(num_bytes);
+        }
+
+        let colors = data.chunks_exact(3).map(|c| [c[0], c[1], c[2]]).collect();
+        Ok(Array2::from_shape_vec(dims.shape(), colors)?)
+    }
+
+    /// Replace the contents of `values` with the
+    /// temperatures, and return the `(height, width)`
+    /// dimensions.
+    fn measure_into(&self, values: &mut Vec<f32>) -> Result<(usize, usize)> {
+        let dims = self.dimensions()?;
+        let num_values = dims.len();
+        let num_bytes = i32::try_from(num_values * size_of::<f32>())?;
+
+        values.clear();
+        values.reserve(num_values);
+        let ret = unsafe { dirp_measure_ex(self.handle, values.as_mut_ptr(), num_bytes) };
         if ret != 0 {
             bail!("could not calculate rjpeg temperatures!");
         }
+        // Safety: `values` has capacity for `num_values`
+        // floats, and on success `dirp_measure_ex` has
+        // initialized exactly `num_bytes = num_values *
+        // size_of::<f32>()` bytes.
         unsafe {
-            values.set_len(num_values as usize);
+            values.set_len(num_values);
         }
 
-        let values = Array2::from_shape_vec((height as usize, width as usize), values)?;
-        Ok(values)
+        Ok(dims.shape())
     }
 }
 
@@ -94,10 +205,10 @@ mod tests {
     use anyhow::{Context, Result};
     use glob::{glob_with, MatchOptions};
 
-    use std::{env, path::Path};
+    use std::env;
 
     use super::RJpeg;
-    use crate::{stats::Stats, ThermalImage};
+    use crate::validate::compare_flir_dji;
 
     #[test]
     fn compare_temperatures() -> Result<()> {
@@ -108,7 +219,7 @@ mod tests {
         for path in glob_with(&format!("{base}/**/*.jpg"), opts)? {
             let path = path?;
             eprintln!("Reading {path}...", path = path.display());
-            match compare_at_path(&path) {
+            match compare_flir_dji(&path) {
                 Ok(stats) => {
                     eprintln!("\t{stats:?}");
                 }
@@ -120,35 +231,69 @@ mod tests {
         Ok(())
     }
 
-    fn compare_at_path(path: &Path) -> Result<Stats> {
-        let rj = RJpeg::try_from_path(path).context("dji rjpeg parsing failed")?;
-        eprintln!("\topened successfully.");
-
-        let (wid, ht) = rj.dimensions()?;
-        let wid = wid as usize;
-        let ht = ht as usize;
+    #[test]
+    #[ignore = "needs a DJI sample in THERMAL_DATASETS_PATH"]
+    fn temperature_image() -> Result<()> {
+        use crate::any::TemperatureImage;
+        use std::convert::TryFrom;
 
-        eprintln!("\tdims: {wid}x{ht}");
+        let base = env::var("THERMAL_DATASETS_PATH").context("env `THERMAL_DATASETS_PATH`")?;
+        let mut opts = MatchOptions::new();
+        opts.case_sensitive = false;
+        let image = glob_with(&format!("{base}/**/*.jpg"), opts)?
+            .filter_map(|path| RJpeg::try_from_path(&path.ok()?).ok())
+            .next()
+            .context("no DJI rjpeg found")?;
 
-        let params = rj.measurement_params()?;
-        eprintln!("\tparams: {params:?}");
+        let temps = TemperatureImage::try_from(&image)?;
+        assert_eq!(temps.temperatures, image.temperatures_f64()?);
+        assert_eq!(temps.dimensions(), image.dimensions()?);
+        Ok(())
+    }
 
-        let t_dji = rj.temperatures()?;
+    /// Resident memory of the process in bytes.
+    #[cfg(target_os = "linux")]
+    fn resident_memory() -> Result<usize> {
+        let statm = std::fs::read_to_string("/proc/self/statm")?;
+        let pages: usize = statm
+            .split_whitespace()
+            .nth(1)
+            .context("unexpected /proc/self/statm")?
+            .parse()?;
+        Ok(pages * 4096)
+    }
 
-        let thermal = ThermalImage::try_from_rjpeg_path(&path).context("flir parsing failed")?;
-        let temp_t = thermal
-            .settings
-            .temperature_transform(params.distance as f64);
-        assert_eq!(thermal.image.dim(), (ht as usize, wid as usize));
+    #[test]
+    #[ignore = "needs a DJI sample in THERMAL_DATASETS_PATH"]
+    #[cfg(target_os = "linux")]
+    fn repeated_parse_does_not_leak() -> Result<()> {
+        let base = env::var("THERMAL_DATASETS_PATH").context("env `THERMAL_DATASETS_PATH`")?;
+        let mut opts = MatchOptions::new();
+        opts.case_sensitive = false;
+        let bytes = glob_with(&format!("{base}/**/*.jpg"), opts)?
+            .filter_map(|path| std::fs::read(path.ok()?).ok())
+            .find(|bytes| RJpeg::try_from_bytes(bytes.clone()).is_ok())
+            .context("no DJI rjpeg found")?;
 
-        let mut stats = Stats::default();
-        for row in 0..ht {
-            for col in 0..wid {
-                let raw = thermal.image[(row, col)] as f64;
-                let temp = temp_t(raw);
-                stats += (temp - t_dji[(row, col)] as f64).abs();
-            }
+        // Warm up, so that allocations of the SDK are not
+        // counted.
+        for _ in 0..10 {
+            RJpeg::try_from_bytes(bytes.clone())?;
         }
-        Ok(stats)
+        let before = resident_memory()?;
+        for _ in 0..1000 {
+            RJpeg::try_from_bytes(bytes.clone())?;
+        }
+        let after = resident_memory()?;
+
+        // Leaking the handle leaks (at least) a copy of the
+        // image per iteration.
+        assert!(
+            after < before + 100 * bytes.len(),
+            "memory grew from {} to {} bytes",
+            before,
+            after
+        );
+        Ok(())
     }
 }
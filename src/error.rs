@@ -0,0 +1,77 @@
+//! Errors that callers may want to tell apart.
+//!
+//! Functions in this crate return [`anyhow::Error`]s; the
+//! errors here are wrapped in them, and may be recovered
+//! using [`anyhow::Error::downcast_ref`]:
+//!
+//! ```rust
+//! # fn test_compile() {
+//! use thermal::{error::ParseError, ThermalImage};
+//!
+//! match ThermalImage::try_from_rjpeg_path("image.jpg") {
+//!     Ok(image) => { /* ... */ }
+//!     Err(e) => match e.downcast_ref::<ParseError>() {
+//!         Some(ParseError::NotRadiometric) => eprintln!("regular photo"),
+//!         _ => eprintln!("broken thermal image: {:#}", e),
+//!     },
+//! }
+//! # }
+//! ```
+use std::{error::Error, fmt};
+
+/// Errors while parsing a thermal image.
+#[derive(Debug, Clone, PartialEq, Eq)]
+#[non_exhaustive]
+pub enum ParseError {
+    /// The image has no FLIR data: likely a regular (not
+    /// radiometric) JPEG.
+    NotRadiometric,
+    /// The FLIR data has no raw sensor values record. This
+    /// is the case for images exported without radiometry,
+    /// eg. MSX / fusion blended images saved by FLIR tools.
+    NoRawData,
+    /// The FLIR data has no camera params record.
+    NoCameraParams,
+    /// A record is shorter (or longer) than its header says:
+    /// the file is likely truncated (eg. an incomplete
+    /// download), or otherwise corrupt.
+    TruncatedOrCorrupt {
+        /// Expected size in bytes.
+        expected: usize,
+        /// Size found in the file.
+        found: usize,
+    },
+    /// A size declared in the file exceeds one of the
+    /// [`ParseLimits`][crate::flir::ParseLimits].
+    LimitExceeded {
+        /// Name of the limit, eg. `"pixels"`.
+        limit: &'static str,
+        /// Value declared in the file.
+        value: usize,
+        /// The limit.
+        max: usize,
+    },
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ParseError::NotRadiometric => write!(f, "not a radiometric image: no FLIR data found"),
+            ParseError::NoRawData => write!(
+                f,
+                "no raw data found: not radiometric (eg. an exported MSX / fusion image)"
+            ),
+            ParseError::NoCameraParams => write!(f, "no camera params found"),
+            ParseError::TruncatedOrCorrupt { expected, found } => write!(
+                f,
+                "file truncated or corrupt: expected {} bytes, found {}",
+                expected, found
+            ),
+            ParseError::LimitExceeded { limit, value, max } => {
+                write!(f, "{} limit exceeded: {} > {}", limit, value, max)
+            }
+        }
+    }
+}
+
+impl Error for ParseError {}
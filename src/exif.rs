@@ -0,0 +1,489 @@
+//! Parse the JSON output of ExifTool.
+//!
+//! The output of `exiftool -j -b` on a thermal image
+//! contains both the parameters required for the
+//! conversion, and the raw sensor values as a base64
+//! encoded image. See [`ThermalExiftoolJson`].
+use std::io::{self, Cursor, Read, Seek};
+
+use anyhow::{bail, ensure, Result};
+use image::{ColorType, ImageDecoder};
+use itertools::Either;
+use ndarray::Array2;
+use serde::de::DeserializeOwned;
+use serde_derive::*;
+use tiff::decoder::{Decoder as TiffFrameDecoder, DecodingResult};
+
+use crate::temperature::ThermalSettings;
+
+/// Parse output of `exiftool` json output.
+///
+/// This is the entry point for users interested in parsing
+/// the output from `exiftool -j -b` on a thermal image. It
+/// expects and extracts both the thermal settings, and the
+/// raw image encoded as a base64 string.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThermalExiftoolJson {
+    #[serde(flatten)]
+    pub settings: ThermalSettings,
+
+    #[serde(flatten)]
+    pub(crate) raw: ThermalRawBytes,
+
+    #[serde(
+        rename = "FocusDistance",
+        default,
+        deserialize_with = "serde_helpers::distance_with_suffix"
+    )]
+    focus_distance: Option<f64>,
+    #[serde(
+        rename = "SubjectDistance",
+        default,
+        deserialize_with = "serde_helpers::distance_with_suffix"
+    )]
+    subject_distance: Option<f64>,
+    #[serde(
+        rename = "FieldOfView",
+        default,
+        deserialize_with = "serde_helpers::distance_with_suffix"
+    )]
+    field_of_view: Option<f64>,
+}
+
+impl ThermalExiftoolJson {
+    /// The raw sensor values, eg. to decode all the frames
+    /// of a sequence with
+    /// [`thermal_images`][ThermalRawBytes::thermal_images].
+    pub fn raw_bytes(&self) -> &ThermalRawBytes {
+        &self.raw
+    }
+
+    /// The `FocusDistance` (from the FLIR metadata), or else
+    /// the EXIF `SubjectDistance`, if recorded. Used as a
+    /// fallback when `ObjectDistance` is not recorded; see
+    /// [`ThermalImage::effective_distance`][crate::image::ThermalImage::effective_distance].
+    pub fn recorded_distance(&self) -> Option<f64> {
+        self.focus_distance.or(self.subject_distance)
+    }
+
+    /// The horizontal `FieldOfView` (in degrees), if
+    /// recorded.
+    pub fn field_of_view(&self) -> Option<f64> {
+        self.field_of_view
+    }
+
+    /// Lazily parse the JSON array output by `exiftool -j
+    /// -b` on multiple images. Only one element of the array
+    /// is held in memory at a time, so this is suitable for
+    /// large exports.
+    ///
+    /// ```rust
+    /// # fn test_compile() -> anyhow::Result<()> {
+    /// use std::{fs::File, io::BufReader};
+    /// use thermal::ThermalExiftoolJson;
+    ///
+    /// let rdr = BufReader::new(File::open("metadata.json")?);
+    /// for json in ThermalExiftoolJson::stream_from_reader(rdr) {
+    ///     let json = json?;
+    /// }
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn stream_from_reader<R: Read>(rdr: R) -> impl Iterator<Item = Result<Self>> {
+        stream_json_array(rdr)
+    }
+
+    /// Same as
+    /// [`stream_from_reader`][ThermalExiftoolJson::stream_from_reader],
+    /// but also accepts a stream of whitespace separated
+    /// objects (eg. the output of multiple `exiftool -j -b`
+    /// runs concatenated together), in addition to a JSON
+    /// array.
+    pub fn from_reader_many<R: Read>(rdr: R) -> impl Iterator<Item = Result<Self>> {
+        stream_json_values(rdr)
+    }
+}
+
+/// Iterate over the elements of a top-level JSON array,
+/// deserializing one element at a time. The reader should
+/// be buffered as it is read one byte at a time.
+pub(crate) fn stream_json_array<T: DeserializeOwned, R: Read>(
+    rdr: R,
+) -> impl Iterator<Item = Result<T>> {
+    JsonArrayStream {
+        rdr: PeekReader {
+            inner: rdr,
+            peeked: None,
+        },
+        started: false,
+        done: false,
+        _marker: std::marker::PhantomData,
+    }
+}
+
+/// Iterate over the elements of a top-level JSON array, or
+/// over a stream of whitespace separated JSON values, one
+/// at a time. The reader should be buffered.
+pub(crate) fn stream_json_values<T: DeserializeOwned, R: Read>(
+    rdr: R,
+) -> impl Iterator<Item = Result<T>> {
+    let mut rdr = PeekReader {
+        inner: rdr,
+        peeked: None,
+    };
+    match rdr.skip_whitespace() {
+        // The `[` is only peeked: the array stream expects it.
+        Ok(Some(b'[')) => Either::Left(JsonArrayStream {
+            rdr,
+            started: false,
+            done: false,
+            _marker: std::marker::PhantomData,
+        }),
+        // A read error is reported again by the deserializer.
+        _ => Either::Right(
+            serde_json::Deserializer::from_reader(rdr)
+                .into_iter()
+                .map(|value| Ok(value?)),
+        ),
+    }
+}
+
+struct JsonArrayStream<R, T> {
+    rdr: PeekReader<R>,
+    started: bool,
+    done: bool,
+    _marker: std::marker::PhantomData<fn() -> T>,
+}
+
+impl<R: Read, T: DeserializeOwned> JsonArrayStream<R, T> {
+    fn next_element(&mut self) -> Result<Option<T>> {
+        if self.done {
+            return Ok(None);
+        }
+
+        let mut next = self.rdr.skip_whitespace()?;
+        if !self.started {
+            ensure!(next == Some(b'['), "expected a JSON array");
+            self.rdr.consume();
+            self.started = true;
+            next = self.rdr.skip_whitespace()?;
+        } else {
+            match next {
+                Some(b',') => self.rdr.consume(),
+                Some(b']') => {}
+                _ => bail!("expected `,` or `]` in JSON array"),
+            }
+        }
+        if next == Some(b']') {
+            self.done = true;
+            return Ok(None);
+        }
+
+        // Each element is parsed by a fresh deserializer.
+        // This does not lose any input: objects and arrays
+        // are self-delimiting, so the deserializer does not
+        // read past the end of the element.
+        let mut de = serde_json::Deserializer::from_reader(&mut self.rdr);
+        Ok(Some(T::deserialize(&mut de)?))
+    }
+}
+
+impl<R: Read, T: DeserializeOwned> Iterator for JsonArrayStream<R, T> {
+    type Item = Result<T>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let next = self.next_element();
+        if next.is_err() {
+            self.done = true;
+        }
+        next.transpose()
+    }
+}
+
+/// A reader with one byte of look-ahead.
+struct PeekReader<R> {
+    inner: R,
+    peeked: Option<u8>,
+}
+
+impl<R: Read> PeekReader<R> {
+    fn peek(&mut self) -> io::Result<Option<u8>> {
+        if self.peeked.is_none() {
+            let mut buf = [0];
+            if self.inner.read(&mut buf)? == 1 {
+                self.peeked = Some(buf[0]);
+            }
+        }
+        Ok(self.peeked)
+    }
+
+    fn consume(&mut self) {
+        self.peeked = None;
+    }
+
+    fn skip_whitespace(&mut self) -> io::Result<Option<u8>> {
+        loop {
+            match self.peek()? {
+                Some(b) if b.is_ascii_whitespace() => self.consume(),
+                next => return Ok(next),
+            }
+        }
+    }
+}
+
+impl<R: Read> Read for PeekReader<R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        match self.peeked.take() {
+            Some(b) => {
+                buf[0] = b;
+                Ok(1)
+            }
+            None => self.inner.read(buf),
+        }
+    }
+}
+
+/// Raw image bytes serialized by `exiftool` as JSON.
+#[derive(Serialize, Deserialize, Debug)]
+pub struct ThermalRawBytes {
+    #[serde(rename = "RawThermalImageType")]
+    ty: String,
+
+    #[serde(
+        rename = "RawThermalImage",
+        deserialize_with = "serde_helpers::base64_bytes"
+    )]
+    base64_bytes: Vec<u8>,
+}
+impl ThermalRawBytes {
+    /// Decode the raw sensor values. If the image has more
+    /// than one frame (eg. a radiometric sequence), this is
+    /// the first frame; see
+    /// [`thermal_images`][ThermalRawBytes::thermal_images].
+    pub fn thermal_image(&self) -> Result<Array2<f64>> {
+        self.check_type()?;
+
+        use image::tiff::TiffDecoder;
+        let decoder = TiffDecoder::new(Cursor::new(&self.base64_bytes))?;
+        let (width, height) = decoder.dimensions();
+        let width = width as usize;
+        let height = height as usize;
+        let depth = match decoder.color_type() {
+            ColorType::L8 => 8,
+            ColorType::L16 => 16,
+            _ => bail!("supported color type: {:?}", decoder.color_type()),
+        };
+
+        use zerocopy::{AsBytes, FromBytes};
+        fn image_as_float<'a, T, R>(decoder: R) -> Result<Vec<f64>>
+        where
+            f64: From<T>,
+            T: AsBytes + FromBytes + Default + Clone,
+            R: ImageDecoder<'a>,
+        {
+            let (width, height) = decoder.dimensions();
+            let num_pixels = width as usize * height as usize;
+            // Zero-initialized, so that the buffer is valid
+            // even if the decoder does not fill it.
+            let mut image = vec![T::default(); num_pixels];
+            decoder.read_image(image.as_bytes_mut())?;
+            Ok(image.into_iter().map(|f| f.into()).collect())
+        }
+
+        let output = if depth == 8 {
+            image_as_float::<u8, _>(decoder)?
+        } else if depth == 16 {
+            image_as_float::<u16, _>(decoder)?
+        } else {
+            unreachable!("unexpected depth: {}", depth);
+        };
+
+        Ok(Array2::from_shape_vec((height, width), output)?)
+    }
+
+    /// Decode the raw sensor values of all the frames (pages
+    /// of the TIFF) in the image.
+    pub fn thermal_images(&self) -> Result<Vec<Array2<f64>>> {
+        self.check_type()?;
+        let mut decoder = TiffFrameDecoder::new(Cursor::new(&self.base64_bytes))?;
+        let mut frames = vec![decode_frame(&mut decoder)?];
+        while decoder.more_images() {
+            decoder.next_image()?;
+            frames.push(decode_frame(&mut decoder)?);
+        }
+        Ok(frames)
+    }
+
+    /// Decode the raw sensor values of the frame at `index`
+    /// (zero-based), without decoding the frames before it.
+    pub fn thermal_image_at(&self, index: usize) -> Result<Array2<f64>> {
+        self.check_type()?;
+        let mut decoder = TiffFrameDecoder::new(Cursor::new(&self.base64_bytes))?;
+        for idx in 0..index {
+            ensure!(
+                decoder.more_images(),
+                "frame {} out of bounds: image has {} frames",
+                index,
+                idx + 1
+            );
+            decoder.next_image()?;
+        }
+        decode_frame(&mut decoder)
+    }
+
+    fn check_type(&self) -> Result<()> {
+        if self.ty != "TIFF" {
+            bail!("unsupported image type: {}", self.ty);
+        }
+        Ok(())
+    }
+}
+
+fn decode_frame<R: Read + Seek>(decoder: &mut TiffFrameDecoder<R>) -> Result<Array2<f64>> {
+    let (width, height) = decoder.dimensions()?;
+    let values: Vec<f64> = match decoder.read_image()? {
+        DecodingResult::U8(values) => values.into_iter().map(f64::from).collect(),
+        DecodingResult::U16(values) => values.into_iter().map(f64::from).collect(),
+        _ => bail!("unsupported color type: {:?}", decoder.colortype()?),
+    };
+    Ok(Array2::from_shape_vec(
+        (height as usize, width as usize),
+        values,
+    )?)
+}
+
+mod serde_helpers {
+    use lazy_static::lazy_static;
+    use regex::Regex;
+    use serde::*;
+
+    pub use crate::temperature::serde_helpers::distance_with_suffix;
+
+    pub fn base64_bytes<'de, D>(de: D) -> Result<Vec<u8>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        lazy_static! {
+            static ref RE: Regex = Regex::new(r"^base64:").unwrap();
+        }
+
+        use serde::de::Error;
+        let str_rep = <String as Deserialize>::deserialize(de)?;
+
+        RE.find(&str_rep).ok_or(Error::custom(
+            "unexpected format: must begin with `base64:`",
+        ))?;
+
+        use base64::decode;
+        let slice = &str_rep[7..];
+        let bytes = decode(slice).map_err(Error::custom)?;
+
+        Ok(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use anyhow::Result;
+    use serde_json::Value;
+
+    use super::{stream_json_array, stream_json_values, ThermalRawBytes};
+
+    #[test]
+    fn stream_array_elements() -> Result<()> {
+        let input = br#" [ {"a": [1, 2]}, {"b": "]"} ,{"c":{}} ] "#;
+        let values = stream_json_array::<Value, _>(&input[..]).collect::<Result<Vec<_>>>()?;
+        assert_eq!(values.len(), 3);
+        assert_eq!(values[1]["b"], "]");
+
+        assert_eq!(stream_json_array::<Value, _>(&b"[]"[..]).count(), 0);
+        assert!(stream_json_array::<Value, _>(&b"{}"[..])
+            .next()
+            .unwrap()
+            .is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn stream_concatenated_values() -> Result<()> {
+        let values =
+            |input: &[u8]| stream_json_values::<Value, _>(input).collect::<Result<Vec<_>>>();
+        assert_eq!(values(b" {\"a\": 1}\n{\"b\": 2} {}\n")?.len(), 3);
+        assert_eq!(values(b"\n[{\"a\": 1}, {\"b\": 2}]")?.len(), 2);
+        assert_eq!(values(b"")?.len(), 0);
+        assert!(values(b"{\"a\": 1} {").is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn truncated_tiff() -> Result<()> {
+        use std::io::Cursor;
+        use tiff::encoder::{colortype::Gray16, TiffEncoder};
+
+        let mut bytes = Cursor::new(vec![]);
+        TiffEncoder::new(&mut bytes)?.write_image::<Gray16>(64, 64, &[7; 64 * 64])?;
+        let mut bytes = bytes.into_inner();
+        let raw = ThermalRawBytes {
+            ty: "TIFF".into(),
+            base64_bytes: bytes.clone(),
+        };
+        assert!(raw.thermal_image()?.iter().all(|&v| v == 7.));
+
+        // Cut into the pixel data (which precedes the IFD),
+        // and move the offsets of the IFD and its resolution
+        // values (just before it) to match. The IFD stays
+        // valid, but the strip now runs past the end.
+        let removed = 4096 - 16;
+        bytes.drain(16..4096);
+        let read_u32 = |bytes: &[u8], at: usize| {
+            u32::from_le_bytes([bytes[at], bytes[at + 1], bytes[at + 2], bytes[at + 3]])
+        };
+        let shift = |bytes: &mut [u8], at: usize| {
+            let offset = read_u32(bytes, at) - removed;
+            bytes[at..at + 4].copy_from_slice(&offset.to_le_bytes());
+        };
+        shift(&mut bytes, 4);
+        let ifd = read_u32(&bytes, 4) as usize;
+        let num_entries = u16::from_le_bytes([bytes[ifd], bytes[ifd + 1]]) as usize;
+        for entry in (0..num_entries).map(|idx| ifd + 2 + 12 * idx) {
+            // Rational values are stored out of line.
+            if bytes[entry + 2] == 5 {
+                shift(&mut bytes, entry + 8);
+            }
+        }
+
+        assert!(image::tiff::TiffDecoder::new(Cursor::new(&bytes)).is_ok());
+        let raw = ThermalRawBytes {
+            ty: "TIFF".into(),
+            base64_bytes: bytes,
+        };
+        assert!(raw.thermal_image().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn multi_page_tiff() -> Result<()> {
+        use std::io::Cursor;
+        use tiff::encoder::{colortype::Gray16, TiffEncoder};
+
+        let mut bytes = Cursor::new(vec![]);
+        let mut encoder = TiffEncoder::new(&mut bytes)?;
+        encoder.write_image::<Gray16>(2, 1, &[1, 2])?;
+        encoder.write_image::<Gray16>(2, 1, &[3, 4])?;
+        let raw = ThermalRawBytes {
+            ty: "TIFF".into(),
+            base64_bytes: bytes.into_inner(),
+        };
+
+        let frames = raw.thermal_images()?;
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[1], ndarray::array![[3., 4.]]);
+        assert_eq!(raw.thermal_image_at(1)?, frames[1]);
+        assert_eq!(raw.thermal_image()?, frames[0]);
+        assert!(raw.thermal_image_at(2).is_err());
+        Ok(())
+    }
+}
@@ -13,13 +13,53 @@
 //! to compute the temperature values via [ThermalExiftoolJson]
 //!
 //! [ExifTool]: //exiftool.org
-//! [ThermalExiftoolJson]: crate::image::ThermalExiftoolJson
+//! [ThermalExiftoolJson]: crate::exif::ThermalExiftoolJson
 use anyhow::{anyhow, bail, ensure, Result};
 use byteordered::{byteorder::ReadBytesExt, ByteOrdered, Endian, Endianness};
-use img_parts::jpeg::{markers, Jpeg};
+use chrono::{DateTime, FixedOffset, TimeZone};
+use img_parts::jpeg::{markers, Jpeg, JpegSegment};
 use ndarray::Array2;
+use std::fmt;
 
-use crate::parse::Parseable;
+use crate::{error::ParseError, parse::Parseable};
+
+/// Largest width / height of the raw sensor values accepted
+/// while parsing. Larger values are assumed to be from a
+/// corrupt header.
+pub const MAX_RAW_DIMENSION: usize = 10000;
+
+/// Limits on the sizes declared in a FLIR file, checked
+/// before allocating. Files exceeding them fail with
+/// [`ParseError::LimitExceeded`]. The defaults are well
+/// above the sizes written by cameras; lower them to parse
+/// untrusted files (eg. on a server) with bounded memory.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ParseLimits {
+    /// Largest number of raw sensor values (width x height).
+    pub max_pixels: usize,
+    /// Largest number of FLIR APP1 segments in a Jpeg.
+    pub max_segments: usize,
+    /// Largest length (in bytes) of a FLIR record.
+    pub max_record_len: usize,
+}
+
+impl Default for ParseLimits {
+    fn default() -> Self {
+        ParseLimits {
+            max_pixels: 1 << 24,
+            max_segments: 256,
+            max_record_len: 1 << 26,
+        }
+    }
+}
+
+fn check_limit(limit: &'static str, value: usize, max: usize) -> Result<()> {
+    ensure!(
+        value <= max,
+        ParseError::LimitExceeded { limit, value, max }
+    );
+    Ok(())
+}
 
 /// FLIR data along with parsed header.
 ///
@@ -35,14 +75,24 @@ use crate::parse::Parseable;
 /// - 0x18 - `int32u` offset to record directory
 /// - 0x1c - `int32u` number of entries in record directory
 /// - 0x20 - `int32u` next free index ID = 2
-/// - 0x24 - `int16u` swap pattern = 0 (?)
+/// - 0x24 - `int16u` swap pattern = 0 (byte-order flag: 0=MSBF)
 /// - 0x28 - `int16u[7]` spares
 /// - 0x34 - `int32u[2]` reserved
 /// - 0x3c - `int32u` checksum
+///
+/// The format version is available as
+/// [`format_version`][FlirSegment::format_version]. The
+/// record layouts are not known to differ between versions,
+/// so it is not used while parsing, except to guess the
+/// byte-order of the header.
 #[derive(Debug)]
 pub struct FlirSegment {
     data: Vec<u8>,
+    endianness: Endianness,
+    forced_endianness: Option<Endianness>,
+    format_version: u32,
     dir: Vec<FlirRecordDirEntry>,
+    limits: ParseLimits,
 }
 
 impl FlirSegment {
@@ -51,8 +101,43 @@ impl FlirSegment {
     /// Returns a `FlirSegment` if both steps are
     /// successful.
     pub fn try_from_jpeg(image: &Jpeg) -> Result<Self> {
-        let data = collect_flir_segment_data_from_jpeg(image)?;
-        Self::try_from_segment_data(data)
+        Self::try_from_jpeg_with_endianness(image, None)
+    }
+
+    /// Same as [`try_from_jpeg`][FlirSegment::try_from_jpeg],
+    /// but reads the APP1 segments directly from the bytes of
+    /// a Jpeg file, without copying the whole file (eg. from
+    /// a memory mapped file). Only the segments before the
+    /// image data are read.
+    pub fn try_from_jpeg_bytes(bytes: &[u8]) -> Result<Self> {
+        let limits = ParseLimits::default();
+        let data = collect_flir_segment_data(jpeg_app1_segments(bytes)?.into_iter(), &limits)?;
+        Self::try_from_segment_data_with_limits(data, None, limits)
+    }
+
+    /// Same as [`try_from_jpeg`][FlirSegment::try_from_jpeg],
+    /// but with the given `limits` instead of the defaults.
+    /// They also apply to the records parsed from the
+    /// segment.
+    pub fn try_from_jpeg_with_limits(image: &Jpeg, limits: ParseLimits) -> Result<Self> {
+        let data = collect_flir_segment_data_from_jpeg(image, &limits)?;
+        Self::try_from_segment_data_with_limits(data, None, limits)
+    }
+
+    /// Same as [`try_from_jpeg`][FlirSegment::try_from_jpeg],
+    /// but if `endianness` is given, it is used for all the
+    /// records, instead of their byte-order markers. Useful
+    /// when the markers are wrong for a camera. The
+    /// byte-order of the header is still guessed: it need
+    /// not match that of the records (see
+    /// [`try_parse_raw_data`][FlirRecordDirEntry::try_parse_raw_data]).
+    pub fn try_from_jpeg_with_endianness(
+        image: &Jpeg,
+        endianness: Option<Endianness>,
+    ) -> Result<Self> {
+        let limits = ParseLimits::default();
+        let data = collect_flir_segment_data_from_jpeg(image, &limits)?;
+        Self::try_from_segment_data_with_limits(data, endianness, limits)
     }
 
     /// Try to find and parse raw sensor values as a 2-D
@@ -62,7 +147,34 @@ impl FlirSegment {
     pub fn try_parse_raw_data(&self) -> Result<Option<Array2<f64>>> {
         self.dir
             .iter()
-            .find_map(|e| e.try_parse_raw_data(&self.data).transpose())
+            .find_map(|e| {
+                e.parse_raw_data(
+                    &self.data,
+                    self.forced_endianness,
+                    self.limits.max_pixels,
+                    false,
+                )
+                .transpose()
+            })
+            .transpose()
+    }
+
+    /// Same as
+    /// [`try_parse_raw_data`][FlirSegment::try_parse_raw_data],
+    /// but for a record of 14-bit packed values; see
+    /// [`FlirRecordDirEntry::try_parse_packed_raw_data`].
+    pub fn try_parse_packed_raw_data(&self) -> Result<Option<Array2<f64>>> {
+        self.dir
+            .iter()
+            .find_map(|e| {
+                e.parse_raw_data(
+                    &self.data,
+                    self.forced_endianness,
+                    self.limits.max_pixels,
+                    true,
+                )
+                .transpose()
+            })
             .transpose()
     }
 
@@ -71,42 +183,357 @@ impl FlirSegment {
     pub fn try_parse_camera_params(&self) -> Result<Option<FlirCameraParams>> {
         self.dir
             .iter()
-            .find_map(|e| e.try_parse_camera_params(&self.data).transpose())
+            .find_map(|e| {
+                e.try_parse_camera_params(&self.data, self.forced_endianness)
+                    .transpose()
+            })
             .transpose()
     }
 
-    fn try_from_segment_data(data: Vec<u8>) -> Result<Self> {
-        parse_as_bindings! {
-            ByteOrdered::native(&data[..]),
-            signature => [u8; 4],
-            _creator as "creator" => [u8; 16],
-            version => u32,
-        }
-
-        ensure!(&signature == b"FFF\0", "unexpected signature");
-
-        // A heuristic to find if header data is LE or BE:
-        // check that version is in [100, 200).
-        let endianness = {
-            let end = Endianness::native();
-            if (100..200).contains(&version) {
-                end
-            } else {
-                end.to_opposite()
+    /// Try to find and parse the GPS information record.
+    /// Returns `None` if not found, or if the camera
+    /// marked the recorded position as invalid.
+    pub fn try_parse_gps(&self) -> Result<Option<FlirGpsInfo>> {
+        self.dir
+            .iter()
+            .find_map(|e| e.try_parse_gps(&self.data, self.endianness).transpose())
+            .transpose()
+    }
+
+    /// Try to find and parse the original date / time of
+    /// capture from the camera params record. Returns `None`
+    /// if not found or not recorded.
+    pub fn try_parse_datetime(&self) -> Result<Option<DateTime<FixedOffset>>> {
+        self.dir
+            .iter()
+            .find_map(|e| {
+                e.try_parse_datetime(&self.data, self.forced_endianness)
+                    .transpose()
+            })
+            .transpose()
+    }
+
+    /// Try to find and parse the focus distance (in meters)
+    /// from the camera params record. Returns `None` if not
+    /// found or not recorded.
+    pub fn try_parse_focus_distance(&self) -> Result<Option<f64>> {
+        self.dir
+            .iter()
+            .find_map(|e| {
+                e.try_parse_focus_distance(&self.data, self.forced_endianness)
+                    .transpose()
+            })
+            .transpose()
+    }
+
+    /// Try to find and parse the palette record. Returns
+    /// `None` if not found.
+    pub fn try_parse_palette(&self) -> Result<Option<FlirPalette>> {
+        self.dir
+            .iter()
+            .find_map(|e| e.try_parse_palette(&self.data).transpose())
+            .transpose()
+    }
+
+    /// Try to find the embedded (visual) image record, and
+    /// return the image data. Returns `None` if not found.
+    pub fn try_parse_embedded_image(&self) -> Result<Option<Vec<u8>>> {
+        self.dir
+            .iter()
+            .find_map(|e| e.try_parse_embedded_image(&self.data).transpose())
+            .transpose()
+    }
+
+    /// Parse all the supported records in a single pass over
+    /// the record directory. Each field is the first record of
+    /// its kind, if any; the first record that fails to parse
+    /// fails the whole, except for the auxiliary GPS, date /
+    /// time and focus distance which are then `None` (as in
+    /// [`ThermalImage::try_from_flir_segment`]).
+    ///
+    /// [`ThermalImage::try_from_flir_segment`]: crate::ThermalImage::try_from_flir_segment
+    pub fn parse_all(&self) -> Result<ParsedFlir> {
+        let endianness = self.forced_endianness;
+        let mut parsed = ParsedFlir::default();
+        for e in &self.dir {
+            match e.ty {
+                0x01 if parsed.raw.is_none() => {
+                    parsed.raw =
+                        e.parse_raw_data(&self.data, endianness, self.limits.max_pixels, false)?;
+                }
+                0x0e if parsed.embedded_image.is_none() => {
+                    parsed.embedded_image = e.try_parse_embedded_image(&self.data)?;
+                }
+                0x20 if parsed.camera_params.is_none() => {
+                    parsed.camera_params = e.try_parse_camera_params(&self.data, endianness)?;
+                    parsed.datetime = e.try_parse_datetime(&self.data, endianness).ok().flatten();
+                    parsed.focus_distance = e
+                        .try_parse_focus_distance(&self.data, endianness)
+                        .ok()
+                        .flatten();
+                }
+                0x22 if parsed.palette.is_none() => {
+                    parsed.palette = e.try_parse_palette(&self.data)?;
+                }
+                0x2b if parsed.gps.is_none() => {
+                    parsed.gps = e.try_parse_gps(&self.data, self.endianness).ok().flatten();
+                }
+                _ => {}
             }
-        };
+        }
+        Ok(parsed)
+    }
 
-        parse_as_bindings! {
-            ByteOrdered::runtime(&data[0x18..], endianness),
-            offset => u32 as usize,
-            num_records => u32 as usize,
+    /// The file format version from the header (`100` in
+    /// the files seen so far). Useful when reporting files
+    /// that fail to parse.
+    pub fn format_version(&self) -> u32 {
+        self.format_version
+    }
+
+    /// The entries of the record directory.
+    pub fn records(&self) -> &[FlirRecordDirEntry] {
+        &self.dir
+    }
+
+    /// Summary of the record directory as `(type, sub-type,
+    /// version, length)` per entry. Useful to survey the
+    /// record types written by a camera; see the
+    /// `flir_records` example.
+    pub fn record_summary(&self) -> Vec<(u16, u16, u32, usize)> {
+        self.dir
+            .iter()
+            .map(|e| (e.ty, e.sub_type, e.version, e.length as usize))
+            .collect()
+    }
+
+    /// Replace the values of the raw sensor values record
+    /// with `raw`, a `(height, width)` array of the same
+    /// dimensions as the record. The values are rounded and
+    /// clamped to 16-bit; non-finite values are written as
+    /// `0`. The layout and byte-order of the record are kept.
+    pub fn set_raw_data(&mut self, raw: &Array2<f64>) -> Result<()> {
+        let entry = self
+            .dir
+            .iter()
+            .find(|e| e.ty == 0x01)
+            .ok_or(ParseError::NoRawData)?;
+        let current = entry
+            .parse_raw_data(
+                &self.data,
+                self.forced_endianness,
+                self.limits.max_pixels,
+                false,
+            )?
+            .ok_or(ParseError::NoRawData)?;
+        ensure!(
+            current.dim() == raw.dim(),
+            "raw data dimensions mismatch: expected {:?}, found {:?}",
+            current.dim(),
+            raw.dim()
+        );
+
+        let data = entry.data(&self.data)?;
+        let endianness = record_endianness(data, self.forced_endianness)?;
+        let start = entry.offset as usize + 0x20;
+        let end = entry.offset as usize + data.len();
+        for (bytes, &val) in self.data[start..end].chunks_exact_mut(2).zip(raw.iter()) {
+            let val = val.round().clamp(0., u16::MAX as f64) as u16;
+            bytes.copy_from_slice(&match endianness {
+                Endianness::Little => val.to_le_bytes(),
+                Endianness::Big => val.to_be_bytes(),
+            });
         }
+        Ok(())
+    }
 
-        let mut reader = ByteOrdered::runtime(&data[offset..], endianness);
-        let dir: Result<_> = (0..num_records)
-            .map(|_| FlirRecordDirEntry::parse(&mut reader))
-            .collect();
-        Ok(FlirSegment { data, dir: dir? })
+    /// A copy of `template` with its FLIR segments replaced
+    /// by this data, split into as many APP1 segments as
+    /// needed. The other segments (eg. the EXIF metadata, and
+    /// the displayed image) are kept as is.
+    pub fn write_into_jpeg(&self, template: &Jpeg) -> Result<Jpeg> {
+        // The segment length, and the FLIR header, take 10
+        // bytes of the 16-bit segment length.
+        const MAX_PAYLOAD: usize = 0xffff - 10;
+        let chunks: Vec<_> = self.data.chunks(MAX_PAYLOAD).collect();
+        ensure!(
+            chunks.len() <= 256,
+            "FLIR data too large for a Jpeg: {} bytes",
+            self.data.len()
+        );
+
+        let mut jpeg = template.clone();
+        let segments = jpeg.segments_mut();
+        let pos = segments
+            .iter()
+            .position(is_flir_app1)
+            .ok_or(ParseError::NotRadiometric)?;
+        segments.retain(|segment| !is_flir_app1(segment));
+
+        let last = (chunks.len() - 1) as u8;
+        let flir_segments = chunks.iter().enumerate().map(|(idx, chunk)| {
+            let mut contents = b"FLIR\0\x01".to_vec();
+            contents.extend_from_slice(&[idx as u8, last]);
+            contents.extend_from_slice(chunk);
+            JpegSegment::new_with_contents(markers::APP1, contents.into())
+        });
+        segments.splice(pos..pos, flir_segments);
+        Ok(jpeg)
+    }
+
+    /// Parse FFF data directly, eg. a frame of a `.seq` file
+    /// (see [`seq`][crate::seq]). The data must start with
+    /// the FFF header.
+    pub fn try_from_fff_bytes(data: Vec<u8>) -> Result<Self> {
+        Self::try_from_segment_data(data, None)
+    }
+
+    /// Length of the FFF header and the record directory at
+    /// the start of `data`; only the header (the first `0x40`
+    /// bytes) is read. This many bytes are needed to find the
+    /// [`fff_length`][FlirSegment::fff_length].
+    pub fn fff_header_length(data: &[u8]) -> Result<usize> {
+        let (_, _, offset, num_records) = parse_fff_preamble(data)?;
+        let dir_len = FLIR_RECORD_DIR_ENTRY_SIZE * num_records;
+        check_limit(
+            "record directory length",
+            dir_len,
+            ParseLimits::default().max_record_len,
+        )?;
+        Ok(FFF_HEADER_SIZE.max(offset + dir_len))
+    }
+
+    /// Length of the FFF data at the start of `data`: up to
+    /// the end of the record directory, or of the last
+    /// record, whichever is later. Used to split the frames
+    /// of a `.seq` file.
+    pub fn fff_length(data: &[u8]) -> Result<usize> {
+        let FffHeader { dir_end, dir, .. } = parse_fff_header(data)?;
+        let records_end = dir
+            .iter()
+            .map(|e| e.offset as usize + e.length as usize)
+            .max()
+            .unwrap_or(0);
+        Ok(dir_end.max(records_end))
+    }
+
+    fn try_from_segment_data(data: Vec<u8>, forced_endianness: Option<Endianness>) -> Result<Self> {
+        Self::try_from_segment_data_with_limits(data, forced_endianness, ParseLimits::default())
+    }
+
+    fn try_from_segment_data_with_limits(
+        data: Vec<u8>,
+        forced_endianness: Option<Endianness>,
+        limits: ParseLimits,
+    ) -> Result<Self> {
+        let FffHeader {
+            endianness,
+            format_version,
+            dir,
+            ..
+        } = parse_fff_header(&data)?;
+        for entry in &dir {
+            check_limit(
+                "record length",
+                entry.length as usize,
+                limits.max_record_len,
+            )?;
+        }
+        Ok(FlirSegment {
+            data,
+            endianness,
+            forced_endianness,
+            format_version,
+            dir,
+            limits,
+        })
+    }
+}
+
+const FFF_HEADER_SIZE: usize = 0x40;
+
+/// The FFF header and the record directory.
+struct FffHeader {
+    endianness: Endianness,
+    format_version: u32,
+    /// End offset of the record directory.
+    dir_end: usize,
+    dir: Vec<FlirRecordDirEntry>,
+}
+
+/// The byte-order and version of the FFF header, and the
+/// offset and number of entries of the record directory.
+fn parse_fff_preamble(data: &[u8]) -> Result<(Endianness, u32, usize, usize)> {
+    ensure!(
+        data.len() >= FFF_HEADER_SIZE,
+        ParseError::TruncatedOrCorrupt {
+            expected: FFF_HEADER_SIZE,
+            found: data.len(),
+        }
+    );
+    parse_as_bindings! {
+        ByteOrdered::native(data),
+        signature => [u8; 4],
+        _creator as "creator" => [u8; 16],
+        version => u32,
+    }
+
+    ensure!(&signature == b"FFF\0", "unexpected signature");
+
+    // A heuristic to find if header data is LE or BE:
+    // check that version is in [100, 200).
+    let endianness = if (100..200).contains(&version) {
+        Endianness::native()
+    } else {
+        Endianness::native().to_opposite()
+    };
+
+    parse_as_bindings! {
+        ByteOrdered::runtime(&data[0x18..], endianness),
+        offset => u32 as usize,
+        num_records => u32 as usize,
+    }
+    Ok((endianness, version, offset, num_records))
+}
+
+fn parse_fff_header(data: &[u8]) -> Result<FffHeader> {
+    let (endianness, version, offset, num_records) = parse_fff_preamble(data)?;
+    let dir_data = data
+        .get(offset..)
+        .ok_or_else(|| anyhow!("record directory offset out of bounds: {:#x}", offset))?;
+    let mut reader = ByteOrdered::runtime(dir_data, endianness);
+    let dir: Result<Vec<_>> = (0..num_records)
+        .map(|_| FlirRecordDirEntry::parse(&mut reader))
+        .collect();
+    let format_version = if endianness == Endianness::native() {
+        version
+    } else {
+        version.swap_bytes()
+    };
+    Ok(FffHeader {
+        endianness,
+        format_version,
+        dir_end: offset + FLIR_RECORD_DIR_ENTRY_SIZE * num_records,
+        dir: dir?,
+    })
+}
+
+/// Lists the records in the directory, one per line. Useful
+/// to inspect files that fail to parse.
+impl fmt::Display for FlirSegment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "FLIR segment: {} bytes, {:?}, format version {}, {} records",
+            self.data.len(),
+            self.endianness,
+            self.format_version,
+            self.dir.len()
+        )?;
+        for entry in &self.dir {
+            writeln!(f, "  {}", entry)?;
+        }
+        Ok(())
     }
 }
 
@@ -122,65 +549,184 @@ impl FlirSegment {
 /// - 0x7: last segment number (= total segments - 1)
 /// - 0x8..: data
 ///
-/// The logic is exactly as in [ExifTool.pm]. We iterate
-/// through all APP1 segments; check each for the signature;
-/// verify the segment idx, total are consistent; and return
-/// the concatenated payload.
+/// The logic is based on [ExifTool.pm]. We iterate through
+/// all APP1 segments; check each for the signature; and
+/// return the concatenated payload.
+///
+/// Some (eg. multi-spectral) cameras write more than one
+/// FLIR stream, possibly interleaved. So, instead of
+/// assuming a single global count, segments are grouped
+/// into streams, each with its own count. The segments of a
+/// stream are expected in order: a segment with idx `0`
+/// starts a new stream, and any other is appended to the
+/// oldest stream (with the same count) waiting for that
+/// idx. A stream is closed once complete. A segment
+/// identical to one already collected (with the same idx
+/// and count) is ignored, as are segments no stream is
+/// waiting for. The first complete stream is returned.
 ///
 /// [ExifTool.pm]: //github.com/exiftool/exiftool/blob/master/lib/Image/ExifTool.pm
-fn collect_flir_segment_data_from_jpeg(image: &Jpeg) -> Result<Vec<u8>> {
-    let mut flir_segments: Vec<Vec<u8>> = vec![];
-    let mut num_copied = 0;
-    let mut total_len = 0;
+fn collect_flir_segment_data_from_jpeg(image: &Jpeg, limits: &ParseLimits) -> Result<Vec<u8>> {
+    collect_flir_segment_data(
+        image
+            .segments_by_marker(markers::APP1)
+            .map(|segment| &segment.contents()[..]),
+        limits,
+    )
+}
+
+/// The image rendered by the camera (with its palette and
+/// range) as Jpeg bytes: the R-JPEG itself, without the FLIR
+/// segments. Use this to show images (eg. in a grid view)
+/// without rendering the temperatures.
+///
+/// FLIR files have no separate preview record: the visual
+/// image record (if any) is the photo from the visual
+/// camera, not a rendering of the thermal data. So the
+/// preview is at the full resolution of the R-JPEG, and not
+/// a thumbnail; it is re-encoded (without decoding the
+/// image data), and is much smaller than the R-JPEG as the
+/// radiometric data is usually most of the file.
+///
+/// Fails with [`ParseError::NotRadiometric`] if there are
+/// no FLIR segments.
+pub fn rendered_preview(image: &Jpeg) -> Result<Vec<u8>> {
+    ensure!(
+        image.segments().iter().any(is_flir_app1),
+        ParseError::NotRadiometric
+    );
+    let mut preview = image.clone();
+    preview
+        .segments_mut()
+        .retain(|segment| !is_flir_app1(segment));
+    Ok(preview.encoder().bytes().to_vec())
+}
+
+fn is_flir_app1(segment: &JpegSegment) -> bool {
+    segment.marker() == markers::APP1 && segment.contents().starts_with(b"FLIR\0")
+}
+
+/// Contents of the APP1 segments of a Jpeg file, up to the
+/// start of the (entropy coded) image data.
+fn jpeg_app1_segments(bytes: &[u8]) -> Result<Vec<&[u8]>> {
+    ensure!(
+        bytes.starts_with(&[markers::P, markers::SOI]),
+        "not a Jpeg: missing SOI marker"
+    );
+
+    let mut segments = vec![];
+    let mut pos = 2;
+    loop {
+        ensure!(
+            bytes.get(pos) == Some(&markers::P),
+            "invalid Jpeg marker at offset {}",
+            pos
+        );
+        // Markers may be preceded by any number of fill
+        // bytes.
+        while bytes.get(pos) == Some(&markers::P) {
+            pos += 1;
+        }
+        let marker = match bytes.get(pos) {
+            Some(&marker) => marker,
+            None => bail!("unexpected EOF while reading Jpeg"),
+        };
+        pos += 1;
+
+        match marker {
+            markers::SOS | markers::EOI => break,
+            // Standalone markers (TEM, RSTn) have no length.
+            0x01 | markers::RST0..=markers::RST7 => continue,
+            _ => {}
+        }
+
+        let len = match bytes.get(pos..pos + 2) {
+            Some(len) => u16::from_be_bytes([len[0], len[1]]) as usize,
+            None => bail!("unexpected EOF while reading Jpeg"),
+        };
+        ensure!(len >= 2, "invalid Jpeg segment length: {}", len);
+        let contents = bytes
+            .get(pos + 2..pos + len)
+            .ok_or_else(|| anyhow!("unexpected EOF while reading Jpeg"))?;
+        if marker == markers::APP1 {
+            segments.push(contents);
+        }
+        pos += len;
+    }
+    Ok(segments)
+}
+
+fn collect_flir_segment_data<'a, I: Iterator<Item = &'a [u8]>>(
+    app1_contents: I,
+    limits: &ParseLimits,
+) -> Result<Vec<u8>> {
+    let mut streams: Vec<FlirStream> = vec![];
 
-    for segment in image.segments_by_marker(markers::APP1) {
-        let contents = segment.contents();
+    let mut num_segments = 0;
+    for contents in app1_contents {
         if contents.len() < 8 || &contents[0..5] != b"FLIR\0" {
             continue;
         }
+        num_segments += 1;
+        check_limit("segments", num_segments, limits.max_segments)?;
 
         let current_segment = contents[6] as usize;
         let total_segments = contents[7] as usize + 1;
+        ensure!(
+            current_segment < total_segments,
+            "FLIR segment idx out of bounds: {} >= {}",
+            current_segment,
+            total_segments
+        );
 
-        match flir_segments.len() {
-            0 => flir_segments.resize(total_segments, vec![]),
-            l if l != total_segments => bail!(
-                "inconsistent count of total FLIR segments: {} != {}",
-                l,
-                total_segments
-            ),
-            l if l <= current_segment as usize => bail!(
-                "FLIR segment idx out of bounds: {} >= {}",
-                current_segment,
-                l
-            ),
-            _ => (),
+        let payload = &contents[8..];
+        let waiting = streams
+            .iter_mut()
+            .find(|s| s.total == total_segments && s.segments.len() == current_segment);
+        if let Some(stream) = waiting {
+            stream.segments.push(payload);
+            continue;
         }
 
-        let curr_seg = &mut flir_segments[current_segment as usize];
-        ensure!(
-            curr_seg.is_empty(),
-            "duplicate FLIR segment: idx = {}",
-            current_segment
-        );
+        // Some cameras write a segment twice: skip
+        // byte-identical duplicates, which would otherwise
+        // start a new (incomplete) stream.
+        let is_duplicate = streams.iter().any(|s| {
+            s.total == total_segments && s.segments.get(current_segment) == Some(&payload)
+        });
+        if !is_duplicate && current_segment == 0 {
+            streams.push(FlirStream {
+                total: total_segments,
+                segments: vec![payload],
+            });
+        }
+    }
 
-        curr_seg.extend_from_slice(&contents[8..]);
-        num_copied += 1;
-        total_len += curr_seg.len();
+    if num_segments == 0 {
+        return Err(ParseError::NotRadiometric.into());
     }
 
-    ensure!(
-        num_copied == flir_segments.len(),
-        "expected {} FLIR segments, found only {}",
-        flir_segments.len(),
-        num_copied
-    );
+    let stream = match streams.iter().find(|s| s.segments.len() == s.total) {
+        Some(stream) => stream,
+        None => match streams.first() {
+            Some(stream) => bail!(
+                "expected {} FLIR segments, found only {}",
+                stream.total,
+                stream.segments.len()
+            ),
+            None => bail!("first FLIR segment not found"),
+        },
+    };
 
-    let mut flir_data = Vec::with_capacity(total_len);
-    for seg in flir_segments {
-        flir_data.extend_from_slice(&seg);
-    }
-    Ok(flir_data)
+    Ok(stream.segments.concat())
+}
+
+/// A FLIR stream being collected: it is waiting for the
+/// segment with idx `segments.len()`, until it has `total`
+/// segments.
+struct FlirStream<'a> {
+    total: usize,
+    segments: Vec<&'a [u8]>,
 }
 
 // # FLIR record entry (ref 3):
@@ -193,32 +739,104 @@ fn collect_flir_segment_data_from_jpeg(image: &Jpeg) -> Result<Vec<u8>> {
 // # 0x14 - int32u parent = 0 (?)
 // # 0x18 - int32u object number = 0 (?)
 // # 0x1c - int32u checksum: 0 for no checksum
+const FLIR_RECORD_DIR_ENTRY_SIZE: usize = 0x20;
+
 declare_parseable_struct! {
     /// Details of a FLIR record
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct FlirRecordDirEntry {
-        ty => u16,
-        sub_type=> u16,
-        version=> u32,
+        pub ty => u16,
+        pub sub_type=> u16,
+        pub version=> u32,
 
         id=> u32,
-        offset=> u32,
-        length=> u32,
+        pub offset=> u32,
+        pub length=> u32,
 
         parent=> u32,
         obj_num=> u32,
         checksum=> u32,
     }
 }
+impl fmt::Display for FlirRecordDirEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "type: {:#06x}, sub-type: {}, offset: {:#x}, length: {}",
+            self.ty, self.sub_type, self.offset, self.length
+        )
+    }
+}
+
 impl FlirRecordDirEntry {
     /// Get the data associated with this record
     pub fn data<'a>(&self, segment: &'a [u8]) -> Result<&'a [u8]> {
-        segment
-            .get(self.offset as usize..(self.offset + self.length) as usize)
-            .ok_or_else(|| anyhow!("unexpected EOF while reading data"))
+        let start = self.offset as usize;
+        let end = start
+            .checked_add(self.length as usize)
+            .ok_or_else(|| anyhow!("record length overflows: {}", self))?;
+        segment.get(start..end).ok_or_else(|| {
+            ParseError::TruncatedOrCorrupt {
+                expected: end,
+                found: segment.len(),
+            }
+            .into()
+        })
+    }
+
+    /// Parse the raw sensor values record (type `0x01`) as a
+    /// `(height, width)` array.
+    ///
+    /// The values are assumed to be stored row-major:
+    /// `width` values of the first row, followed by the next
+    /// row, and so on. No camera is known to store them
+    /// otherwise; the swap pattern of the FFF header is a
+    /// byte-order flag, not the orientation.
+    ///
+    /// The byte-order is read from the record, unless
+    /// `endianness` is given.
+    ///
+    /// The values are 16-bit: a record of any other length
+    /// is [`ParseError::TruncatedOrCorrupt`]. See
+    /// [`try_parse_packed_raw_data`][FlirRecordDirEntry::try_parse_packed_raw_data]
+    /// to read it as 14-bit packed values instead.
+    pub fn try_parse_raw_data(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+    ) -> Result<Option<Array2<f64>>> {
+        let max_pixels = ParseLimits::default().max_pixels;
+        self.parse_raw_data(segment, endianness, max_pixels, false)
     }
 
-    pub fn try_parse_raw_data(&self, segment: &[u8]) -> Result<Option<Array2<f64>>> {
+    /// Parse the raw sensor values record (type `0x01`) as
+    /// 14-bit packed values: `width * height * 14 / 8`
+    /// (rounded up) bytes after the header, instead of
+    /// `width * height * 2`.
+    ///
+    /// This is opt-in, and the layout is unverified: ExifTool
+    /// does not document a packed layout, and no sample file
+    /// is known. The values are read as a continuous bit
+    /// stream, least significant bits first for little-endian
+    /// records, and most significant first for big-endian. A
+    /// record of any other length is
+    /// [`ParseError::TruncatedOrCorrupt`].
+    pub fn try_parse_packed_raw_data(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+    ) -> Result<Option<Array2<f64>>> {
+        let max_pixels = ParseLimits::default().max_pixels;
+        self.parse_raw_data(segment, endianness, max_pixels, true)
+    }
+
+    fn parse_raw_data(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+        max_pixels: usize,
+        packed: bool,
+    ) -> Result<Option<Array2<f64>>> {
         if self.ty != 0x01 {
             return Ok(None);
         }
@@ -232,18 +850,7 @@ impl FlirRecordDirEntry {
             data.len(),
         );
 
-        let endianness = {
-            parse_as_bindings! {
-                ByteOrdered::native(&data[0..2]),
-                check_val => u16,
-            }
-            let end = Endianness::native();
-            if check_val == 2 {
-                end
-            } else {
-                end.to_opposite()
-            }
-        };
+        let endianness = record_endianness(data, endianness)?;
 
         parse_as_bindings! {
             ByteOrdered::runtime(&data[2..], endianness),
@@ -251,25 +858,54 @@ impl FlirRecordDirEntry {
             height => u16 as usize,
         }
 
-        let expected = 2 * (16 + width * height);
+        // Reject absurd dimensions (from a corrupt header)
+        // before allocating.
+        ensure!(
+            width <= MAX_RAW_DIMENSION && height <= MAX_RAW_DIMENSION,
+            "implausible raw data dimensions: {}x{}",
+            width,
+            height
+        );
+        check_limit("pixels", width * height, max_pixels)?;
+        let pixels = width * height;
+        let expected = if packed {
+            Some(0x20 + (pixels * 14).div_ceil(8))
+        } else {
+            pixels
+                .checked_add(16)
+                .and_then(|words| words.checked_mul(2))
+        }
+        .ok_or_else(|| anyhow!("raw data dimensions overflow: {}x{}", width, height))?;
         ensure!(
             data.len() == expected,
-            "raw data record size mismatch: expected {} bytes, found {}",
-            expected,
-            data.len()
+            ParseError::TruncatedOrCorrupt {
+                expected,
+                found: data.len(),
+            }
         );
 
-        let mut reader = ByteOrdered::runtime(&data[0x20..], endianness);
-        let mut raw_data = Vec::with_capacity(width * height);
-        for _ in 0..height {
-            for _ in 0..width {
+        let raw_data = if packed {
+            unpack_14bit(&data[0x20..], pixels, endianness)
+        } else {
+            let mut reader = ByteOrdered::runtime(&data[0x20..], endianness);
+            let mut raw_data = Vec::with_capacity(pixels);
+            for _ in 0..pixels {
                 raw_data.push(u16::parse(&mut reader)? as f64);
             }
-        }
+            raw_data
+        };
 
         Ok(Some(Array2::from_shape_vec((height, width), raw_data)?))
     }
-    pub fn try_parse_camera_params(&self, segment: &[u8]) -> Result<Option<FlirCameraParams>> {
+    /// Parse the camera params record (type `0x20`). The
+    /// byte-order is read from the record, unless
+    /// `endianness` is given. Fails if the Planck constants
+    /// are implausible, eg. for a record of another layout.
+    pub fn try_parse_camera_params(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+    ) -> Result<Option<FlirCameraParams>> {
         if self.ty != 0x20 {
             return Ok(None);
         }
@@ -278,28 +914,37 @@ impl FlirRecordDirEntry {
 
         ensure!(
             data.len() >= 0x384,
-            "raw data record size mismatch: expected at least {} bytes, found {}",
+            "camera params record size mismatch: expected at least {} bytes, found {}",
             0x384,
             data.len()
         );
 
-        let endianness = {
-            parse_as_bindings! {
-                ByteOrdered::native(&data[0..2]),
-                check_val => u16,
-            }
-            let end = Endianness::native();
-            if check_val == 2 {
-                end
-            } else {
-                end.to_opposite()
-            }
-        };
+        let endianness = record_endianness(data, endianness)?;
 
         parse_as_bindings! {
             ByteOrdered::runtime(&data[0x20..], endianness),
             temperature_params => FlirTemperatureParams,
         }
+        // The length alone does not identify the layout: a
+        // record with the same type but a different layout
+        // (eg. from other firmware) has garbage constants.
+        let FlirTemperatureParams {
+            planck_r1,
+            planck_b,
+            planck_f,
+            ..
+        } = temperature_params;
+        ensure!(
+            planck_b.is_finite() && planck_b > 0.,
+            "params record found but planck_b implausible: {}",
+            planck_b
+        );
+        ensure!(
+            planck_r1.is_finite() && planck_r1 != 0. && planck_f.is_finite(),
+            "params record found but planck constants implausible: r1 = {}, f = {}",
+            planck_r1,
+            planck_f
+        );
         parse_as_bindings! {
             ByteOrdered::runtime(&data[0xd4..], endianness),
             camera_info => FlirCameraInfo,
@@ -325,10 +970,271 @@ impl FlirRecordDirEntry {
             extra_params,
         }))
     }
+
+    /// Parse the original date / time of capture from the
+    /// camera params record (type `0x20`), stored at offset
+    /// `0x384` as in the `FLIR::CameraInfo` table of
+    /// ExifTool. Older cameras write shorter records without
+    /// it.
+    pub fn try_parse_datetime(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+    ) -> Result<Option<DateTime<FixedOffset>>> {
+        if self.ty != 0x20 {
+            return Ok(None);
+        }
+
+        let data = self.data(segment)?;
+        if data.len() < 0x38e {
+            return Ok(None);
+        }
+
+        let endianness = record_endianness(data, endianness)?;
+        parse_as_bindings! {
+            ByteOrdered::runtime(&data[0x384..], endianness),
+            datetime => FlirDateTime,
+        }
+        datetime.to_datetime()
+    }
+
+    /// Parse the focus distance (in meters) from the camera
+    /// params record (type `0x20`), stored as a `f32` at
+    /// offset `0x45c` (`FocusDistance` in the
+    /// `FLIR::CameraInfo` table of ExifTool; `0x464` is the
+    /// `FrameRate`). Older cameras write shorter records
+    /// without it. A value of `0.0` (or garbage) means
+    /// unknown.
+    pub fn try_parse_focus_distance(
+        &self,
+        segment: &[u8],
+        endianness: Option<Endianness>,
+    ) -> Result<Option<f64>> {
+        if self.ty != 0x20 {
+            return Ok(None);
+        }
+
+        let data = self.data(segment)?;
+        if data.len() < 0x460 {
+            return Ok(None);
+        }
+
+        let endianness = record_endianness(data, endianness)?;
+        parse_as_bindings! {
+            ByteOrdered::runtime(&data[0x45c..], endianness),
+            focus_distance => f32 as f64,
+        }
+        Ok(crate::temperature::valid_distance(focus_distance))
+    }
+
+    /// Parse the GPS info record (type `0x2b`). Unlike the
+    /// raw data and camera params records, this record has
+    /// no byte-order marker and uses the byte-order of the
+    /// FLIR header.
+    pub fn try_parse_gps(
+        &self,
+        segment: &[u8],
+        endianness: Endianness,
+    ) -> Result<Option<FlirGpsInfo>> {
+        if self.ty != 0x2b {
+            return Ok(None);
+        }
+
+        let data = self.data(segment)?;
+        parse_as_bindings! {
+            ByteOrdered::runtime(data, endianness),
+            gps_info => FlirGpsInfo,
+        }
+
+        if gps_info.valid == 0 {
+            return Ok(None);
+        }
+        Ok(Some(gps_info))
+    }
+
+    /// Parse the embedded image record (type `0x0e`): the
+    /// photo from the visual camera, if the camera has one.
+    /// The image data (usually a JPEG, or a PNG) starts after
+    /// a `0x20` byte header, as in the `FLIR::EmbeddedImage`
+    /// table of ExifTool, and is returned as is.
+    pub fn try_parse_embedded_image(&self, segment: &[u8]) -> Result<Option<Vec<u8>>> {
+        if self.ty != 0x0e {
+            return Ok(None);
+        }
+
+        let data = self.data(segment)?;
+        ensure!(
+            data.len() > 0x20,
+            ParseError::TruncatedOrCorrupt {
+                expected: 0x21,
+                found: data.len(),
+            }
+        );
+        Ok(Some(data[0x20..].to_vec()))
+    }
+
+    /// Parse the palette record (type `0x22`), laid out as in
+    /// the `FLIR::PaletteInfo` table of ExifTool. The record
+    /// only has single byte fields, so there is no
+    /// byte-order to detect.
+    pub fn try_parse_palette(&self, segment: &[u8]) -> Result<Option<FlirPalette>> {
+        if self.ty != 0x22 {
+            return Ok(None);
+        }
+
+        let data = self.data(segment)?;
+        parse_as_bindings! {
+            ByteOrdered::native(data),
+            info => FlirPaletteInfo,
+        }
+
+        let colors_end = 0x70 + 3 * info.num_colors as usize;
+        ensure!(
+            data.len() >= colors_end,
+            ParseError::TruncatedOrCorrupt {
+                expected: colors_end,
+                found: data.len(),
+            }
+        );
+        let colors = data[0x70..colors_end]
+            .chunks_exact(3)
+            .map(|c| ycrcb_to_rgb([c[0], c[1], c[2]]))
+            .collect();
+
+        Ok(Some(FlirPalette {
+            name: decode_fixed_string(&info.name),
+            file_name: decode_fixed_string(&info.file_name),
+            colors,
+            info,
+        }))
+    }
+}
+
+/// Decode a `string[N]` field: it is NUL terminated
+/// (unless it fills the field), and may be followed by
+/// garbage. Bytes that are not valid UTF-8 are replaced with
+/// `U+FFFD`.
+pub fn decode_fixed_string(bytes: &[u8]) -> String {
+    let len = bytes.iter().position(|&b| b == 0).unwrap_or(bytes.len());
+    String::from_utf8_lossy(&bytes[..len]).into_owned()
+}
+
+/// Convert a color from the `Y Cr Cb` components stored in
+/// the palette record to RGB (using the full range JPEG
+/// conversion).
+pub fn ycrcb_to_rgb([y, cr, cb]: [u8; 3]) -> [u8; 3] {
+    let (y, cr, cb) = (y as f64, cr as f64 - 128., cb as f64 - 128.);
+    let clamp = |val: f64| val.round().clamp(0., 255.) as u8;
+    [
+        clamp(y + 1.402 * cr),
+        clamp(y - 0.344136 * cb - 0.714136 * cr),
+        clamp(y + 1.772 * cb),
+    ]
+}
+
+/// All the records parsed by
+/// [`parse_all`][FlirSegment::parse_all]. Each field is
+/// `None` if the record is not found (or, for the date /
+/// time, GPS position and focus distance, not recorded).
+#[derive(Debug, Clone, Default)]
+pub struct ParsedFlir {
+    /// Raw sensor values with shape `(height, width)`.
+    pub raw: Option<Array2<f64>>,
+    pub camera_params: Option<FlirCameraParams>,
+    pub gps: Option<FlirGpsInfo>,
+    pub datetime: Option<DateTime<FixedOffset>>,
+    /// Focus distance in meters.
+    pub focus_distance: Option<f64>,
+    pub palette: Option<FlirPalette>,
+    /// Data of the embedded visual image; see
+    /// [`try_parse_embedded_image`][FlirRecordDirEntry::try_parse_embedded_image].
+    pub embedded_image: Option<Vec<u8>>,
+}
+
+/// Palette used by the camera to render the image.
+///
+/// The record does not store the temperatures the palette
+/// spans: the camera spreads it over the range of raw
+/// values recorded for the image (see
+/// [`FlirExtraParams::raw_value_range`]).
+#[derive(Debug, Clone)]
+pub struct FlirPalette {
+    /// Name of the palette (eg. `iron`).
+    pub name: String,
+    /// File name of the palette on the camera.
+    pub file_name: String,
+    /// Colors (in RGB) from the coldest to the hottest.
+    pub colors: Vec<[u8; 3]>,
+    /// The other fields of the record. Colors in it are in
+    /// `Y Cr Cb`; see [`ycrcb_to_rgb`].
+    pub info: FlirPaletteInfo,
+}
+
+/// Byte-order of a record with a leading byte-order marker:
+/// the first `u16` is `2` in the byte-order of the record.
+/// The `forced` byte-order, if given, is used instead.
+///
+/// The byte-order of a record need not match that of the
+/// FLIR header (many cameras write a big-endian header, and
+/// little-endian records), so it is detected per record as
+/// in ExifTool. A marker that is not `2` in either
+/// byte-order is an error: the record is then corrupt, and
+/// parsing it would only produce garbage.
+fn record_endianness(data: &[u8], forced: Option<Endianness>) -> Result<Endianness> {
+    if let Some(endianness) = forced {
+        return Ok(endianness);
+    }
+    parse_as_bindings! {
+        ByteOrdered::native(data),
+        check_val => u16,
+    }
+    let end = Endianness::native();
+    Ok(match check_val {
+        2 => end,
+        0x0200 => end.to_opposite(),
+        _ => bail!("invalid record byte-order marker: {:#06x}", check_val),
+    })
+}
+
+/// Unpack `count` 14-bit values from a bit stream; see
+/// [`FlirRecordDirEntry::try_parse_packed_raw_data`].
+fn unpack_14bit(data: &[u8], count: usize, endianness: Endianness) -> Vec<f64> {
+    const MASK: u32 = (1 << 14) - 1;
+    let mut values = Vec::with_capacity(count);
+    // Bits read, but not yet unpacked.
+    let (mut acc, mut bits) = (0u32, 0);
+    for &byte in data {
+        match endianness {
+            Endianness::Little => acc |= (byte as u32) << bits,
+            Endianness::Big => acc = (acc << 8) | byte as u32,
+        }
+        bits += 8;
+        if bits < 14 {
+            continue;
+        }
+        bits -= 14;
+        let val = match endianness {
+            Endianness::Little => {
+                let val = acc & MASK;
+                acc >>= 14;
+                val
+            }
+            Endianness::Big => {
+                let val = acc >> bits;
+                acc &= (1 << bits) - 1;
+                val
+            }
+        };
+        values.push(val as f64);
+        if values.len() == count {
+            break;
+        }
+    }
+    values
 }
 
 /// Flir Camera Parameters
-#[derive(Debug)]
+#[derive(Debug, Clone)]
 pub struct FlirCameraParams {
     pub temperature_params: FlirTemperatureParams,
     pub camera_info: FlirCameraInfo,
@@ -339,7 +1245,14 @@ pub struct FlirCameraParams {
 
 declare_parseable_structs! {
     /// Flir Temperature Parameters
-    #[derive(Debug)]
+    ///
+    /// `camera_temperature_range` is at offset `0x90` of the
+    /// camera params record. As per the `FLIR::CameraInfo`
+    /// table of ExifTool, these are the `Max`, `Min`,
+    /// `MaxClip`, `MinClip`, `MaxWarn`, `MinWarn`,
+    /// `MaxSaturated` and `MinSaturated` temperatures (in
+    /// Kelvin) of the camera.
+    #[derive(Debug, Clone)]
     pub struct FlirTemperatureParams {
         pub emissivity => f32,
         pub object_distance => f32,
@@ -370,7 +1283,7 @@ declare_parseable_structs! {
     }
 
     /// Flir Camera Info
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct FlirCameraInfo {
         pub camera_mode => [u8; 32],
         pub camera_part_number => [u8; 16],
@@ -379,15 +1292,21 @@ declare_parseable_structs! {
     }
 
     /// Flir Lens Info
-    #[derive(Debug)]
+    ///
+    /// `field_of_view` (horizontal, in degrees) is at offset
+    /// `0x1b4` of the camera params record, as `FieldOfView`
+    /// in the `FLIR::CameraInfo` table of ExifTool.
+    #[derive(Debug, Clone)]
     pub struct FlirLensInfo {
         pub lens_mode => [u8; 32],
         pub lens_part_number => [u8; 16],
         pub lens_serial_number => [u8; 16],
+        _dummy_ignore => u32,
+        pub field_of_view => f32,
     }
 
     /// Flir Filter Info
-    #[derive(Debug)]
+    #[derive(Debug, Clone)]
     pub struct FlirFilterInfo {
         pub filter_mode => [u8; 32],
         pub filter_part_number => [u8; 16],
@@ -395,10 +1314,736 @@ declare_parseable_structs! {
     }
 
     /// Flir Extra Info
-    #[derive(Debug)]
+    ///
+    /// `raw_value_ranges` is at offset `0x310` of the camera
+    /// params record. As per the `FLIR::CameraInfo` table of
+    /// ExifTool, the first two are `RawValueRangeMin` and
+    /// `RawValueRangeMax`: the range of raw values the camera
+    /// recorded for the image (not the limits of the sensor;
+    /// see [`FlirTemperatureParams::saturation_range`]). The
+    /// other two are not known.
+    #[derive(Debug, Clone)]
     pub struct FlirExtraParams {
         pub planck_o => i32,
         pub planck_r2 => f32,
         pub raw_value_ranges => [u16; 4],
     }
+
+    /// Flir Date / Time Original
+    ///
+    /// Seconds since the unix epoch (UTC), milliseconds, and
+    /// the timezone as minutes west of UTC.
+    #[derive(Debug, Clone)]
+    pub struct FlirDateTime {
+        pub seconds => u32,
+        pub milliseconds => u32,
+        pub timezone_minutes => i16,
+    }
+
+    /// Flir Palette Info
+    ///
+    /// Header of the palette record, as in the
+    /// `FLIR::PaletteInfo` table of ExifTool; the `num_colors`
+    /// colors follow at offset `0x70`.
+    #[derive(Debug, Clone)]
+    pub struct FlirPaletteInfo {
+        pub num_colors => u8,
+        _dummy_ignore => [u8; 5],
+        pub above_color => [u8; 3],
+        pub below_color => [u8; 3],
+        pub overflow_color => [u8; 3],
+        pub underflow_color => [u8; 3],
+        pub isotherm1_color => [u8; 3],
+        pub isotherm2_color => [u8; 3],
+        _dummy_ignore_1 => [u8; 2],
+        pub method => u8,
+        pub stretch => u8,
+        _dummy_ignore_2 => [u8; 20],
+        pub file_name => [u8; 32],
+        pub name => [u8; 32],
+    }
+
+    /// Flir GPS Info
+    ///
+    /// Layout as in the `FLIR::GPSInfo` table of ExifTool.
+    /// The latitude and longitude are unsigned degrees; the
+    /// hemisphere is given by the corresponding reference
+    /// field (`N` / `S`, and `E` / `W`).
+    #[derive(Debug, Clone)]
+    pub struct FlirGpsInfo {
+        pub valid => u32,
+        pub version_id => [u8; 4],
+        pub latitude_ref => [u8; 2],
+        pub longitude_ref => [u8; 2],
+        _dummy_ignore => u32,
+        pub latitude => f64,
+        pub longitude => f64,
+        pub altitude => f32,
+    }
+}
+
+impl FlirTemperatureParams {
+    /// The `(min, max)` temperatures (in celicius) at which
+    /// the sensor saturates, if valid (i.e. `min < max`,
+    /// above absolute zero).
+    pub fn saturation_range(&self) -> Option<(f64, f64)> {
+        let max = self.camera_temperature_range[6] as f64;
+        let min = self.camera_temperature_range[7] as f64;
+        if min > 0. && min < max && max.is_finite() {
+            Some((min - 273.15, max - 273.15))
+        } else {
+            None
+        }
+    }
+}
+
+impl FlirCameraInfo {
+    /// Camera model (`CameraModel` in ExifTool).
+    pub fn model(&self) -> String {
+        decode_fixed_string(&self.camera_mode)
+    }
+
+    pub fn part_number(&self) -> String {
+        decode_fixed_string(&self.camera_part_number)
+    }
+
+    pub fn serial_number(&self) -> String {
+        decode_fixed_string(&self.camera_serial_number)
+    }
+
+    pub fn software(&self) -> String {
+        decode_fixed_string(&self.camera_software)
+    }
+}
+
+impl FlirLensInfo {
+    /// Lens model (`LensModel` in ExifTool).
+    pub fn model(&self) -> String {
+        decode_fixed_string(&self.lens_mode)
+    }
+
+    pub fn part_number(&self) -> String {
+        decode_fixed_string(&self.lens_part_number)
+    }
+
+    pub fn serial_number(&self) -> String {
+        decode_fixed_string(&self.lens_serial_number)
+    }
+}
+
+impl FlirFilterInfo {
+    /// Filter model (`FilterModel` in ExifTool).
+    pub fn model(&self) -> String {
+        decode_fixed_string(&self.filter_mode)
+    }
+
+    pub fn part_number(&self) -> String {
+        decode_fixed_string(&self.filter_part_number)
+    }
+
+    pub fn serial_number(&self) -> String {
+        decode_fixed_string(&self.filter_serial_number)
+    }
+}
+
+impl FlirExtraParams {
+    /// The recorded `(min, max)` raw values, if valid (i.e.
+    /// `min < max`).
+    pub fn raw_value_range(&self) -> Option<(u16, u16)> {
+        let [min, max, ..] = self.raw_value_ranges;
+        if min < max {
+            Some((min, max))
+        } else {
+            None
+        }
+    }
+}
+
+impl FlirDateTime {
+    /// Convert to a date / time in the timezone of the
+    /// camera. Returns `None` if the time was not recorded
+    /// (`seconds` is zero).
+    pub fn to_datetime(&self) -> Result<Option<DateTime<FixedOffset>>> {
+        if self.seconds == 0 {
+            return Ok(None);
+        }
+        // ExifTool displays `seconds - 60 * timezone_minutes`
+        // as the local time, with the timezone string of
+        // `-timezone_minutes`: i.e. `seconds` is the UTC
+        // timestamp and the offset is west of UTC.
+        let offset = FixedOffset::west_opt(self.timezone_minutes as i32 * 60)
+            .ok_or_else(|| anyhow!("invalid timezone: {} minutes", self.timezone_minutes))?;
+        let datetime = offset
+            .timestamp_opt(self.seconds as i64, (self.milliseconds % 1000) * 1_000_000)
+            .single()
+            .ok_or_else(|| anyhow!("invalid timestamp: {}", self.seconds))?;
+        Ok(Some(datetime))
+    }
+}
+
+impl FlirGpsInfo {
+    /// Signed latitude in degrees (positive is north).
+    pub fn signed_latitude(&self) -> f64 {
+        if self.latitude_ref[0] == b'S' {
+            -self.latitude.abs()
+        } else {
+            self.latitude
+        }
+    }
+
+    /// Signed longitude in degrees (positive is east).
+    pub fn signed_longitude(&self) -> f64 {
+        if self.longitude_ref[0] == b'W' {
+            -self.longitude.abs()
+        } else {
+            self.longitude
+        }
+    }
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        collect_flir_segment_data, decode_fixed_string, rendered_preview, ycrcb_to_rgb,
+        FlirDateTime, FlirRecordDirEntry, FlirSegment, ParseLimits,
+    };
+    use crate::error::ParseError;
+    use img_parts::jpeg::Jpeg;
+    use ndarray::array;
+
+    /// A FFF segment with a 3x2 (width x height) raw data
+    /// record storing `0..6` in the native byte-order.
+    pub(crate) fn raw_data_segment() -> Vec<u8> {
+        let (width, height) = (3u16, 2u16);
+        let mut data = vec![0u8; 0x60];
+        data[0..4].copy_from_slice(b"FFF\0");
+        data[0x14..0x18].copy_from_slice(&100u32.to_ne_bytes());
+        data[0x18..0x1c].copy_from_slice(&0x40u32.to_ne_bytes());
+        data[0x1c..0x20].copy_from_slice(&1u32.to_ne_bytes());
+
+        // Record directory entry.
+        let length = 2 * (16 + width as u32 * height as u32);
+        data[0x40..0x42].copy_from_slice(&1u16.to_ne_bytes());
+        data[0x42..0x44].copy_from_slice(&2u16.to_ne_bytes());
+        data[0x4c..0x50].copy_from_slice(&0x60u32.to_ne_bytes());
+        data[0x50..0x54].copy_from_slice(&length.to_ne_bytes());
+
+        // Raw data record.
+        let mut record = vec![0u8; 0x20];
+        record[0..2].copy_from_slice(&2u16.to_ne_bytes());
+        record[2..4].copy_from_slice(&width.to_ne_bytes());
+        record[4..6].copy_from_slice(&height.to_ne_bytes());
+        for val in 0..width * height {
+            record.extend_from_slice(&val.to_ne_bytes());
+        }
+        data.extend(record);
+        data
+    }
+
+    /// Append a record of type `ty` to `segment` (eg. from
+    /// [`raw_data_segment`]), moving the record directory
+    /// after it.
+    pub(crate) fn with_record(mut segment: Vec<u8>, ty: u16, record: &[u8]) -> Vec<u8> {
+        let read_u32 = |data: &[u8], at: usize| {
+            u32::from_ne_bytes([data[at], data[at + 1], data[at + 2], data[at + 3]])
+        };
+        let dir_offset = read_u32(&segment, 0x18) as usize;
+        let num_records = read_u32(&segment, 0x1c);
+        let dir = segment[dir_offset..dir_offset + 0x20 * num_records as usize].to_vec();
+
+        let record_offset = segment.len() as u32;
+        segment.extend_from_slice(record);
+        let dir_offset = segment.len() as u32;
+        segment.extend(dir);
+        let mut entry = vec![0u8; 0x20];
+        entry[0..2].copy_from_slice(&ty.to_ne_bytes());
+        entry[0xc..0x10].copy_from_slice(&record_offset.to_ne_bytes());
+        entry[0x10..0x14].copy_from_slice(&(record.len() as u32).to_ne_bytes());
+        segment.extend(entry);
+
+        segment[0x18..0x1c].copy_from_slice(&dir_offset.to_ne_bytes());
+        segment[0x1c..0x20].copy_from_slice(&(num_records + 1).to_ne_bytes());
+        segment
+    }
+
+    #[test]
+    fn lenient_auxiliary_records() {
+        // Truncated GPS and camera params records.
+        let data = with_record(raw_data_segment(), 0x2b, &[1, 0, 0, 0]);
+        let segment = FlirSegment::try_from_segment_data(data.clone(), None).unwrap();
+        assert!(segment.try_parse_gps().is_err());
+        let all = segment.parse_all().unwrap();
+        assert!(all.gps.is_none());
+        assert_eq!(all.raw.unwrap(), array![[0., 1., 2.], [3., 4., 5.]]);
+
+        let data = with_record(data, 0x20, &[0u8; 0x10]);
+        let segment = FlirSegment::try_from_segment_data(data, None).unwrap();
+        assert!(segment.parse_all().is_err());
+    }
+
+    #[test]
+    fn raw_data_orientation() {
+        let segment = FlirSegment::try_from_segment_data(raw_data_segment(), None).unwrap();
+        let image = segment.try_parse_raw_data().unwrap().unwrap();
+        assert_eq!(image, array![[0., 1., 2.], [3., 4., 5.]]);
+        assert_eq!(segment.record_summary(), vec![(1, 2, 0, 44)]);
+        assert_eq!(segment.format_version(), 100);
+        assert_eq!(
+            FlirSegment::fff_length(&raw_data_segment()).unwrap(),
+            raw_data_segment().len()
+        );
+    }
+
+    #[test]
+    fn truncated_raw_data() {
+        let mut data = raw_data_segment();
+        let len = data.len();
+        data.truncate(len - 4);
+        let segment = FlirSegment::try_from_segment_data(data, None).unwrap();
+        let err = segment.try_parse_raw_data().unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::TruncatedOrCorrupt {
+                expected: len,
+                found: len - 4
+            })
+        );
+    }
+
+    #[test]
+    fn forced_endianness() {
+        use byteordered::Endianness;
+
+        let native = Some(Endianness::native());
+        let segment = FlirSegment::try_from_segment_data(raw_data_segment(), native).unwrap();
+        let image = segment.try_parse_raw_data().unwrap().unwrap();
+        assert_eq!(image, array![[0., 1., 2.], [3., 4., 5.]]);
+
+        let opposite = Some(Endianness::native().to_opposite());
+        let segment = FlirSegment::try_from_segment_data(raw_data_segment(), opposite).unwrap();
+        assert!(segment.try_parse_raw_data().is_err());
+
+        // The header in the opposite byte-order of the
+        // records (as written by many cameras): forcing the
+        // byte-order of the records does not affect it.
+        let mut data = raw_data_segment();
+        let header_fields = [(0x14, 4), (0x18, 4), (0x1c, 4), (0x40, 2), (0x42, 2)];
+        let entry_fields = (0x44..0x60).step_by(4).map(|offset| (offset, 4));
+        for (offset, len) in header_fields.iter().copied().chain(entry_fields) {
+            data[offset..offset + len].reverse();
+        }
+        for forced in [None, native].iter() {
+            let segment = FlirSegment::try_from_segment_data(data.clone(), *forced).unwrap();
+            let image = segment.try_parse_raw_data().unwrap().unwrap();
+            assert_eq!(image, array![[0., 1., 2.], [3., 4., 5.]]);
+        }
+    }
+
+    #[test]
+    fn jpeg_bytes() {
+        let segment = raw_data_segment();
+        let (first, second) = segment.split_at(40);
+
+        let mut jpeg = vec![0xff, 0xd8];
+        for app1 in [
+            b"Exif\0\0".to_vec(),
+            flir_app1(0, 1, first),
+            flir_app1(1, 1, second),
+        ]
+        .iter()
+        {
+            jpeg.extend_from_slice(&[0xff, 0xe1]);
+            jpeg.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+            jpeg.extend_from_slice(app1);
+        }
+        // Image data is not read.
+        jpeg.extend_from_slice(&[0xff, 0xda, 0xff, 0xff]);
+
+        let segment = FlirSegment::try_from_jpeg_bytes(&jpeg).unwrap();
+        let image = segment.try_parse_raw_data().unwrap().unwrap();
+        assert_eq!(image, array![[0., 1., 2.], [3., 4., 5.]]);
+
+        jpeg.truncate(20);
+        assert!(FlirSegment::try_from_jpeg_bytes(&jpeg).is_err());
+    }
+
+    /// A JPEG with `segment` in a single FLIR APP1 segment.
+    pub(crate) fn flir_jpeg(segment: &[u8]) -> Jpeg {
+        let mut jpeg = vec![0xff, 0xd8, 0xff, 0xe1];
+        let app1 = flir_app1(0, 0, segment);
+        jpeg.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+        jpeg.extend_from_slice(&app1);
+        jpeg.extend_from_slice(&[0xff, 0xda, 0x00, 0x02, 0xff, 0xd9]);
+        Jpeg::from_bytes(jpeg.into()).unwrap()
+    }
+
+    fn flir_app1(idx: u8, last: u8, payload: &[u8]) -> Vec<u8> {
+        let mut contents = b"FLIR\0\x01".to_vec();
+        contents.extend_from_slice(&[idx, last]);
+        contents.extend_from_slice(payload);
+        contents
+    }
+
+    #[test]
+    fn parse_limits() {
+        let limit_of = |err: anyhow::Error| match err.downcast_ref::<ParseError>() {
+            Some(&ParseError::LimitExceeded { limit, .. }) => limit,
+            _ => panic!("unexpected error: {}", err),
+        };
+
+        let limits = ParseLimits {
+            max_pixels: 5,
+            ..Default::default()
+        };
+        let segment =
+            FlirSegment::try_from_segment_data_with_limits(raw_data_segment(), None, limits)
+                .unwrap();
+        assert_eq!(
+            limit_of(segment.try_parse_raw_data().unwrap_err()),
+            "pixels"
+        );
+
+        let limits = ParseLimits {
+            max_record_len: 40,
+            ..Default::default()
+        };
+        let err = FlirSegment::try_from_segment_data_with_limits(raw_data_segment(), None, limits)
+            .unwrap_err();
+        assert_eq!(limit_of(err), "record length");
+
+        let limits = ParseLimits {
+            max_segments: 1,
+            ..Default::default()
+        };
+        let segments = [flir_app1(0, 1, b"ab"), flir_app1(1, 1, b"cd")];
+        let err = collect_flir_segment_data(segments.iter().map(|s| &s[..]), &limits).unwrap_err();
+        assert_eq!(limit_of(err), "segments");
+    }
+
+    #[test]
+    fn picks_complete_flir_stream() {
+        let segments = [
+            b"Exif\0\0".to_vec(),
+            flir_app1(0, 1, b"ab"),
+            flir_app1(0, 2, b"xx"),
+            flir_app1(1, 1, b"cd"),
+            flir_app1(1, 2, b"yy"),
+        ];
+        let data =
+            collect_flir_segment_data(segments.iter().map(|s| &s[..]), &ParseLimits::default())
+                .unwrap();
+        assert_eq!(data, b"abcd");
+
+        // An incomplete stream before the primary one.
+        let segments = [
+            flir_app1(0, 2, b"xx"),
+            flir_app1(0, 1, b"ab"),
+            flir_app1(1, 1, b"cd"),
+        ];
+        let data =
+            collect_flir_segment_data(segments.iter().map(|s| &s[..]), &ParseLimits::default())
+                .unwrap();
+        assert_eq!(data, b"abcd");
+
+        let segments = [flir_app1(0, 1, b"ab")];
+        assert!(collect_flir_segment_data(
+            segments.iter().map(|s| &s[..]),
+            &ParseLimits::default()
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn duplicate_flir_segments() {
+        let segments = [
+            flir_app1(0, 2, b"ab"),
+            flir_app1(1, 2, b"cd"),
+            flir_app1(0, 2, b"ab"),
+            flir_app1(2, 2, b"ef"),
+        ];
+        let data =
+            collect_flir_segment_data(segments.iter().map(|s| &s[..]), &ParseLimits::default())
+                .unwrap();
+        assert_eq!(data, b"abcdef");
+
+        // A conflicting first segment starts another stream,
+        // without affecting the first one.
+        let segments = [
+            flir_app1(0, 2, b"ab"),
+            flir_app1(1, 2, b"cd"),
+            flir_app1(0, 2, b"xx"),
+            flir_app1(2, 2, b"ef"),
+        ];
+        let data =
+            collect_flir_segment_data(segments.iter().map(|s| &s[..]), &ParseLimits::default())
+                .unwrap();
+        assert_eq!(data, b"abcdef");
+
+        // Segments out of order, or without a first segment.
+        for segments in [
+            [flir_app1(1, 1, b"cd"), flir_app1(0, 1, b"ab")],
+            [flir_app1(1, 1, b"cd"), flir_app1(1, 1, b"ef")],
+        ]
+        .iter()
+        {
+            assert!(collect_flir_segment_data(
+                segments.iter().map(|s| &s[..]),
+                &ParseLimits::default()
+            )
+            .is_err());
+        }
+    }
+
+    #[test]
+    fn interleaved_flir_streams() {
+        let segments = [
+            flir_app1(0, 2, b"ab"),
+            flir_app1(0, 2, b"uv"),
+            flir_app1(1, 2, b"cd"),
+            flir_app1(1, 2, b"wx"),
+            flir_app1(2, 2, b"ef"),
+            flir_app1(2, 2, b"yz"),
+        ];
+        let data =
+            collect_flir_segment_data(segments.iter().map(|s| &s[..]), &ParseLimits::default())
+                .unwrap();
+        assert_eq!(data, b"abcdef");
+    }
+
+    #[test]
+    fn preview() {
+        let mut bytes = vec![0xff, 0xd8];
+        for app1 in [b"Exif\0\0".to_vec(), flir_app1(0, 0, b"FFF\0")].iter() {
+            bytes.extend_from_slice(&[0xff, 0xe1]);
+            bytes.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+            bytes.extend_from_slice(app1);
+        }
+        bytes.extend_from_slice(&[0xff, 0xda, 0x00, 0x03, 0x01, 0x12, 0x34, 0xff, 0xd9]);
+
+        let preview = rendered_preview(&Jpeg::from_bytes(bytes.into()).unwrap()).unwrap();
+        let mut expected = vec![0xff, 0xd8, 0xff, 0xe1, 0x00, 0x08];
+        expected.extend_from_slice(b"Exif\0\0");
+        expected.extend_from_slice(&[0xff, 0xda, 0x00, 0x03, 0x01, 0x12, 0x34, 0xff, 0xd9]);
+        assert_eq!(preview, expected);
+
+        let err = rendered_preview(&Jpeg::from_bytes(preview.into()).unwrap()).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::NotRadiometric)
+        );
+    }
+
+    #[test]
+    fn write_raw_data() {
+        let fff = raw_data_segment();
+        let mut bytes = vec![0xff, 0xd8];
+        let app1s = [
+            b"Exif\0\0".to_vec(),
+            flir_app1(0, 1, &fff[..0x30]),
+            flir_app1(1, 1, &fff[0x30..]),
+        ];
+        for app1 in app1s.iter() {
+            bytes.extend_from_slice(&[0xff, 0xe1]);
+            bytes.extend_from_slice(&(app1.len() as u16 + 2).to_be_bytes());
+            bytes.extend_from_slice(app1);
+        }
+        bytes.extend_from_slice(&[0xff, 0xda, 0x00, 0x03, 0x01, 0x12, 0x34, 0xff, 0xd9]);
+        let template = Jpeg::from_bytes(bytes.into()).unwrap();
+
+        let mut segment = FlirSegment::try_from_jpeg(&template).unwrap();
+        assert!(segment.set_raw_data(&array![[1., 2.], [3., 4.]]).is_err());
+        let raw = array![[5., 4.4, 3.], [2., -1., f64::NAN]];
+        segment.set_raw_data(&raw).unwrap();
+        let written = segment.write_into_jpeg(&template).unwrap();
+
+        let written = Jpeg::from_bytes(written.encoder().bytes()).unwrap();
+        assert_eq!(written.segments().len(), 3);
+        assert_eq!(&written.segments()[0].contents()[..], b"Exif\0\0");
+        let parsed = FlirSegment::try_from_jpeg(&written).unwrap();
+        let expected = array![[5., 4., 3.], [2., 0., 0.]];
+        assert_eq!(parsed.try_parse_raw_data().unwrap().unwrap(), expected);
+    }
+
+    #[test]
+    fn plain_jpeg_is_not_radiometric() {
+        // Just the SOI and EOI markers.
+        let jpeg = Jpeg::from_bytes(vec![0xff, 0xd8, 0xff, 0xd9].into()).unwrap();
+        let err = FlirSegment::try_from_jpeg(&jpeg).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::NotRadiometric)
+        );
+    }
+
+    #[test]
+    fn datetime_with_timezone() {
+        let datetime = FlirDateTime {
+            seconds: 1_600_000_000,
+            milliseconds: 250,
+            timezone_minutes: -330,
+        };
+        assert_eq!(
+            datetime.to_datetime().unwrap().unwrap().to_rfc3339(),
+            "2020-09-13T17:56:40.250+05:30"
+        );
+
+        let unset = FlirDateTime {
+            seconds: 0,
+            milliseconds: 0,
+            timezone_minutes: 0,
+        };
+        assert!(unset.to_datetime().unwrap().is_none());
+    }
+
+    #[test]
+    fn palette() {
+        let mut record = vec![0u8; 0x70];
+        record[0] = 2;
+        record[0x50..0x54].copy_from_slice(b"iron");
+        record.extend_from_slice(&[16, 128, 128, 235, 128, 128]);
+
+        let mut data = vec![0u8; 0x60];
+        data[0..4].copy_from_slice(b"FFF\0");
+        data[0x14..0x18].copy_from_slice(&100u32.to_ne_bytes());
+        data[0x18..0x1c].copy_from_slice(&0x40u32.to_ne_bytes());
+        data[0x1c..0x20].copy_from_slice(&1u32.to_ne_bytes());
+        data[0x40..0x42].copy_from_slice(&0x22u16.to_ne_bytes());
+        data[0x4c..0x50].copy_from_slice(&0x60u32.to_ne_bytes());
+        data[0x50..0x54].copy_from_slice(&(record.len() as u32).to_ne_bytes());
+        data.extend(record);
+
+        let segment = FlirSegment::try_from_segment_data(data, None).unwrap();
+        let palette = segment.try_parse_palette().unwrap().unwrap();
+        assert_eq!(palette.name, "iron");
+        assert_eq!(palette.colors, vec![[16, 16, 16], [235, 235, 235]]);
+        assert!(segment.try_parse_raw_data().unwrap().is_none());
+
+        let all = segment.parse_all().unwrap();
+        assert_eq!(all.palette.unwrap().name, "iron");
+        assert!(all.raw.is_none() && all.camera_params.is_none());
+
+        let segment = FlirSegment::try_from_segment_data(raw_data_segment(), None).unwrap();
+        assert!(segment.try_parse_palette().unwrap().is_none());
+        let all = segment.parse_all().unwrap();
+        assert_eq!(all.raw, segment.try_parse_raw_data().unwrap());
+        assert!(all.palette.is_none() && all.embedded_image.is_none());
+
+        assert_eq!(ycrcb_to_rgb([76, 255, 85]), [254, 0, 0]);
+    }
+
+    #[test]
+    fn invalid_byte_order_marker() {
+        let mut data = raw_data_segment();
+        data[0x60..0x62].copy_from_slice(&[0xab, 0xcd]);
+        let segment = FlirSegment::try_from_segment_data(data, None).unwrap();
+        assert!(segment.try_parse_raw_data().is_err());
+    }
+
+    #[test]
+    fn packed_14bit_raw_data() {
+        let entry = FlirRecordDirEntry {
+            ty: 0x01,
+            sub_type: 2,
+            version: 0x64,
+            id: 1,
+            offset: 0,
+            length: 0x20 + 7,
+            parent: 0,
+            obj_num: 0,
+            checksum: 0,
+        };
+        // 2x2 values: 0x3fff, 0x0001, 0x2aaa, 0x1555
+        let le = [0xff, 0x7f, 0x00, 0xa0, 0xaa, 0x56, 0x55];
+        let be = [0xff, 0xfc, 0x00, 0x1a, 0xaa, 0x95, 0x55];
+        let expected = array![[16383., 1.], [10922., 5461.]];
+
+        let mut data = vec![0u8; 0x20];
+        data[0..2].copy_from_slice(&2u16.to_le_bytes());
+        data[2..4].copy_from_slice(&2u16.to_le_bytes());
+        data[4..6].copy_from_slice(&2u16.to_le_bytes());
+        data.extend_from_slice(&le);
+        // A 16-bit record truncated to the packed length.
+        let err = entry.try_parse_raw_data(&data, None).unwrap_err();
+        assert!(matches!(
+            err.downcast_ref::<ParseError>(),
+            Some(ParseError::TruncatedOrCorrupt { .. })
+        ));
+        let raw = entry.try_parse_packed_raw_data(&data, None).unwrap();
+        assert_eq!(raw.unwrap(), expected);
+
+        for word in data[0..6].chunks_exact_mut(2) {
+            word.swap(0, 1);
+        }
+        data[0x20..].copy_from_slice(&be);
+        let raw = entry.try_parse_packed_raw_data(&data, None).unwrap();
+        assert_eq!(raw.unwrap(), expected);
+
+        data.pop();
+        assert!(entry.try_parse_packed_raw_data(&data, None).is_err());
+    }
+
+    #[test]
+    fn implausible_camera_params() {
+        let entry = FlirRecordDirEntry {
+            ty: 0x20,
+            sub_type: 1,
+            version: 0x64,
+            id: 1,
+            offset: 0,
+            length: 0x384,
+            parent: 0,
+            obj_num: 0,
+            checksum: 0,
+        };
+        let mut data = vec![0u8; 0x384];
+        data[0..2].copy_from_slice(&2u16.to_ne_bytes());
+        data[0x58..0x5c].copy_from_slice(&21106.77f32.to_ne_bytes());
+        data[0x60..0x64].copy_from_slice(&1f32.to_ne_bytes());
+
+        let err = entry.try_parse_camera_params(&data, None).unwrap_err();
+        assert!(err.to_string().contains("planck_b implausible"));
+
+        data[0x5c..0x60].copy_from_slice(&1501f32.to_ne_bytes());
+        let params = entry.try_parse_camera_params(&data, None).unwrap().unwrap();
+        assert_eq!(params.temperature_params.planck_b, 1501.);
+    }
+
+    #[test]
+    fn focus_distance() {
+        let mut entry = FlirRecordDirEntry {
+            ty: 0x20,
+            sub_type: 1,
+            version: 0x64,
+            id: 1,
+            offset: 0,
+            length: 0x468,
+            parent: 0,
+            obj_num: 0,
+            checksum: 0,
+        };
+        let mut data = vec![0u8; 0x468];
+        data[0..2].copy_from_slice(&2u16.to_ne_bytes());
+        data[0x45c..0x460].copy_from_slice(&4.5f32.to_ne_bytes());
+        data[0x464..0x468].copy_from_slice(&30f32.to_ne_bytes());
+        let distance = entry.try_parse_focus_distance(&data, None).unwrap();
+        assert_eq!(distance, Some(4.5));
+
+        // Not recorded.
+        data[0x45c..0x460].copy_from_slice(&0f32.to_ne_bytes());
+        let distance = entry.try_parse_focus_distance(&data, None).unwrap();
+        assert_eq!(distance, None);
+
+        // A record too short to have the field.
+        entry.length = 0x45c;
+        let distance = entry.try_parse_focus_distance(&data, None).unwrap();
+        assert_eq!(distance, None);
+    }
+
+    #[test]
+    fn fixed_strings() {
+        assert_eq!(decode_fixed_string(b"T640\0\0\xffgarbage"), "T640");
+        assert_eq!(decode_fixed_string(b"full"), "full");
+        assert_eq!(decode_fixed_string(b"caf\xe9\0"), "caf\u{fffd}");
+        assert_eq!(decode_fixed_string(b"\0T640"), "");
+    }
 }
@@ -1,34 +1,470 @@
 //! Parse and extract raw thermal image and temperature
 //! params.
-use std::{convert::TryFrom, fs::read, io::Cursor, path::Path};
+use std::{
+    convert::TryFrom,
+    fmt,
+    fs::{read, File},
+    io::{BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+};
 
-use anyhow::{anyhow, bail, Result};
-use image::{ColorType, ImageDecoder};
+use ::image::{GrayImage, ImageBuffer, Luma};
+use anyhow::{ensure, Context, Result};
+use chrono::{DateTime, FixedOffset};
 use img_parts::jpeg::Jpeg;
-use ndarray::Array2;
+use ndarray::{s, Array2, ArrayView2, Zip};
 use serde_derive::*;
 
-use crate::{flir::FlirSegment, temperature::ThermalSettings};
+use crate::{
+    error::ParseError,
+    flir::FlirSegment,
+    stats::histogram,
+    temperature::{ThermalSettings, DEFAULT_DISTANCE},
+};
+
+pub use crate::exif::ThermalExiftoolJson;
 
 /// Container for the raw sensor values, and the parameters
 /// of a single Flir image.
+///
+/// This can be serialized to cache a parsed image; see
+/// [`save_cache`][ThermalImage::save_cache].
+///
+/// The `Debug` output shows the dimensions of the image
+/// instead of all the raw values.
+#[derive(Serialize, Deserialize, Clone)]
 pub struct ThermalImage {
     pub settings: ThermalSettings,
+    /// Raw sensor values, with shape `(height, width)`. The
+    /// parsers produce a row-major (standard layout) array,
+    /// but this is not guaranteed if the array is modified;
+    /// see [`as_contiguous_slice`][ThermalImage::as_contiguous_slice].
     pub image: Array2<f64>,
+
+    /// Position of the camera, if recorded in the image.
+    pub gps: Option<GpsCoordinates>,
+
+    /// Date / time of capture (in the timezone of the
+    /// camera), if recorded in the image.
+    pub datetime: Option<DateTime<FixedOffset>>,
+
+    #[serde(default)]
+    raw_value_range: Option<(u16, u16)>,
+
+    #[serde(default)]
+    saturation_range: Option<(f64, f64)>,
+
+    #[serde(default)]
+    recorded_distance: Option<f64>,
+
+    #[serde(default)]
+    field_of_view: Option<f64>,
+}
+
+/// Dimensions of an image, in pixels. Note that arrays of
+/// the image have the shape `(height, width)`; see
+/// [`shape`][Dimensions::shape].
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Dimensions {
+    pub width: usize,
+    pub height: usize,
+}
+
+impl Dimensions {
+    /// From the shape `(height, width)` of an array of the
+    /// image, eg. `Dimensions::from_shape(array.dim())`.
+    pub fn from_shape((height, width): (usize, usize)) -> Self {
+        Dimensions { width, height }
+    }
+
+    /// The shape `(height, width)` of the arrays of the
+    /// image.
+    pub fn shape(&self) -> (usize, usize) {
+        (self.height, self.width)
+    }
+
+    /// Number of pixels.
+    pub fn len(&self) -> usize {
+        self.width * self.height
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+/// GPS position recorded by the camera.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy)]
+pub struct GpsCoordinates {
+    /// Latitude in degrees (positive is north).
+    pub latitude: f64,
+    /// Longitude in degrees (positive is east).
+    pub longitude: f64,
+    /// Altitude in meters.
+    pub altitude: f64,
 }
 impl ThermalImage {
+    /// An image with the given parameters and raw sensor
+    /// values, without any of the other metadata (GPS
+    /// position, raw value range, etc.).
+    pub fn new(settings: ThermalSettings, image: Array2<f64>) -> Self {
+        ThermalImage {
+            settings,
+            image,
+            gps: None,
+            datetime: None,
+            raw_value_range: None,
+            saturation_range: None,
+            recorded_distance: None,
+            field_of_view: None,
+        }
+    }
+
     /// Parse a `ThermalImage` from
     /// [`Jpeg`][`img_parts::jpeg::Jpeg`].
+    ///
+    /// # MSX / fusion images
+    ///
+    /// Cameras with MSX blend edges from the visual camera
+    /// into the displayed JPEG only: the raw sensor values
+    /// record is not edge-enhanced, and the temperatures
+    /// computed from it are valid. Images exported as MSX /
+    /// fusion without radiometry have no raw sensor values
+    /// record, and fail with [`ParseError::NoRawData`].
+    ///
+    /// The FLIR records parsed here do not carry an MSX /
+    /// fusion flag, so blended raw data (if a camera were to
+    /// write it) can not be detected.
     pub fn try_from_rjpeg(image: &Jpeg) -> Result<Self> {
+        Self::try_from_flir_segment(&FlirSegment::try_from_jpeg(image)?)
+    }
+
+    /// Write the image as an R-JPEG to `out`: `template` (eg.
+    /// the R-JPEG the image was parsed from) with the raw
+    /// sensor values record replaced by
+    /// [`image`][ThermalImage::image]. The other records,
+    /// including the camera params, and the displayed image
+    /// are copied from `template`, so changes to the
+    /// [`settings`][ThermalImage::settings] are not written.
+    ///
+    /// The raw values are rounded and clamped to 16-bit; see
+    /// [`FlirSegment::set_raw_data`].
+    pub fn write_rjpeg<W: Write>(&self, template: &Jpeg, out: W) -> Result<()> {
+        let mut flir_segment = FlirSegment::try_from_jpeg(template)?;
+        flir_segment.set_raw_data(&self.image)?;
+        flir_segment
+            .write_into_jpeg(template)?
+            .encoder()
+            .write_to(out)?;
+        Ok(())
+    }
+
+    /// Parse only the raw sensor values, and the settings if
+    /// the camera params record is present. Some exports
+    /// store the parameters only in the EXIF / XMP metadata;
+    /// the raw values of these can be converted with settings
+    /// from elsewhere (eg. built with
+    /// [`ThermalSettings::builder`]). The settings are `None`
+    /// if the record is missing or could not be parsed.
+    pub fn try_raw_only_from_rjpeg(image: &Jpeg) -> Result<(Array2<f64>, Option<ThermalSettings>)> {
         let flir_segment = FlirSegment::try_from_jpeg(image)?;
+        let raw = flir_segment
+            .try_parse_raw_data()?
+            .ok_or(ParseError::NoRawData)?;
+        // The raw values are usable even if the params
+        // record is corrupt (or the constants implausible).
+        let settings = flir_segment
+            .try_parse_camera_params()
+            .ok()
+            .flatten()
+            .map(Into::into);
+        Ok((raw, settings))
+    }
+
+    /// Parse a `ThermalImage` from an already parsed
+    /// [`FlirSegment`] (eg. one parsed with a forced
+    /// byte-order via
+    /// [`try_from_jpeg_with_endianness`][FlirSegment::try_from_jpeg_with_endianness]).
+    pub fn try_from_flir_segment(flir_segment: &FlirSegment) -> Result<Self> {
         let image = flir_segment
             .try_parse_raw_data()?
-            .ok_or_else(|| anyhow!("no raw data found"))?;
-        let settings: ThermalSettings = flir_segment
+            .ok_or(ParseError::NoRawData)?;
+        let camera_params = flir_segment
             .try_parse_camera_params()?
-            .ok_or_else(|| anyhow!("no camera params found"))?
-            .into();
-        Ok(ThermalImage { image, settings })
+            .ok_or(ParseError::NoCameraParams)?;
+        let raw_value_range = camera_params.extra_params.raw_value_range();
+        let saturation_range = camera_params.temperature_params.saturation_range();
+        let field_of_view = Some(camera_params.lens_info.field_of_view as f64)
+            .filter(|fov| *fov > 0. && *fov < 180.);
+        let settings: ThermalSettings = camera_params.into();
+
+        // GPS is auxiliary info: do not reject an otherwise
+        // valid image because of a malformed GPS record.
+        let gps = flir_segment
+            .try_parse_gps()
+            .ok()
+            .flatten()
+            .map(|gps| GpsCoordinates {
+                latitude: gps.signed_latitude(),
+                longitude: gps.signed_longitude(),
+                altitude: gps.altitude as f64,
+            });
+        let datetime = flir_segment.try_parse_datetime().ok().flatten();
+        let recorded_distance = flir_segment.try_parse_focus_distance().ok().flatten();
+        Ok(ThermalImage {
+            image,
+            settings,
+            gps,
+            datetime,
+            raw_value_range,
+            saturation_range,
+            recorded_distance,
+            field_of_view,
+        })
+    }
+
+    /// A distance (in meters) recorded in the image other
+    /// than the `ObjectDistance` of the settings: the
+    /// `FocusDistance` of the camera (or the EXIF
+    /// `SubjectDistance` for images parsed from exiftool
+    /// JSON). A recorded `0.0` is treated as unknown.
+    ///
+    /// The focus distance is only as accurate as the focus,
+    /// but is usually a better guess than the default.
+    pub fn recorded_distance(&self) -> Option<f64> {
+        self.recorded_distance
+    }
+
+    /// Resolve the distance to use for the conversion. This
+    /// is the `distance` if provided; else the embedded
+    /// `ObjectDistance` (see
+    /// [`ThermalSettings::effective_distance`]); else the
+    /// [`recorded_distance`][ThermalImage::recorded_distance];
+    /// else [`DEFAULT_DISTANCE`].
+    pub fn effective_distance(&self, distance: Option<f64>) -> f64 {
+        distance
+            .or_else(|| self.settings.embedded_distance())
+            .or(self.recorded_distance)
+            .unwrap_or(DEFAULT_DISTANCE)
+    }
+
+    /// Horizontal field of view (in degrees) of the camera,
+    /// if recorded.
+    pub fn field_of_view(&self) -> Option<f64> {
+        self.field_of_view
+    }
+
+    /// Dimensions of the image.
+    pub fn dimensions(&self) -> Dimensions {
+        Dimensions::from_shape(self.image.dim())
+    }
+
+    /// Iterate over the temperatures (in celicius) of the
+    /// pixels as `(row, col, temperature)`, in row-major
+    /// order. The distance is resolved using
+    /// [`effective_distance`][ThermalImage::effective_distance].
+    pub fn temperature_pixels(
+        &self,
+        distance: Option<f64>,
+    ) -> impl Iterator<Item = (usize, usize, f64)> + '_ {
+        let distance = self.effective_distance(distance);
+        let temp_t = self.settings.temperature_transform(distance);
+        self.image
+            .indexed_iter()
+            .map(move |((row, col), &raw)| (row, col, temp_t(raw)))
+    }
+
+    /// Histogram of the image in `bins` equal width bins, as
+    /// `(lo, hi, counts)`: of the raw sensor values if
+    /// `distance` is `None`, else of the temperatures (in
+    /// celicius) at `distance`. Useful to pick a range to
+    /// render. NaN values (eg. of dead pixels) are dropped;
+    /// see [`stats::histogram`].
+    ///
+    /// [`stats::histogram`]: crate::stats::histogram
+    pub fn histogram(&self, distance: Option<f64>, bins: usize) -> (f64, f64, Vec<u64>) {
+        match distance {
+            None => histogram(self.image.iter().copied(), bins),
+            Some(distance) => histogram(
+                self.temperature_pixels(Some(distance))
+                    .map(|(_, _, temp)| temp),
+                bins,
+            ),
+        }
+    }
+
+    /// Flag saturated pixels (eg. from sun glint, or failed
+    /// sensor elements): those pinned at raw values `0` or
+    /// `65535`, and those at or beyond the limits of the
+    /// sensor (see
+    /// [`saturation_range`][ThermalImage::saturation_range]),
+    /// if recorded. The limits are compared to the
+    /// temperatures from the Planck curve alone (see
+    /// [`planck_only_transform`][ThermalSettings::planck_only_transform]),
+    /// which do not depend on the object parameters.
+    ///
+    /// The temperatures of such pixels are not meaningful;
+    /// see [`Stats::accumulate_masked`] to skip them.
+    ///
+    /// [`Stats::accumulate_masked`]: crate::stats::Stats::accumulate_masked
+    pub fn saturation_mask(&self) -> Array2<bool> {
+        let (min, max) = self
+            .saturation_range
+            .unwrap_or((f64::NEG_INFINITY, f64::INFINITY));
+        let planck = self.settings.planck_only_transform();
+        self.image.mapv(|raw| {
+            let temp = planck(raw);
+            raw <= 0. || raw >= u16::MAX as f64 || temp <= min || temp >= max
+        })
+    }
+
+    /// Flag the pixels with temperatures (in celicius)
+    /// above `threshold`. Pixels with NaN temperatures (eg.
+    /// dead pixels) are not flagged. The distance is
+    /// resolved using
+    /// [`effective_distance`][ThermalImage::effective_distance].
+    pub fn mask_above(&self, distance: Option<f64>, threshold: f64) -> Array2<bool> {
+        let distance = self.effective_distance(distance);
+        let temp_t = self.settings.temperature_transform(distance);
+        self.image.mapv(|raw| temp_t(raw) > threshold)
+    }
+
+    /// Fraction of the pixels with temperatures (in
+    /// celicius) above `threshold`, eg. the part of a roof
+    /// above 40°C. Pixels with NaN temperatures are
+    /// excluded from the count, and the total; the result
+    /// is NaN if all of them are. The distance is resolved
+    /// as with [`mask_above`][ThermalImage::mask_above].
+    pub fn fraction_above(&self, distance: Option<f64>, threshold: f64) -> f64 {
+        let (above, valid) = self
+            .temperature_pixels(distance)
+            .map(|(_, _, temp)| temp)
+            .filter(|temp| !temp.is_nan())
+            .fold((0usize, 0usize), |(above, valid), temp| {
+                (above + (temp > threshold) as usize, valid + 1)
+            });
+        above as f64 / valid as f64
+    }
+
+    /// Downsample the image by an integer `factor` (eg. for
+    /// thumbnails), averaging the raw values in each `factor
+    /// x factor` block. Blocks at the right / bottom edges
+    /// may be partial. The settings and other metadata are
+    /// kept.
+    ///
+    /// Averaging raw values is cheaper than averaging
+    /// temperatures, but as the conversion is not linear the
+    /// temperatures differ slightly (more so in blocks with
+    /// large temperature differences): fine for display. See
+    /// [`downsample_temperatures`][ThermalImage::downsample_temperatures]
+    /// to average the temperatures instead.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is `0`.
+    pub fn downsample(&self, factor: usize) -> ThermalImage {
+        ThermalImage {
+            image: block_mean(self.image.view(), factor),
+            ..self.clone()
+        }
+    }
+
+    /// Same as [`downsample`][ThermalImage::downsample], but
+    /// averages the temperatures (in celicius). NaN
+    /// temperatures are skipped, and a block with only NaN
+    /// values is NaN.
+    ///
+    /// # Panics
+    ///
+    /// If `factor` is `0`.
+    pub fn downsample_temperatures(&self, factor: usize, distance: Option<f64>) -> Array2<f64> {
+        let distance = self.effective_distance(distance);
+        let temps = self.settings.temperatures(distance, &self.image);
+        block_mean(temps.view(), factor)
+    }
+
+    /// Per-pixel temperature difference (in celicius) of this
+    /// image from a `baseline` image of the same scene: i.e.
+    /// `temperature - baseline temperature`. Each image is
+    /// converted using its own settings, at the given
+    /// `distance`.
+    ///
+    /// Errors if the dimensions of the images differ.
+    pub fn difference(&self, baseline: &ThermalImage, distance: f64) -> Result<Array2<f64>> {
+        ensure!(
+            self.image.dim() == baseline.image.dim(),
+            "image dimensions differ: {:?} vs baseline {:?}",
+            self.image.dim(),
+            baseline.image.dim()
+        );
+        let temp_t = self.settings.temperature_transform(distance);
+        let baseline_t = baseline.settings.temperature_transform(distance);
+        Ok(Zip::from(&self.image)
+            .and(&baseline.image)
+            .map_collect(|&raw, &baseline_raw| temp_t(raw) - baseline_t(baseline_raw)))
+    }
+
+    /// Render the temperatures as a 16-bit grayscale image:
+    /// temperatures in `[min, max]` are mapped linearly to
+    /// the full `u16` range, and those outside are clamped.
+    pub fn to_gray16(
+        &self,
+        distance: Option<f64>,
+        min: f64,
+        max: f64,
+    ) -> ImageBuffer<Luma<u16>, Vec<u16>> {
+        let coeffs = gray16_coeffs(min, max);
+        let data = self
+            .temperature_pixels(distance)
+            .map(|(_, _, temp)| scale_to_u16(coeffs, temp))
+            .collect();
+
+        let (ht, wid) = self.image.dim();
+        ImageBuffer::from_raw(wid as u32, ht as u32, data).expect("buffer matches dimensions")
+    }
+
+    /// Render the temperatures as an 8-bit grayscale image.
+    /// The values are the 8 most significant bits of those
+    /// of [`to_gray16`][ThermalImage::to_gray16].
+    pub fn to_gray8(&self, distance: Option<f64>, min: f64, max: f64) -> GrayImage {
+        let gray16 = self.to_gray16(distance, min, max);
+        let data = gray16.iter().map(|val| (val >> 8) as u8).collect();
+        GrayImage::from_raw(gray16.width(), gray16.height(), data)
+            .expect("buffer matches dimensions")
+    }
+
+    /// The `(min, max)` raw sensor values recorded by the
+    /// camera for the image (`RawValueRangeMin` /
+    /// `RawValueRangeMax` in ExifTool), if any. Converting
+    /// these to temperatures gives a range to scale the image
+    /// to, without computing percentiles.
+    pub fn raw_value_range(&self) -> Option<(u16, u16)> {
+        self.raw_value_range
+    }
+
+    /// The `(min, max)` temperatures (in celicius) at which
+    /// the sensor saturates (`CameraTemperatureMinSaturated`
+    /// / `CameraTemperatureMaxSaturated` in ExifTool), if
+    /// recorded. See
+    /// [`saturation_mask`][ThermalImage::saturation_mask].
+    pub fn saturation_range(&self) -> Option<(f64, f64)> {
+        self.saturation_range
+    }
+
+    /// The raw sensor values as a contiguous slice, in
+    /// row-major order: the value at `(row, col)` is at index
+    /// `row * width + col`. Returns `None` if the array is
+    /// not in standard layout (eg. after transposing it); see
+    /// [`to_row_major_vec`][ThermalImage::to_row_major_vec].
+    pub fn as_contiguous_slice(&self) -> Option<&[f64]> {
+        self.image.as_slice()
+    }
+
+    /// The raw sensor values in row-major order (as in
+    /// [`as_contiguous_slice`][ThermalImage::as_contiguous_slice]),
+    /// copied into a new vector regardless of the layout.
+    pub fn to_row_major_vec(&self) -> Vec<f64> {
+        self.image.iter().copied().collect()
     }
 
     /// Parse a `ThermalImage` from path to a R-Jpeg image file.
@@ -37,30 +473,90 @@ impl ThermalImage {
         Self::try_from_rjpeg(&image)
     }
 
+    /// Same as
+    /// [`try_from_rjpeg_path`][ThermalImage::try_from_rjpeg_path],
+    /// but memory maps the file instead of reading it, and
+    /// only reads the FLIR data. This reduces the peak
+    /// memory when parsing many large R-JPEGs (eg. with
+    /// embedded high resolution visual images) in parallel.
+    ///
+    /// Requires the `mmap` feature. The file must not be
+    /// modified while it is being parsed.
+    #[cfg(feature = "mmap")]
+    pub fn try_from_rjpeg_mmap<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let file = File::open(path)?;
+        // Safety: the map is only read while parsing, and
+        // not held beyond it. As documented, concurrent
+        // modification of the file by another process is not
+        // guarded against.
+        let map = unsafe { memmap2::Mmap::map(&file)? };
+        Self::try_from_flir_segment(&FlirSegment::try_from_jpeg_bytes(&map)?)
+    }
+
+    /// Save the image (settings and raw values) to `path` in
+    /// the `bincode` format, to skip parsing it again in a
+    /// later stage of a pipeline. See
+    /// [`load_cache`][ThermalImage::load_cache].
+    pub fn save_cache<P: AsRef<Path>>(&self, path: P) -> Result<()> {
+        bincode::serialize_into(BufWriter::new(File::create(path)?), self)?;
+        Ok(())
+    }
+
+    /// Load an image saved using
+    /// [`save_cache`][ThermalImage::save_cache].
+    pub fn load_cache<P: AsRef<Path>>(path: P) -> Result<Self> {
+        Ok(bincode::deserialize_from(BufReader::new(File::open(
+            path,
+        )?))?)
+    }
+
     /// Try to convert a parsed `ThermalExiftoolJson`
     /// structure into a `ThermalImage`.
     pub fn try_from_thermal_exiftool_json(json: ThermalExiftoolJson) -> Result<Self> {
         Ok(Self {
+            recorded_distance: json.recorded_distance(),
+            field_of_view: json.field_of_view(),
             settings: json.settings,
             image: json.raw.thermal_image()?,
+            gps: None,
+            datetime: None,
+            raw_value_range: None,
+            saturation_range: None,
         })
     }
 }
 
-/// Parse output of `exiftool` json output.
-///
-/// This is the entry point for users interested in parsing
-/// the output from `exiftool -j -b` on a thermal image. It
-/// expects and extracts both the thermal settings, and the
-/// raw image encoded as a base64 string.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ThermalExiftoolJson {
-    #[serde(flatten)]
-    pub settings: ThermalSettings,
+/// Coefficients `[c0, c1]` of the linear map `value = c0 +
+/// c1 * temperature` taking temperatures in `[min, max]` to
+/// the full `u16` range; see [`scale_to_u16`].
+pub fn gray16_coeffs(min: f64, max: f64) -> [f64; 2] {
+    let factor = u16::MAX as f64 / (max - min);
+    [-min * factor, factor]
+}
 
-    #[serde(flatten)]
-    pub(crate) raw: ThermalRawBytes,
+/// Map a temperature to `u16` using the coefficients from
+/// [`gray16_coeffs`], clamping values outside the range
+/// (and NaN to `0`). This is the mapping of
+/// [`ThermalImage::to_gray16`].
+pub fn scale_to_u16([c0, c1]: [f64; 2], temp: f64) -> u16 {
+    (c0 + c1 * temp).clamp(0., u16::MAX as f64) as u16
 }
+
+impl fmt::Debug for ThermalImage {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ThermalImage")
+            .field("settings", &self.settings)
+            .field("dimensions", &self.image.dim())
+            .field("gps", &self.gps)
+            .field("datetime", &self.datetime)
+            .field("raw_value_range", &self.raw_value_range)
+            .field("saturation_range", &self.saturation_range)
+            .field("recorded_distance", &self.recorded_distance)
+            .field("field_of_view", &self.field_of_view)
+            .finish()
+    }
+}
+
 impl TryFrom<ThermalExiftoolJson> for ThermalImage {
     type Error = anyhow::Error;
 
@@ -69,88 +565,290 @@ impl TryFrom<ThermalExiftoolJson> for ThermalImage {
     }
 }
 
-/// Raw image bytes serialized by `exiftool` as JSON.
-#[derive(Serialize, Deserialize, Debug)]
-pub struct ThermalRawBytes {
-    #[serde(rename = "RawThermalImageType")]
-    ty: String,
-
-    #[serde(
-        rename = "RawThermalImage",
-        deserialize_with = "serde_helpers::base64_bytes"
-    )]
-    base64_bytes: Vec<u8>,
+/// Mean of the non-NaN values in each `factor x factor`
+/// block of `values`.
+fn block_mean(values: ArrayView2<f64>, factor: usize) -> Array2<f64> {
+    assert!(factor > 0, "downsampling factor must be positive");
+    let (ht, wid) = values.dim();
+    let dim = (ht.div_ceil(factor), wid.div_ceil(factor));
+    Array2::from_shape_fn(dim, |(row, col)| {
+        let block = values.slice(s![
+            row * factor..((row + 1) * factor).min(ht),
+            col * factor..((col + 1) * factor).min(wid)
+        ]);
+        let (sum, count) = block
+            .iter()
+            .filter(|val| !val.is_nan())
+            .fold((0., 0usize), |(sum, count), val| (sum + val, count + 1));
+        sum / count as f64
+    })
 }
-impl ThermalRawBytes {
-    pub fn thermal_image(&self) -> Result<Array2<f64>> {
-        if self.ty != "TIFF" {
-            bail!("unsupported image type: {}", self.ty);
+
+/// Parse R-JPEGs lazily, one at a time on the calling
+/// thread, as the iterator is advanced. Unlike
+/// `cli::process_paths_par`, this needs no thread pool and
+/// shows no progress, and only one image is in memory at a
+/// time: suitable for streaming on constrained devices.
+///
+/// Errors carry the path of the image as context.
+pub fn iter_rjpegs<I>(paths: I) -> impl Iterator<Item = Result<ThermalImage>>
+where
+    I: IntoIterator<Item = PathBuf>,
+{
+    paths.into_iter().map(|path| {
+        ThermalImage::try_from_rjpeg_path(&path)
+            .with_context(|| format!("parsing {}", path.display()))
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::{
+        gray16_coeffs, iter_rjpegs, scale_to_u16, Dimensions, GpsCoordinates, ThermalImage,
+    };
+    use crate::temperature::ThermalSettings;
+    use ndarray::{array, Array2};
+
+    pub(crate) fn sample_image() -> ThermalImage {
+        ThermalImage {
+            settings: ThermalSettings::builder()
+                .emissivity(0.9)
+                .embedded_distance(12.)
+                .build()
+                .unwrap(),
+            image: array![[17000., 17500.], [18000., 18500.]],
+            gps: Some(GpsCoordinates {
+                latitude: 12.5,
+                longitude: -77.25,
+                altitude: 100.,
+            }),
+            datetime: None,
+            raw_value_range: Some((17000, 18500)),
+            saturation_range: Some((-40., 150.)),
+            recorded_distance: Some(3.),
+            field_of_view: Some(45.),
         }
+    }
 
-        use image::tiff::TiffDecoder;
-        let decoder = TiffDecoder::new(Cursor::new(&self.base64_bytes))?;
-        let (width, height) = decoder.dimensions();
-        let width = width as usize;
-        let height = height as usize;
-        let depth = match decoder.color_type() {
-            ColorType::L8 => 8,
-            ColorType::L16 => 16,
-            _ => bail!("supported color type: {:?}", decoder.color_type()),
-        };
+    #[test]
+    fn new() {
+        let sample = sample_image();
+        let image = ThermalImage::new(sample.settings.clone(), sample.image.clone());
+        assert_eq!(image.image, sample.image);
+        assert!(image.gps.is_none());
+        assert_eq!(image.raw_value_range(), None);
+        assert_eq!(image.field_of_view(), None);
+        assert_eq!(image.effective_distance(None), 12.);
+    }
 
-        use zerocopy::{AsBytes, FromBytes};
-        fn image_as_float<'a, T, R>(decoder: R) -> Result<Vec<f64>>
-        where
-            f64: From<T>,
-            T: AsBytes + FromBytes,
-            R: ImageDecoder<'a>,
-        {
-            let (width, height) = decoder.dimensions();
-            let num_pixels = (width * height) as usize;
-            let mut image: Vec<T> = Vec::with_capacity(num_pixels);
-            unsafe {
-                image.set_len(num_pixels);
-            }
-            decoder.read_image(image.as_bytes_mut())?;
-            Ok(image.into_iter().map(|f| f.into()).collect())
+    #[test]
+    fn temperatures_at() {
+        use crate::any::RadiometricImage;
+
+        let image = sample_image();
+        let temps = RadiometricImage::temperatures(&image, Some(5.)).unwrap();
+        let picked = image.temperatures_at(Some(5.), &[3, 1, 1]).unwrap();
+        assert_eq!(picked, vec![temps[(1, 1)], temps[(0, 1)], temps[(0, 1)]]);
+        assert!(image.temperatures_at(None, &[4]).is_err());
+    }
+
+    #[test]
+    fn fraction_above() {
+        let mut image = sample_image();
+        let temps = image.settings.temperatures(5., &image.image);
+        let threshold = (temps[(0, 1)] + temps[(1, 0)]) / 2.;
+        assert_eq!(
+            image.mask_above(Some(5.), threshold),
+            array![[false, false], [true, true]]
+        );
+        assert_eq!(image.fraction_above(Some(5.), threshold), 0.5);
+
+        image.image[(1, 1)] = f64::NAN;
+        assert_eq!(
+            image.mask_above(Some(5.), threshold),
+            array![[false, false], [true, false]]
+        );
+        assert_eq!(image.fraction_above(Some(5.), threshold), 1. / 3.);
+
+        image.image.fill(f64::NAN);
+        assert!(image.fraction_above(Some(5.), threshold).is_nan());
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let image = sample_image();
+
+        let bytes = bincode::serialize(&image).unwrap();
+        let cached: ThermalImage = bincode::deserialize(&bytes).unwrap();
+        let json = serde_json::to_string(&image).unwrap();
+        let from_json: ThermalImage = serde_json::from_str(&json).unwrap();
+
+        let debug = format!("{:?}", image.clone());
+        assert!(debug.contains("dimensions: (2, 2)"));
+        assert!(!debug.contains("17500"));
+
+        for other in [cached, from_json].iter() {
+            assert_eq!(other.image, image.image);
+            assert_eq!(other.settings.embedded_distance(), Some(12.));
+            assert_eq!(
+                other.settings.temperatures(5., &other.image),
+                image.settings.temperatures(5., &image.image)
+            );
+            assert_eq!(other.gps.unwrap().longitude, -77.25);
+            assert_eq!(other.raw_value_range(), Some((17000, 18500)));
         }
+    }
+
+    #[test]
+    fn gray_images() {
+        let image = sample_image();
+        let temps: Vec<f64> = image.temperature_pixels(None).map(|(_, _, t)| t).collect();
+
+        let gray = image.to_gray16(None, temps[0], temps[3]);
+        assert_eq!(gray.dimensions(), (2, 2));
+        assert_eq!(gray.get_pixel(0, 0).0, [0]);
+        assert_eq!(gray.get_pixel(1, 1).0, [u16::MAX]);
+
+        // Values outside the range are clamped.
+        let gray = image.to_gray8(None, temps[1], temps[2]);
+        assert_eq!(gray.get_pixel(0, 0).0, [0]);
+        assert_eq!(gray.get_pixel(1, 1).0, [u8::MAX]);
+        assert_eq!(scale_to_u16(gray16_coeffs(0., 1.), f64::NAN), 0);
+    }
+
+    #[test]
+    fn difference() {
+        let baseline = sample_image();
+        let mut current = sample_image();
+        current.image[(0, 1)] += 500.;
+
+        let diff = current.difference(&baseline, 5.).unwrap();
+        assert_eq!(diff[(0, 0)], 0.);
+        assert!(diff[(0, 1)] > 0.);
+        assert_eq!(diff[(1, 1)], 0.);
+
+        current.image = ndarray::array![[17000., 17500.]];
+        assert!(current.difference(&baseline, 5.).is_err());
+    }
 
-        let output = if depth == 8 {
-            image_as_float::<u8, _>(decoder)?
-        } else if depth == 16 {
-            image_as_float::<u16, _>(decoder)?
-        } else {
-            unreachable!("unexpected depth: {}", depth);
+    #[test]
+    fn raw_only() {
+        use crate::{
+            error::ParseError,
+            flir::tests::{flir_jpeg, raw_data_segment, with_record},
         };
 
-        Ok(Array2::from_shape_vec((height, width), output)?)
+        let jpeg = flir_jpeg(&raw_data_segment());
+        let err = ThermalImage::try_from_rjpeg(&jpeg).unwrap_err();
+        assert_eq!(
+            err.downcast_ref::<ParseError>(),
+            Some(&ParseError::NoCameraParams)
+        );
+        let (raw, settings) = ThermalImage::try_raw_only_from_rjpeg(&jpeg).unwrap();
+        assert_eq!(raw, array![[0., 1., 2.], [3., 4., 5.]]);
+        assert!(settings.is_none());
+
+        // A truncated camera params record.
+        let segment = with_record(raw_data_segment(), 0x20, &[0u8; 0x10]);
+        let jpeg = flir_jpeg(&segment);
+        assert!(ThermalImage::try_from_rjpeg(&jpeg).is_err());
+        let (raw, settings) = ThermalImage::try_raw_only_from_rjpeg(&jpeg).unwrap();
+        assert_eq!(raw, array![[0., 1., 2.], [3., 4., 5.]]);
+        assert!(settings.is_none());
     }
-}
 
-mod serde_helpers {
-    use lazy_static::lazy_static;
-    use regex::Regex;
-    use serde::*;
-
-    pub fn base64_bytes<'de, D>(de: D) -> Result<Vec<u8>, D::Error>
-    where
-        D: Deserializer<'de>,
-    {
-        lazy_static! {
-            static ref RE: Regex = Regex::new(r"^base64:").unwrap();
-        }
+    #[test]
+    fn saturation_mask() {
+        use crate::stats::Stats;
+
+        // The sensor limits are wider than the data: the
+        // extremes of the image are kept.
+        let mut image = sample_image();
+        let (min, max) = image.saturation_range().unwrap();
+        let planck = image.settings.planck_only_transform();
+        assert!(min < planck(17000.) && planck(18500.) < max);
+        assert!(!image.saturation_mask().iter().any(|m| *m));
+
+        image.image[(0, 1)] = u16::MAX as f64;
+        image.image[(1, 0)] = 0.;
+        let mask = image.saturation_mask();
+        assert_eq!(mask, ndarray::array![[false, true], [true, false]]);
+
+        let mut stats = Stats::default();
+        stats.accumulate_masked(
+            image.temperature_pixels(None).map(|(_, _, t)| t),
+            mask.iter().copied(),
+        );
+        assert_eq!(stats.count(), 2.);
+        let hottest = image.temperature_pixels(None).last().unwrap().2;
+        assert_eq!(stats.max(), hottest);
+
+        // Beyond the limits of a narrower sensor.
+        image.saturation_range = Some((min, planck(18500.)));
+        let mask = image.saturation_mask();
+        assert_eq!(mask, ndarray::array![[false, true], [true, true]]);
+    }
 
-        use serde::de::Error;
-        let str_rep = <String as Deserialize>::deserialize(de)?;
+    #[test]
+    fn row_major_values() {
+        let mut image = sample_image();
+        let expected = vec![17000., 17500., 18000., 18500.];
+        assert_eq!(image.as_contiguous_slice(), Some(&expected[..]));
+        assert_eq!(image.to_row_major_vec(), expected);
 
-        RE.find(&str_rep).ok_or(Error::custom(
-            "unexpected format: must begin with `base64:`",
-        ))?;
+        image.image = image.image.reversed_axes();
+        assert_eq!(image.as_contiguous_slice(), None);
+        assert_eq!(image.to_row_major_vec(), [17000., 18000., 17500., 18500.]);
+    }
+
+    #[test]
+    fn iter_rjpegs_is_lazy() {
+        let paths = vec!["missing-1.jpg".into(), "missing-2.jpg".into()];
+        let mut images = iter_rjpegs(paths);
+        let err = images.next().unwrap().unwrap_err();
+        assert!(format!("{:#}", err).contains("missing-1.jpg"));
+        assert!(images.next().unwrap().is_err());
+        assert!(images.next().is_none());
+    }
+
+    #[test]
+    fn distance_resolution() {
+        let mut image = sample_image();
+        assert_eq!(image.effective_distance(Some(5.)), 5.);
+        assert_eq!(image.effective_distance(None), 12.);
 
-        use base64::decode;
-        let slice = &str_rep[7..];
-        let bytes = decode(slice).map_err(Error::custom)?;
+        image.settings = ThermalSettings::builder().build().unwrap();
+        assert_eq!(image.effective_distance(None), 3.);
+        image.recorded_distance = None;
+        assert_eq!(image.effective_distance(None), 1.);
+    }
+
+    #[test]
+    fn downsample() {
+        let mut image = sample_image();
+        image.image = Array2::from_shape_fn((3, 5), |(row, col)| (row * 5 + col) as f64);
+
+        let small = image.downsample(2);
+        assert_eq!(small.image, array![[3., 5., 6.5], [10.5, 12.5, 14.]]);
+        assert_eq!(
+            small.dimensions(),
+            Dimensions {
+                width: 3,
+                height: 2
+            }
+        );
+        assert_eq!(small.dimensions().shape(), small.image.dim());
+        assert_eq!(Dimensions::from_shape((2, 3)), small.dimensions());
+        assert_eq!(small.settings.embedded_distance(), Some(12.));
+        assert_eq!(image.downsample(1).image, image.image);
 
-        Ok(bytes)
+        let temps = sample_image().downsample_temperatures(2, None);
+        let expected = sample_image()
+            .temperature_pixels(None)
+            .map(|(_, _, t)| t)
+            .sum::<f64>()
+            / 4.;
+        assert_eq!(temps.dim(), (1, 1));
+        assert!((temps[(0, 0)] - expected).abs() < 1e-9);
     }
 }
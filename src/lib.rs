@@ -69,22 +69,28 @@
 //! [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
 //! [ExifTool]: //exiftool.org
 //! [parsing-rjpeg]: crate::image::ThermalImage::try_from_rjpeg
-//! [parsing-exiftool]: crate::image::ThermalExiftoolJson
+//! [parsing-exiftool]: crate::exif::ThermalExiftoolJson
 
 #[macro_use]
 mod parse;
-pub(crate) mod flir;
+pub mod flir;
 
+pub mod any;
+pub mod error;
+pub mod exif;
 pub mod image;
+pub mod seq;
 pub mod temperature;
 
 pub mod stats;
 
-pub use crate::image::ThermalExiftoolJson;
+pub use crate::exif::ThermalExiftoolJson;
 pub use crate::image::ThermalImage;
 
 #[cfg(feature = "dji")]
 pub mod dji;
+#[cfg(feature = "dji")]
+pub mod validate;
 
 #[cfg(feature = "cli")]
 pub mod cli;
@@ -0,0 +1,213 @@
+//! Parse radiometric sequences (`.seq` files) recorded by
+//! FLIR research cameras.
+//!
+//! A `.seq` file is a concatenation of frames, each stored
+//! as FFF data (see [`FlirSegment`]) with its own raw
+//! sensor values and camera params records. Frames with PNG
+//! compressed raw values are not yet supported.
+use anyhow::{anyhow, ensure, Context, Result};
+use std::{
+    fs::File,
+    io::{Cursor, Read, Seek, SeekFrom},
+    ops::Range,
+    path::Path,
+    sync::Mutex,
+};
+
+use crate::{flir::FlirSegment, ThermalImage};
+
+/// Size of the chunks read while scanning for the frames.
+const SCAN_CHUNK_SIZE: usize = 1 << 16;
+
+/// The frames of a `.seq` file. The frames are located when
+/// the file is opened, but only read and parsed on demand
+/// (see [`frame`][SeqFile::frame]), so that they may be
+/// parsed in parallel.
+pub struct SeqFile {
+    source: Source,
+    frames: Vec<Range<u64>>,
+}
+
+/// Where the frames are read from.
+enum Source {
+    Bytes(Vec<u8>),
+    /// Frames are read by seeking, so that sequences larger
+    /// than the memory can be processed.
+    File(Mutex<File>),
+}
+
+impl SeqFile {
+    /// Locate the frames in the contents of a `.seq` file.
+    /// Fails if there are none.
+    pub fn try_from_bytes(data: Vec<u8>) -> Result<Self> {
+        let frames = locate_frames(&mut Cursor::new(&data[..]))?;
+        Ok(SeqFile {
+            source: Source::Bytes(data),
+            frames,
+        })
+    }
+
+    /// Locate the frames of the `.seq` file at `path`. Only
+    /// the headers of the frames are read here: the file is
+    /// kept open to read the frames on demand.
+    pub fn try_from_path<P: AsRef<Path>>(path: P) -> Result<Self> {
+        let mut file = File::open(path)?;
+        let frames = locate_frames(&mut file)?;
+        Ok(SeqFile {
+            source: Source::File(Mutex::new(file)),
+            frames,
+        })
+    }
+
+    /// Number of frames.
+    pub fn len(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.frames.is_empty()
+    }
+
+    /// Parse the FFF data of frame `idx` (zero-based).
+    pub fn frame_segment(&self, idx: usize) -> Result<FlirSegment> {
+        let range = self.frames.get(idx).cloned().ok_or_else(|| {
+            anyhow!(
+                "frame {} out of bounds: sequence has {} frames",
+                idx,
+                self.len()
+            )
+        })?;
+        let data = match &self.source {
+            Source::Bytes(data) => data[range.start as usize..range.end as usize].to_vec(),
+            Source::File(file) => {
+                let mut file = file.lock().unwrap();
+                read_at(&mut *file, range.start, range.end - range.start)?
+            }
+        };
+        FlirSegment::try_from_fff_bytes(data)
+    }
+
+    /// Parse frame `idx` (zero-based) as a `ThermalImage`.
+    pub fn frame(&self, idx: usize) -> Result<ThermalImage> {
+        ThermalImage::try_from_flir_segment(&self.frame_segment(idx)?)
+    }
+}
+
+/// Locate the frames by reading only their headers and
+/// record directories.
+fn locate_frames<R: Read + Seek>(rdr: &mut R) -> Result<Vec<Range<u64>>> {
+    let data_len = rdr.seek(SeekFrom::End(0))?;
+    let mut frames = vec![];
+    let mut pos = 0;
+    while let Some(start) = find_fff(rdr, pos)? {
+        let len = frame_length(rdr, start)
+            .with_context(|| format!("frame {} at offset {:#x}", frames.len(), start))?;
+        // A truncated last frame is kept: parsing it
+        // reports the truncation.
+        let end = (start + len).min(data_len);
+        frames.push(start..end);
+        pos = end;
+    }
+    ensure!(
+        !frames.is_empty(),
+        "no FFF frames found: not a radiometric sequence"
+    );
+    Ok(frames)
+}
+
+/// Length of the FFF data at `start`; see
+/// [`FlirSegment::fff_length`].
+fn frame_length<R: Read + Seek>(rdr: &mut R, start: u64) -> Result<u64> {
+    let header = read_at(rdr, start, 0x40)?;
+    let header_len = FlirSegment::fff_header_length(&header)?;
+    let header = read_at(rdr, start, header_len as u64)?;
+    Ok(FlirSegment::fff_length(&header)? as u64)
+}
+
+/// Read `len` bytes at `offset`, or fewer if the data ends
+/// before.
+fn read_at<R: Read + Seek>(rdr: &mut R, offset: u64, len: u64) -> Result<Vec<u8>> {
+    rdr.seek(SeekFrom::Start(offset))?;
+    let mut buf = vec![];
+    rdr.take(len).read_to_end(&mut buf)?;
+    Ok(buf)
+}
+
+/// Offset of the next FFF signature at or after `from`.
+fn find_fff<R: Read + Seek>(rdr: &mut R, from: u64) -> Result<Option<u64>> {
+    const SIGNATURE: &[u8] = b"FFF\0";
+
+    rdr.seek(SeekFrom::Start(from))?;
+    let mut buf = vec![0u8; SCAN_CHUNK_SIZE];
+    // Offset of `buf[0]`, and the number of bytes in `buf`
+    // carried over from the previous chunk.
+    let (mut base, mut carried) = (from, 0);
+    loop {
+        let read = rdr.read(&mut buf[carried..])?;
+        let filled = carried + read;
+        if let Some(pos) = buf[..filled].windows(4).position(|w| w == SIGNATURE) {
+            return Ok(Some(base + pos as u64));
+        }
+        if read == 0 {
+            return Ok(None);
+        }
+        // Keep the tail: a signature may span two chunks.
+        let keep = filled.min(SIGNATURE.len() - 1);
+        buf.copy_within(filled - keep..filled, 0);
+        base += (filled - keep) as u64;
+        carried = keep;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{find_fff, SeqFile, SCAN_CHUNK_SIZE};
+    use std::io::Cursor;
+
+    /// FFF header with an empty record directory.
+    fn empty_frame() -> Vec<u8> {
+        let mut data = vec![0u8; 0x40];
+        data[0..4].copy_from_slice(b"FFF\0");
+        data[0x14..0x18].copy_from_slice(&100u32.to_ne_bytes());
+        data[0x18..0x1c].copy_from_slice(&0x40u32.to_ne_bytes());
+        data
+    }
+
+    #[test]
+    fn locate_frames() {
+        let mut data = b"header".to_vec();
+        data.extend(empty_frame());
+        data.extend(&[0, 0, 0]);
+        data.extend(empty_frame());
+
+        let seq = SeqFile::try_from_bytes(data.clone()).unwrap();
+        assert_eq!(seq.len(), 2);
+        assert_eq!(seq.frames, vec![6..0x46, 0x49..0x89]);
+        assert!(seq.frame_segment(1).unwrap().records().is_empty());
+        assert!(seq.frame_segment(2).is_err());
+
+        assert!(SeqFile::try_from_bytes(b"not a seq".to_vec()).is_err());
+
+        // Read from a file.
+        let path = std::env::temp_dir().join(format!("thermal-{}.seq", std::process::id()));
+        std::fs::write(&path, &data).unwrap();
+        let res =
+            SeqFile::try_from_path(&path).and_then(|seq| Ok((seq.frame_segment(1)?, seq.frames)));
+        std::fs::remove_file(&path).unwrap();
+        let (segment, frames) = res.unwrap();
+        assert_eq!(frames, vec![6..0x46, 0x49..0x89]);
+        assert!(segment.records().is_empty());
+    }
+
+    #[test]
+    fn signature_across_chunks() {
+        let mut data = vec![0u8; SCAN_CHUNK_SIZE - 2];
+        data.extend(b"FFF\0");
+        let mut rdr = Cursor::new(&data[..]);
+        assert_eq!(
+            find_fff(&mut rdr, 0).unwrap(),
+            Some(SCAN_CHUNK_SIZE as u64 - 2)
+        );
+        assert_eq!(find_fff(&mut rdr, SCAN_CHUNK_SIZE as u64).unwrap(), None);
+    }
+}
@@ -1,6 +1,8 @@
 //! Utilities to accumulate first and second moments; min;
 //! and max of a `f64` statistic incrementally.
-use serde_derive::Serialize;
+use ndarray::{Array2, Axis};
+use rayon::prelude::*;
+use serde::{ser::SerializeStruct, Deserialize, Deserializer, Serialize, Serializer};
 use std::ops::AddAssign;
 
 /// Stores the statistics collected from a `f64` random
@@ -10,12 +12,24 @@ use std::ops::AddAssign;
 /// - a `f64` value.  Adds a new sample
 /// - a `(f64, f64)` tuple.  Adds the first component with weight specified by the second component.
 /// - another `PixelStats` value.  Accumulates the statistic from the other into `self`.
-#[derive(Debug, Serialize, Clone)]
+///
+/// NaN values (eg. temperatures of dead pixels) are
+/// skipped.
+///
+/// # Numerical Stability
+///
+/// The mean and the second central moment are updated
+/// using Welford's online algorithm, and two partial
+/// statistics are merged using Chan et al.'s parallel
+/// formula. This avoids the catastrophic cancellation in
+/// `E[x^2] - E[x]^2` when the variance is small compared
+/// to the mean (eg. temperatures in Kelvin).
+#[derive(Debug, Clone)]
 pub struct Stats {
     max: f64,
     min: f64,
-    sum: f64,
-    sum_2: f64,
+    mean: f64,
+    m2: f64,
     count: f64,
 }
 
@@ -25,19 +39,28 @@ impl Default for Stats {
         Stats {
             max: NEG_INFINITY,
             min: INFINITY,
-            sum: 0.,
-            sum_2: 0.,
+            mean: 0.,
+            m2: 0.,
             count: 0.,
         }
     }
 }
 impl AddAssign<(f64, f64)> for Stats {
     fn add_assign(&mut self, other: (f64, f64)) {
-        self.max = self.max.max(other.0);
-        self.min = self.min.min(other.0);
-        self.sum += other.0;
-        self.sum_2 += other.0 * other.0;
-        self.count += other.1;
+        let (val, weight) = other;
+        if val.is_nan() {
+            return;
+        }
+        self.max = self.max.max(val);
+        self.min = self.min.min(val);
+
+        self.count += weight;
+        if self.count == 0. {
+            return;
+        }
+        let delta = val - self.mean;
+        self.mean += delta * weight / self.count;
+        self.m2 += weight * delta * (val - self.mean);
     }
 }
 
@@ -50,13 +73,55 @@ impl AddAssign<&Stats> for Stats {
     fn add_assign(&mut self, other: &Stats) {
         self.max = self.max.max(other.max);
         self.min = self.min.min(other.min);
-        self.sum += other.sum;
-        self.sum_2 += other.sum_2;
-        self.count += other.count;
+
+        let count = self.count + other.count;
+        if count == 0. {
+            return;
+        }
+        let delta = other.mean - self.mean;
+        self.mean += delta * other.count / count;
+        self.m2 += other.m2 + delta * delta * self.count * other.count / count;
+        self.count = count;
     }
 }
 
 impl Stats {
+    /// Accumulate the `values`, skipping those whose
+    /// corresponding entry in `mask` is set (eg. saturated
+    /// pixels from
+    /// [`saturation_mask`][crate::image::ThermalImage::saturation_mask]).
+    pub fn accumulate_masked<I, M>(&mut self, values: I, mask: M)
+    where
+        I: IntoIterator<Item = f64>,
+        M: IntoIterator<Item = bool>,
+    {
+        for (val, masked) in values.into_iter().zip(mask) {
+            if !masked {
+                *self += val;
+            }
+        }
+    }
+
+    /// Accumulate the stats of `values` in parallel: the
+    /// rows are accumulated separately (using `rayon`), and
+    /// the partial stats merged. Only worth it for large
+    /// images (or when processing few images).
+    pub fn from_array_par(values: &Array2<f64>) -> Stats {
+        values
+            .axis_iter(Axis(0))
+            .into_par_iter()
+            .fold(Stats::default, |mut stats, row| {
+                for &val in row.iter() {
+                    stats += val;
+                }
+                stats
+            })
+            .reduce(Stats::default, |mut stats, other| {
+                stats += &other;
+                stats
+            })
+    }
+
     #[inline]
     pub fn max(&self) -> f64 {
         self.max
@@ -69,12 +134,15 @@ impl Stats {
 
     #[inline]
     pub fn sum(&self) -> f64 {
-        self.sum
+        self.mean * self.count
     }
 
+    /// Sum of squares of the samples. This is derived from
+    /// the accumulated moments and is only provided for
+    /// compatibility.
     #[inline]
     pub fn sum_2(&self) -> f64 {
-        self.sum_2
+        self.m2 + self.count * self.mean * self.mean
     }
 
     #[inline]
@@ -84,12 +152,12 @@ impl Stats {
 
     #[inline]
     pub fn mean(&self) -> f64 {
-        self.sum / self.count
+        self.mean
     }
 
     #[inline]
     pub fn variance(&self) -> f64 {
-        self.sum_2 / self.count - self.mean().powi(2)
+        self.m2 / self.count
     }
 
     #[inline]
@@ -97,3 +165,223 @@ impl Stats {
         self.variance().sqrt()
     }
 }
+
+/// Serializes the same fields as the earlier sum-of-squares
+/// based implementation, so that existing consumers of the
+/// JSON output keep working, along with the derived `mean`,
+/// `variance` and `std_dev`.
+impl Serialize for Stats {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut st = serializer.serialize_struct("Stats", 8)?;
+        st.serialize_field("max", &self.max)?;
+        st.serialize_field("min", &self.min)?;
+        st.serialize_field("sum", &self.sum())?;
+        st.serialize_field("sum_2", &self.sum_2())?;
+        st.serialize_field("count", &self.count)?;
+        st.serialize_field("mean", &self.mean())?;
+        st.serialize_field("variance", &self.variance())?;
+        st.serialize_field("std_dev", &self.std_deviation())?;
+        st.end()
+    }
+}
+
+/// Reconstructs the statistics from the accumulated fields
+/// (`max`, `min`, `sum`, `sum_2` and `count`); the derived
+/// fields are ignored. `min` and `max` may be `null` (as
+/// written by `serde_json` for empty statistics).
+impl<'de> Deserialize<'de> for Stats {
+    fn deserialize<D: Deserializer<'de>>(de: D) -> Result<Self, D::Error> {
+        #[derive(serde_derive::Deserialize)]
+        struct Accumulated {
+            max: Option<f64>,
+            min: Option<f64>,
+            sum: f64,
+            sum_2: f64,
+            count: f64,
+        }
+
+        let acc = Accumulated::deserialize(de)?;
+        if acc.count == 0. {
+            return Ok(Stats::default());
+        }
+        let mean = acc.sum / acc.count;
+        Ok(Stats {
+            max: acc.max.unwrap_or(f64::NEG_INFINITY),
+            min: acc.min.unwrap_or(f64::INFINITY),
+            mean,
+            m2: (acc.sum_2 - acc.count * mean * mean).max(0.),
+            count: acc.count,
+        })
+    }
+}
+
+/// Compute percentiles (each in `[0, 100]`) of a
+/// collection of values using the nearest-rank method. NaN
+/// values are ignored. Returns `None` if there are no
+/// (non-NaN) values.
+///
+/// Unlike [`Stats`], this needs all the values in memory.
+pub fn percentiles<I: IntoIterator<Item = f64>>(values: I, pcts: &[f64]) -> Option<Vec<f64>> {
+    let mut values: Vec<f64> = values.into_iter().filter(|v| !v.is_nan()).collect();
+    if values.is_empty() {
+        return None;
+    }
+    values.sort_unstable_by(|a, b| a.partial_cmp(b).unwrap());
+
+    let len = values.len();
+    Some(
+        pcts.iter()
+            .map(|pct| {
+                let rank = (pct.clamp(0., 100.) / 100. * len as f64).ceil() as usize;
+                values[rank.max(1) - 1]
+            })
+            .collect(),
+    )
+}
+
+/// Histogram of a collection of values in `bins` equal
+/// width bins spanning the range of the values, as `(lo,
+/// hi, counts)`. The last bin includes `hi`. NaN (and
+/// infinite) values are dropped; if there are no other
+/// values, `lo` and `hi` are NaN and all counts are zero.
+pub fn histogram<I: IntoIterator<Item = f64>>(values: I, bins: usize) -> (f64, f64, Vec<u64>) {
+    let values: Vec<f64> = values.into_iter().filter(|v| v.is_finite()).collect();
+    let mut counts = vec![0; bins];
+    if values.is_empty() {
+        return (f64::NAN, f64::NAN, counts);
+    }
+    let lo = values.iter().copied().fold(f64::INFINITY, f64::min);
+    let hi = values.iter().copied().fold(f64::NEG_INFINITY, f64::max);
+    if bins == 0 {
+        return (lo, hi, counts);
+    }
+
+    let width = (hi - lo) / bins as f64;
+    for val in values {
+        let bin = if width > 0. {
+            (((val - lo) / width) as usize).min(bins - 1)
+        } else {
+            0
+        };
+        counts[bin] += 1;
+    }
+    (lo, hi, counts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{histogram, percentiles, Stats};
+    use ndarray::Array2;
+
+    fn dataset() -> Vec<f64> {
+        // Temperatures in Kelvin with a small spread: the
+        // worst case for the naive formula.
+        (0..640 * 512usize)
+            .map(|i| 300. + ((i * 7919) % 1000) as f64 * 1e-4)
+            .collect()
+    }
+
+    fn two_pass_variance(data: &[f64]) -> f64 {
+        let n = data.len() as f64;
+        let mean = data.iter().sum::<f64>() / n;
+        data.iter().map(|x| (x - mean).powi(2)).sum::<f64>() / n
+    }
+
+    #[test]
+    fn welford_matches_two_pass() {
+        let data = dataset();
+        let mut stats = Stats::default();
+        for &x in &data {
+            stats += x;
+        }
+
+        let expected = two_pass_variance(&data);
+        assert!(stats.variance() >= 0.);
+        assert!((stats.variance() - expected).abs() < 1e-12);
+        assert!((stats.mean() - data.iter().sum::<f64>() / data.len() as f64).abs() < 1e-9);
+        assert_eq!(stats.count(), data.len() as f64);
+    }
+
+    #[test]
+    fn merge_matches_sequential() {
+        let data = dataset();
+        let mut sequential = Stats::default();
+        for &x in &data {
+            sequential += x;
+        }
+
+        let mut merged = Stats::default();
+        for chunk in data.chunks(4097) {
+            let mut partial = Stats::default();
+            for &x in chunk {
+                partial += x;
+            }
+            merged += &partial;
+        }
+        merged += &Stats::default();
+
+        assert!(merged.variance() >= 0.);
+        assert!((merged.variance() - sequential.variance()).abs() < 1e-12);
+        assert!((merged.mean() - sequential.mean()).abs() < 1e-9);
+        assert_eq!(merged.min(), sequential.min());
+        assert_eq!(merged.max(), sequential.max());
+    }
+
+    #[test]
+    fn par_matches_sequential() {
+        let data = dataset();
+        let mut sequential = Stats::default();
+        for &x in &data {
+            sequential += x;
+        }
+
+        let par = Stats::from_array_par(&Array2::from_shape_vec((512, 640), data).unwrap());
+        assert!((par.variance() - sequential.variance()).abs() < 1e-12);
+        assert!((par.mean() - sequential.mean()).abs() < 1e-9);
+        assert_eq!(par.count(), sequential.count());
+        assert_eq!(par.min(), sequential.min());
+        assert_eq!(par.max(), sequential.max());
+    }
+
+    #[test]
+    fn nearest_rank_percentiles() {
+        let data = (1..=100).map(|i| i as f64).chain(Some(f64::NAN));
+        assert_eq!(
+            percentiles(data, &[0., 1., 50., 99., 100.]),
+            Some(vec![1., 1., 50., 99., 100.])
+        );
+        assert_eq!(percentiles(vec![f64::NAN], &[50.]), None);
+    }
+
+    #[test]
+    fn equal_width_histogram() {
+        let data = vec![0., 1., 2., 3., 4., f64::NAN, 10.];
+        assert_eq!(histogram(data, 5), (0., 10., vec![2, 2, 1, 0, 1]));
+        assert_eq!(histogram(vec![2., 2.], 3), (2., 2., vec![2, 0, 0]));
+
+        let (lo, hi, counts) = histogram(vec![f64::NAN], 2);
+        assert!(lo.is_nan() && hi.is_nan());
+        assert_eq!(counts, vec![0, 0]);
+    }
+
+    #[test]
+    fn serde_round_trip() {
+        let mut stats = Stats::default();
+        for &x in &[1., 2., 3., 4.] {
+            stats += x;
+        }
+        let json = serde_json::to_value(&stats).unwrap();
+        assert_eq!(json["mean"], 2.5);
+        assert_eq!(json["variance"], 1.25);
+        assert_eq!(json["std_dev"], 1.25f64.sqrt());
+
+        let parsed: Stats = serde_json::from_value(json).unwrap();
+        assert_eq!(parsed.mean(), 2.5);
+        assert_eq!(parsed.variance(), 1.25);
+        assert_eq!((parsed.min(), parsed.max()), (1., 4.));
+
+        let empty = serde_json::to_string(&Stats::default()).unwrap();
+        let parsed: Stats = serde_json::from_str(&empty).unwrap();
+        assert_eq!(parsed.count(), 0.);
+    }
+}
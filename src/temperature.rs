@@ -6,6 +6,15 @@
 //! [read_thermal.py]: //github.com/Nervengift/read_thermal.py/blob/master/flir_image_extractor.py
 //! [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
 
+use std::{
+    convert::TryInto,
+    fmt,
+    ops::{Add, AddAssign, Sub, SubAssign},
+    str::FromStr,
+};
+
+use anyhow::{bail, ensure, Error, Result};
+use ndarray::Array2;
 use serde_derive::*;
 
 use crate::flir::FlirCameraParams;
@@ -37,7 +46,16 @@ use crate::flir::FlirCameraParams;
 /// using `1.0` instead of a true value of `50.0` is about
 /// 2-3 deg C; the relative error (i.e. error in temperature
 /// difference across pixels) is much smaller.
-#[derive(Serialize, Deserialize, Debug)]
+///
+/// Some cameras do record an `ObjectDistance` parameter.
+/// This is available as
+/// [`embedded_distance`][ThermalSettings::embedded_distance],
+/// and [`effective_distance`][ThermalSettings::effective_distance]
+/// resolves the distance to use from a user provided
+/// override, the embedded value and the above default.
+/// Images may also record a focus distance; see
+/// [`ThermalImage::effective_distance`][crate::image::ThermalImage::effective_distance].
+#[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "PascalCase")]
 pub struct ThermalSettings {
     #[serde(
@@ -52,10 +70,14 @@ pub struct ThermalSettings {
 
     #[serde(
         rename = "IRWindowTemperature",
+        default = "defaults::ir_window_temperature",
         deserialize_with = "serde_helpers::float_with_suffix"
     )]
     ir_window_temperature: f64,
-    #[serde(rename = "IRWindowTransmission")]
+    #[serde(
+        rename = "IRWindowTransmission",
+        default = "defaults::ir_window_transmission"
+    )]
     ir_window_transmission: f64,
 
     planck_r1: f64,
@@ -66,20 +88,76 @@ pub struct ThermalSettings {
 
     #[serde(deserialize_with = "serde_helpers::float_with_suffix")]
     atmospheric_temperature: f64,
-    #[serde(rename = "AtmosphericTransAlpha1")]
+    #[serde(
+        rename = "AtmosphericTransAlpha1",
+        default = "defaults::atmospheric_transmission_alpha_1"
+    )]
     atmospheric_transmission_alpha_1: f64,
-    #[serde(rename = "AtmosphericTransAlpha2")]
+    #[serde(
+        rename = "AtmosphericTransAlpha2",
+        default = "defaults::atmospheric_transmission_alpha_2"
+    )]
     atmospheric_transmission_alpha_2: f64,
-    #[serde(rename = "AtmosphericTransBeta1")]
+    #[serde(
+        rename = "AtmosphericTransBeta1",
+        default = "defaults::atmospheric_transmission_beta_1"
+    )]
     atmospheric_transmission_beta_1: f64,
-    #[serde(rename = "AtmosphericTransBeta2")]
+    #[serde(
+        rename = "AtmosphericTransBeta2",
+        default = "defaults::atmospheric_transmission_beta_2"
+    )]
     atmospheric_transmission_beta_2: f64,
-    #[serde(rename = "AtmosphericTransX")]
+    #[serde(
+        rename = "AtmosphericTransX",
+        default = "defaults::atmospheric_transmission_x"
+    )]
     atmospheric_transmission_x: f64,
+    /// Coefficients of the water vapour pressure model; see
+    /// [`DEFAULT_WATER_VAPOR_SERIES`]. Not recorded by
+    /// cameras.
+    #[serde(default = "defaults::water_vapor_series")]
+    water_vapor_series: [f64; 4],
+
+    #[serde(
+        rename = "ObjectDistance",
+        default,
+        deserialize_with = "serde_helpers::distance_with_suffix"
+    )]
+    embedded_distance: Option<f64>,
+
+    /// Ignore the IR window parameters; see
+    /// [`no_window`][ThermalSettings::no_window].
+    #[serde(default)]
+    no_window: bool,
 }
 
 const CELICIUS_OFFSET: f64 = 273.15;
+
+/// Distance (in meters) used when none is given or
+/// recorded. See [`ThermalSettings`].
+pub const DEFAULT_DISTANCE: f64 = 1.0;
+
+/// Coefficients `[c0, c1, c2, c3]` of the default model of
+/// the saturation water vapour pressure (in mmHg) at the
+/// atmospheric temperature `T` (in celicius):
+///
+/// `h2o = RH / 100 * exp(c0 + c1 T + c2 T^2 + c3 T^3)`
+///
+/// These are the constants of the [Thermimage R library],
+/// fit for typical ground level conditions. Use
+/// [`with_water_vapor_series`][ThermalSettings::with_water_vapor_series]
+/// to use other coefficients.
+///
+/// [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
+pub const DEFAULT_WATER_VAPOR_SERIES: [f64; 4] = [1.5587, 0.06939, -0.00027816, 0.00000068455];
 impl ThermalSettings {
+    /// Construct settings by hand. See
+    /// [`ThermalSettingsBuilder`].
+    pub fn builder() -> ThermalSettingsBuilder {
+        ThermalSettingsBuilder::default()
+    }
+
     // raw = PR1/(PR2*(exp(PB/(temp+273.15))-PF))-PO
     fn planck_temp_to_raw(&self, temp: f64) -> f64 {
         self.planck_r1
@@ -89,88 +167,310 @@ impl ThermalSettings {
 
     // inverse of above
     fn planck_raw_to_temp(&self, raw: f64) -> f64 {
-        self.planck_b
-            / (self.planck_r1 / (self.planck_r2 * (raw + self.planck_o)) + self.planck_f).ln()
-            - CELICIUS_OFFSET
+        self.try_planck_raw_to_temp(raw).unwrap_or(f64::NAN)
     }
 
-    // tau1<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))
-    //  +(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
-    fn atmospheric_affine1(&self, val: f64) -> f64 {
-        self.atmospheric_transmission_alpha_1 + self.atmospheric_transmission_beta_1 * val
+    /// Invert the Planck curve: compute the temperature in
+    /// celicius from an (atmospherically adjusted) sensor
+    /// value. Returns `None` if there is no such
+    /// temperature, eg. for dead pixels whose value is below
+    /// the offset `PO`. The conversion methods return `NaN`
+    /// for such values.
+    pub fn try_planck_raw_to_temp(&self, raw: f64) -> Option<f64> {
+        let arg = self.planck_r1 / (self.planck_r2 * (raw + self.planck_o)) + self.planck_f;
+        if arg.is_nan() || arg <= 0. {
+            return None;
+        }
+        let temp = self.planck_b / arg.ln() - CELICIUS_OFFSET;
+        if temp.is_finite() && temp > -CELICIUS_OFFSET {
+            Some(temp)
+        } else {
+            None
+        }
     }
 
-    fn atmospheric_affine2(&self, val: f64) -> f64 {
-        self.atmospheric_transmission_alpha_2 + self.atmospheric_transmission_beta_2 * val
+    /// Override the emissivity (in `(0, 1]`), eg. when the
+    /// value recorded by the camera is a default that does
+    /// not match the object.
+    ///
+    /// An [`AtmosphericModel`] built from the settings
+    /// earlier does not see the new value: build it again.
+    pub fn with_emissivity(mut self, emissivity: f64) -> Result<Self> {
+        check_emissivity(emissivity)?;
+        self.emissivity = emissivity;
+        Ok(self)
     }
 
-    fn atmospheric_interpolate(&self, val1: f64, val2: f64) -> f64 {
-        self.atmospheric_transmission_x * val1 + (1. - self.atmospheric_transmission_x) * val2
+    /// Override the relative humidity (as a percentage in
+    /// `[0, 100]`).
+    ///
+    /// An [`AtmosphericModel`] built from the settings
+    /// earlier does not see the new value: build it again.
+    pub fn with_relative_humidity(mut self, humidity: f64) -> Result<Self> {
+        check_relative_humidity(humidity)?;
+        self.relative_humidity_percentage = humidity;
+        Ok(self)
     }
 
-    /// Construct a transform to compute adjusted sensor values from the raw sensor values.
-    pub fn raw_transform(&self, distance: f64) -> impl Fn(f64) -> f64 {
-        // This is step to step port of the R code
+    /// Ignore the IR window: the window emission and
+    /// reflection terms are dropped from
+    /// [`raw_transform`][ThermalSettings::raw_transform], as if
+    /// the window transmission were `1`.
+    ///
+    /// Use this for images taken without an external IR
+    /// window, when the recorded window parameters are not
+    /// trustworthy (eg. a transmission slightly below `1`, or
+    /// an arbitrary window temperature), which would otherwise
+    /// bias the temperatures.
+    ///
+    /// An [`AtmosphericModel`] built from the settings
+    /// earlier does not see the change: build it again.
+    pub fn no_window(mut self) -> Self {
+        self.no_window = true;
+        self
+    }
 
-        //   emiss.wind<-1-IRT
-        let emiss_wind = 1. - self.ir_window_transmission;
+    /// Override the coefficients of the water vapour
+    /// pressure model (see [`DEFAULT_WATER_VAPOR_SERIES`]),
+    /// eg. with a fit for high altitude conditions. The model
+    /// must give the pressure in mmHg.
+    ///
+    /// An [`AtmosphericModel`] built from the settings
+    /// earlier does not see the new value: build it again.
+    pub fn with_water_vapor_series(mut self, coeffs: [f64; 4]) -> Result<Self> {
+        check_water_vapor_series(&coeffs)?;
+        self.water_vapor_series = coeffs;
+        Ok(self)
+    }
 
-        //   refl.wind<-0 # anti-reflective coating on window
-        let refl_wind = 0.;
+    /// Override the reflected apparent temperature (in
+    /// celicius).
+    ///
+    /// An [`AtmosphericModel`] built from the settings
+    /// earlier does not see the new value: build it again.
+    pub fn with_reflected_temperature(mut self, temp: f64) -> Result<Self> {
+        check_temperature(temp)?;
+        self.reflected_apparent_temperature = temp;
+        Ok(self)
+    }
 
-        // ############ transmission through the air
-        //   h2o<-(RH/100)*exp(1.5587+0.06939*(ATemp)-0.00027816*(ATemp)^2+0.00000068455*(ATemp)^3)
-        //   # converts relative humidity into water vapour pressure (I think in units mmHg)
-        const ATMOSPHERIC_SERIES: [f64; 4] = [1.5587, 0.06939, -0.00027816, 0.00000068455];
-        let h2o = (self.relative_humidity_percentage / 100.)
-            * power_series_at(&ATMOSPHERIC_SERIES, self.atmospheric_temperature).exp();
+    /// The Planck constants used in the conversion.
+    pub fn planck_constants(&self) -> PlanckConstants {
+        PlanckConstants {
+            r1: self.planck_r1,
+            b: self.planck_b,
+            f: self.planck_f,
+            o: self.planck_o,
+            r2: self.planck_r2,
+        }
+    }
 
-        let h2o_sqrt = h2o.sqrt();
+    /// The parameters of the atmospheric transmission model
+    /// used in the conversion.
+    pub fn atmospheric_params(&self) -> AtmosphericParams {
+        AtmosphericParams {
+            temperature: self.atmospheric_temperature,
+            relative_humidity: self.relative_humidity_percentage,
+            alpha_1: self.atmospheric_transmission_alpha_1,
+            alpha_2: self.atmospheric_transmission_alpha_2,
+            beta_1: self.atmospheric_transmission_beta_1,
+            beta_2: self.atmospheric_transmission_beta_2,
+            x: self.atmospheric_transmission_x,
+            water_vapor_series: self.water_vapor_series,
+        }
+    }
 
-        //   tau1<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
-        //   tau2<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
-        //   # transmission through atmosphere - equations from Minkina and Dudzik's Infrared Thermography Book
-        //   # Note: for this script, we assume the thermal window is at the mid-point (OD/2) between the source
-        //   # and the camera sensor
-        let dist_factor = (distance as f64 / 2.).sqrt();
+    /// The object distance recorded by the camera, if any.
+    /// A recorded value of `0.0` is treated as unknown.
+    pub fn embedded_distance(&self) -> Option<f64> {
+        self.embedded_distance
+    }
 
-        let tau = self.atmospheric_interpolate(
-            (-dist_factor * self.atmospheric_affine1(h2o_sqrt)).exp(),
-            (-dist_factor * self.atmospheric_affine2(h2o_sqrt)).exp(),
+    /// Resolve the distance to use for the conversion. This
+    /// is the `distance` if provided; else the embedded
+    /// distance if recorded; else [`DEFAULT_DISTANCE`].
+    pub fn effective_distance(&self, distance: Option<f64>) -> f64 {
+        distance
+            .or(self.embedded_distance)
+            .unwrap_or(DEFAULT_DISTANCE)
+    }
+
+    /// Check the parameters for physically implausible
+    /// values, eg. an emissivity of `0` or a humidity of
+    /// `1000%`, that are likely from corrupt metadata. These
+    /// usually make the conversion produce infinities or
+    /// `NaN`s, but are not rejected when parsing; callers
+    /// may report the warnings, or skip the image.
+    pub fn sanity_check(&self) -> Vec<Warning> {
+        let mut warnings = vec![];
+        let mut check = |parameter, value: f64, valid: bool, expected| {
+            if !valid {
+                warnings.push(Warning {
+                    parameter,
+                    value,
+                    expected,
+                });
+            }
+        };
+
+        let e = self.emissivity;
+        check("emissivity", e, e > 0. && e <= 1., "in (0, 1]");
+        let rh = self.relative_humidity_percentage;
+        check(
+            "relative humidity",
+            rh,
+            (0. ..=100.).contains(&rh),
+            "in [0, 100]",
         );
+        let t = self.ir_window_transmission;
+        if !self.no_window {
+            check("IR window transmission", t, t > 0. && t <= 1., "in (0, 1]");
+        }
+        for &(parameter, temp) in [
+            ("reflected temperature", self.reflected_apparent_temperature),
+            ("atmospheric temperature", self.atmospheric_temperature),
+            ("IR window temperature", self.ir_window_temperature),
+        ]
+        .iter()
+        {
+            let valid = temp.is_finite() && temp > -CELICIUS_OFFSET;
+            check(parameter, temp, valid, "above absolute zero");
+        }
+        for &(parameter, value) in [
+            ("planck_r1", self.planck_r1),
+            ("planck_r2", self.planck_r2),
+            ("planck_b", self.planck_b),
+        ]
+        .iter()
+        {
+            let valid = value.is_finite() && value != 0.;
+            check(parameter, value, valid, "finite and non-zero");
+        }
+        for &(parameter, value) in [
+            ("planck_f", self.planck_f),
+            ("planck_o", self.planck_o),
+            (
+                "atmospheric_transmission_alpha_1",
+                self.atmospheric_transmission_alpha_1,
+            ),
+            (
+                "atmospheric_transmission_alpha_2",
+                self.atmospheric_transmission_alpha_2,
+            ),
+            (
+                "atmospheric_transmission_beta_1",
+                self.atmospheric_transmission_beta_1,
+            ),
+            (
+                "atmospheric_transmission_beta_2",
+                self.atmospheric_transmission_beta_2,
+            ),
+            (
+                "atmospheric_transmission_x",
+                self.atmospheric_transmission_x,
+            ),
+        ]
+        .iter()
+        {
+            check(parameter, value, value.is_finite(), "finite");
+        }
+        warnings
+    }
+
+    /// Precompute the parts of the atmospheric model that
+    /// do not depend on the distance to the object.
+    ///
+    /// [`raw_transform`][ThermalSettings::raw_transform]
+    /// builds this model on every call. If the same settings
+    /// are used at many distances, build the model once and
+    /// call
+    /// [`transform_at_distance`][AtmosphericModel::transform_at_distance]
+    /// for each distance instead. The model is a snapshot:
+    /// it does not reflect later changes to the settings.
+    ///
+    /// ```rust
+    /// # fn test_compile(settings: thermal::temperature::ThermalSettings) {
+    /// let model = settings.atmospheric_model();
+    /// for distance in [1., 10., 50.].iter() {
+    ///     let raw_t = model.transform_at_distance(*distance);
+    ///     let adjusted = raw_t(12345.);
+    /// }
+    /// # }
+    /// ```
+    pub fn atmospheric_model(&self) -> AtmosphericModel {
+        // This is step to step port of the R code
+
+        // ############ transmission through the air
+        //   h2o<-(RH/100)*exp(1.5587+0.06939*(ATemp)-0.00027816*(ATemp)^2+0.00000068455*(ATemp)^3)
+        //   # converts relative humidity into water vapour pressure (I think in units mmHg)
+        let h2o = (self.relative_humidity_percentage / 100.)
+            * power_series_at(&self.water_vapor_series, self.atmospheric_temperature).exp();
 
         //   raw.refl1<-PR1/(PR2*(exp(PB/(RTemp+273.15))-PF))-PO   # radiance reflecting off the object before the window
-        //   raw.refl1.attn<-(1-E)/E*raw.refl1   # attn = the attenuated radiance (in raw units)
-        let refl1 = self.planck_temp_to_raw(self.reflected_apparent_temperature);
-        let refl1_attn = (1. - self.emissivity) / self.emissivity * refl1;
+        //   raw.refl2<-PR1/(PR2*(exp(PB/(RTemp+273.15))-PF))-PO
+        let refl = self.planck_temp_to_raw(self.reflected_apparent_temperature);
 
         //   raw.atm1<-PR1/(PR2*(exp(PB/(ATemp+273.15))-PF))-PO # radiance from the atmosphere (before the window)
-        //   raw.atm1.attn<-(1-tau1)/E/tau1*raw.atm1 # attn = the attenuated radiance (in raw units)
-        let atm1 = self.planck_temp_to_raw(self.atmospheric_temperature);
-        let atm1_attn = (1. - tau) / tau / self.emissivity * atm1;
+        //   raw.atm2<-PR1/(PR2*(exp(PB/(ATemp+273.15))-PF))-PO
+        let atm = self.planck_temp_to_raw(self.atmospheric_temperature);
 
         //   raw.wind<-PR1/(PR2*(exp(PB/(IRWTemp+273.15))-PF))-PO
-        //   raw.wind.attn<-emiss.wind/E/tau1/IRT*raw.wind
         let wind = self.planck_temp_to_raw(self.ir_window_temperature);
-        let wind_attn = emiss_wind / self.emissivity / tau / self.ir_window_transmission * wind;
 
-        //   raw.refl2<-PR1/(PR2*(exp(PB/(RTemp+273.15))-PF))-PO
-        //   raw.refl2.attn<-refl.wind/E/tau1/IRT*raw.refl2
-        let refl2 = self.planck_temp_to_raw(self.reflected_apparent_temperature);
-        let refl2_attn = refl_wind / self.emissivity / tau / self.ir_window_transmission * refl2;
+        // Without a window, the window terms in
+        // `transform_at_distance` vanish with a transmission
+        // of `1`.
+        let ir_window_transmission = if self.no_window {
+            1.
+        } else {
+            self.ir_window_transmission
+        };
 
-        //   raw.atm2<-PR1/(PR2*(exp(PB/(ATemp+273.15))-PF))-PO
-        //   raw.atm2.attn<-(1-tau2)/E/tau1/IRT/tau2*raw.atm2
-        let atm2 = self.planck_temp_to_raw(self.atmospheric_temperature);
-        let atm2_attn =
-            (1. - tau) / self.emissivity / tau / self.ir_window_transmission / tau * atm2;
+        AtmosphericModel {
+            emissivity: self.emissivity,
+            ir_window_transmission,
+            atmospheric_transmission_alpha_1: self.atmospheric_transmission_alpha_1,
+            atmospheric_transmission_alpha_2: self.atmospheric_transmission_alpha_2,
+            atmospheric_transmission_beta_1: self.atmospheric_transmission_beta_1,
+            atmospheric_transmission_beta_2: self.atmospheric_transmission_beta_2,
+            atmospheric_transmission_x: self.atmospheric_transmission_x,
+            h2o_sqrt: h2o.sqrt(),
+            refl,
+            atm,
+            wind,
+        }
+    }
 
-        let coeffs = [
-            -atm1_attn - atm2_attn - wind_attn - refl1_attn - refl2_attn,
-            1. / self.emissivity / tau / self.ir_window_transmission / tau,
-        ];
+    /// Transmission through the atmosphere (`tau` in the
+    /// [Thermimage R library]) for an object at the given
+    /// distance. A low transmission (eg. at long distances,
+    /// or high humidity) means most of the radiance is from
+    /// the atmosphere, and the temperatures are unreliable.
+    ///
+    /// [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
+    pub fn atmospheric_transmission(&self, distance: f64) -> f64 {
+        self.atmospheric_model().atmospheric_transmission(distance)
+    }
 
-        move |raw| power_series_at(&coeffs, raw)
+    /// Construct a transform to compute adjusted sensor values from the raw sensor values.
+    pub fn raw_transform(&self, distance: f64) -> impl Fn(f64) -> f64 {
+        self.atmospheric_model().transform_at_distance(distance)
+    }
+
+    /// Construct a transform to compute the radiance of the
+    /// object from raw sensor values: the raw value after
+    /// removing the contributions of reflections, the
+    /// atmosphere and the IR window, and adjusting for the
+    /// emissivity.
+    ///
+    /// This is the same as
+    /// [`raw_transform`][ThermalSettings::raw_transform]. The
+    /// values are in the camera's raw units: offset by
+    /// `planck_o`, they are proportional to the radiance
+    /// over the spectral band of the camera. The temperature
+    /// is obtained by inverting the Planck curve on them.
+    pub fn radiance_transform(&self, distance: f64) -> impl Fn(f64) -> f64 {
+        self.raw_transform(distance)
     }
 
     /// Construct a transform to compute temperature in
@@ -186,14 +486,612 @@ impl ThermalSettings {
         }
     }
 
+    /// Construct a transform to compute an approximate
+    /// temperature in celicius, by inverting only the Planck
+    /// curve: the raw values are not adjusted for emissivity,
+    /// reflections, the atmosphere or the IR window. This is
+    /// cheaper than
+    /// [`temperature_transform`][ThermalSettings::temperature_transform],
+    /// and is meant for quick previews.
+    ///
+    /// The result is the apparent temperature of a black
+    /// body at zero distance. It matches the full model only
+    /// for an emissivity of `1` and negligible atmospheric
+    /// absorption; otherwise the error grows with the
+    /// distance, the humidity and the difference from the
+    /// reflected temperature (typically a few degrees, but
+    /// more for low emissivities). The error is smooth across
+    /// pixels, so the image still looks the same.
+    pub fn planck_only_transform(&self) -> impl Fn(f64) -> f64 + '_ {
+        move |raw| self.planck_raw_to_temp(raw)
+    }
+
+    /// Construct a transform that computes both the adjusted
+    /// sensor value (as in
+    /// [`raw_transform`][ThermalSettings::raw_transform]),
+    /// and the temperature in celicius from a raw sensor
+    /// value.
+    pub fn raw_and_temperature_transform(&self, distance: f64) -> impl Fn(f64) -> (f64, f64) + '_ {
+        let t = self.raw_transform(distance);
+        move |raw| {
+            let raw = t(raw);
+            (raw, self.planck_raw_to_temp(raw))
+        }
+    }
+
     /// Compute temperature in celicius from raw sensor values.
     pub fn raw_to_temp(&self, distance: f64, raw: f64) -> f64 {
         self.temperature_transform(distance)(raw)
     }
+
+    /// Same as [`raw_to_temp`][ThermalSettings::raw_to_temp],
+    /// but returns a [`Temperature`].
+    pub fn raw_to_temperature(&self, distance: f64, raw: f64) -> Temperature {
+        Temperature(self.raw_to_temp(distance, raw))
+    }
+
+    /// Compute temperatures in celicius from a 2-D array of
+    /// raw sensor values.
+    pub fn temperatures(&self, distance: f64, raw: &Array2<f64>) -> Array2<f64> {
+        raw.mapv(self.temperature_transform(distance))
+    }
+
+    /// Same as [`temperatures`][ThermalSettings::temperatures],
+    /// but converts the values in parallel using `rayon`.
+    /// Useful for large images; for a batch of images, it
+    /// is usually better to parallelize across images.
+    pub fn temperatures_par(&self, distance: f64, raw: &Array2<f64>) -> Array2<f64> {
+        let mut temps = raw.clone();
+        temps.par_mapv_inplace(self.temperature_transform(distance));
+        temps
+    }
+
+    /// Precompute the temperature in celicius for every
+    /// possible (16-bit) raw sensor value. Converting a
+    /// frame with [`apply_lut`] is then a table lookup per
+    /// pixel, which is faster than
+    /// [`temperatures`][ThermalSettings::temperatures] for
+    /// large frames, or many frames at the same distance.
+    pub fn build_lut(&self, distance: f64) -> Box<[f64; RAW_LUT_SIZE]> {
+        let temp_t = self.temperature_transform(distance);
+        let lut: Box<[f64]> = (0..RAW_LUT_SIZE).map(|raw| temp_t(raw as f64)).collect();
+        lut.try_into().expect("LUT has one value per raw value")
+    }
+}
+
+/// Number of possible (16-bit) raw sensor values.
+pub const RAW_LUT_SIZE: usize = 1 << 16;
+
+/// Convert raw sensor values to temperatures using a table
+/// built by [`build_lut`][ThermalSettings::build_lut]. The
+/// raw values are expected to be 16-bit integers, as parsed
+/// from images; others are truncated and clamped to that
+/// range.
+pub fn apply_lut(lut: &[f64; RAW_LUT_SIZE], raw: &Array2<f64>) -> Array2<f64> {
+    raw.mapv(|raw| lut[raw as u16 as usize])
+}
+
+/// Unit to report temperatures in. Temperatures are
+/// computed in celicius, and converted with
+/// [`convert`][TemperatureUnit::convert].
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum TemperatureUnit {
+    #[default]
+    Celsius,
+    Fahrenheit,
+    Kelvin,
+}
+
+impl TemperatureUnit {
+    /// Convert a temperature in celicius to this unit.
+    pub fn convert(self, temp: f64) -> f64 {
+        match self {
+            TemperatureUnit::Celsius => temp,
+            TemperatureUnit::Fahrenheit => temp * 1.8 + 32.,
+            TemperatureUnit::Kelvin => temp + CELICIUS_OFFSET,
+        }
+    }
+
+    /// Symbol of the unit, eg. `°C`.
+    pub fn symbol(self) -> &'static str {
+        match self {
+            TemperatureUnit::Celsius => "°C",
+            TemperatureUnit::Fahrenheit => "°F",
+            TemperatureUnit::Kelvin => "K",
+        }
+    }
+}
+
+/// A temperature in celicius.
+///
+/// The [`Display`][fmt::Display] impl shows the temperature
+/// in celicius, with one decimal unless a precision is
+/// given (eg. `{:.2}`). Use
+/// [`display`][Temperature::display] to show it in another
+/// unit.
+///
+/// ```rust
+/// use thermal::temperature::{Temperature, TemperatureUnit};
+///
+/// let temp = Temperature(21.25) + 1.;
+/// assert_eq!(temp.to_string(), "22.2 °C");
+/// assert_eq!(format!("{:.0}", temp.display(TemperatureUnit::Kelvin)), "295 K");
+/// ```
+#[derive(Serialize, Deserialize, Debug, Default, Clone, Copy, PartialEq, PartialOrd)]
+#[serde(transparent)]
+pub struct Temperature(pub f64);
+
+impl Temperature {
+    pub fn as_celsius(self) -> f64 {
+        self.0
+    }
+
+    pub fn as_fahrenheit(self) -> f64 {
+        TemperatureUnit::Fahrenheit.convert(self.0)
+    }
+
+    pub fn as_kelvin(self) -> f64 {
+        TemperatureUnit::Kelvin.convert(self.0)
+    }
+
+    /// Display the temperature in `unit`.
+    pub fn display(self, unit: TemperatureUnit) -> TemperatureDisplay {
+        TemperatureDisplay { temp: self, unit }
+    }
+}
+
+impl fmt::Display for Temperature {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.display(TemperatureUnit::Celsius).fmt(f)
+    }
+}
+
+impl From<f64> for Temperature {
+    fn from(temp: f64) -> Self {
+        Temperature(temp)
+    }
+}
+
+impl From<Temperature> for f64 {
+    fn from(temp: Temperature) -> Self {
+        temp.0
+    }
+}
+
+impl Add<f64> for Temperature {
+    type Output = Temperature;
+
+    fn add(self, delta: f64) -> Temperature {
+        Temperature(self.0 + delta)
+    }
+}
+
+impl Sub<f64> for Temperature {
+    type Output = Temperature;
+
+    fn sub(self, delta: f64) -> Temperature {
+        Temperature(self.0 - delta)
+    }
+}
+
+/// The difference between two temperatures, in celicius
+/// (or kelvin).
+impl Sub for Temperature {
+    type Output = f64;
+
+    fn sub(self, other: Temperature) -> f64 {
+        self.0 - other.0
+    }
+}
+
+impl AddAssign<f64> for Temperature {
+    fn add_assign(&mut self, delta: f64) {
+        self.0 += delta;
+    }
+}
+
+impl SubAssign<f64> for Temperature {
+    fn sub_assign(&mut self, delta: f64) {
+        self.0 -= delta;
+    }
+}
+
+/// Displays a [`Temperature`] in a unit; see
+/// [`Temperature::display`].
+#[derive(Debug, Clone, Copy)]
+pub struct TemperatureDisplay {
+    temp: Temperature,
+    unit: TemperatureUnit,
+}
+
+impl fmt::Display for TemperatureDisplay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let precision = f.precision().unwrap_or(1);
+        let value = self.unit.convert(self.temp.0);
+        write!(f, "{:.*} {}", precision, value, self.unit.symbol())
+    }
+}
+
+impl FromStr for TemperatureUnit {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        Ok(match s.to_lowercase().as_str() {
+            "c" | "celsius" => TemperatureUnit::Celsius,
+            "f" | "fahrenheit" => TemperatureUnit::Fahrenheit,
+            "k" | "kelvin" => TemperatureUnit::Kelvin,
+            _ => bail!("unknown temperature unit: {}", s),
+        })
+    }
+}
+
+/// A physically implausible parameter value found by
+/// [`ThermalSettings::sanity_check`].
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Warning {
+    /// Name of the parameter.
+    pub parameter: &'static str,
+    /// Value found in the settings.
+    pub value: f64,
+    /// Description of the expected values.
+    pub expected: &'static str,
+}
+
+impl fmt::Display for Warning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "implausible {}: {} (expected {})",
+            self.parameter, self.value, self.expected
+        )
+    }
+}
+
+/// Planck constants of the camera (`PR1`, `PB`, `PF`, `PO`
+/// and `PR2` in the [Thermimage R library]).
+///
+/// [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct PlanckConstants {
+    pub r1: f64,
+    pub b: f64,
+    pub f: f64,
+    pub o: f64,
+    pub r2: f64,
+}
+
+/// Parameters of the atmospheric transmission model (`ATemp`,
+/// `RH`, `ATA1`, `ATA2`, `ATB1`, `ATB2` and `ATX` in the
+/// [Thermimage R library]).
+///
+/// [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
+#[derive(Serialize, Debug, Clone, Copy, PartialEq)]
+pub struct AtmosphericParams {
+    /// Atmospheric temperature in celicius.
+    pub temperature: f64,
+    /// Relative humidity as a percentage.
+    pub relative_humidity: f64,
+    pub alpha_1: f64,
+    pub alpha_2: f64,
+    pub beta_1: f64,
+    pub beta_2: f64,
+    pub x: f64,
+    /// See [`DEFAULT_WATER_VAPOR_SERIES`].
+    pub water_vapor_series: [f64; 4],
+}
+
+/// Convert a sequence of frames (eg. of a radiometric
+/// video) sharing the same settings and distance to
+/// temperatures in celicius. The transform is built once,
+/// and applied to each frame.
+///
+/// ```rust
+/// # fn test_compile(
+/// #     settings: thermal::temperature::ThermalSettings,
+/// #     frames: Vec<ndarray::Array2<f64>>,
+/// # ) {
+/// use thermal::temperature::convert_frames;
+/// for temps in convert_frames(&settings, 10., &frames) {
+///     // ...
+/// }
+/// # }
+/// ```
+pub fn convert_frames<'a, I>(
+    settings: &'a ThermalSettings,
+    distance: f64,
+    frames: I,
+) -> impl Iterator<Item = Array2<f64>> + 'a
+where
+    I: IntoIterator<Item = &'a Array2<f64>>,
+    I::IntoIter: 'a,
+{
+    let temp_t = settings.temperature_transform(distance);
+    frames.into_iter().map(move |raw| raw.mapv(&temp_t))
+}
+
+/// Builder to construct [`ThermalSettings`] by hand, eg.
+/// from a calibration sheet, without an image file.
+///
+/// Parameters that are not set default to those of the
+/// `raw2temp` function of the [Thermimage R library].
+/// Temperatures are in celicius, and the relative humidity
+/// is a percentage.
+///
+/// ```rust
+/// # fn test_compile() -> anyhow::Result<()> {
+/// use thermal::temperature::ThermalSettings;
+/// let settings = ThermalSettings::builder()
+///     .emissivity(0.98)
+///     .relative_humidity(65.)
+///     .build()?;
+/// let temp = settings.raw_to_temp(1.0, 18000.);
+/// # Ok(())
+/// # }
+/// ```
+///
+/// [Thermimage R library]: //github.com/gtatters/Thermimage/blob/master/R/raw2temp.R
+#[derive(Debug)]
+pub struct ThermalSettingsBuilder {
+    settings: ThermalSettings,
+}
+
+macro_rules! builder_setters {
+    ($($(#[$meta:meta])* $name:ident => $field:ident),* $(,)?) => {
+        $(
+            $(#[$meta])*
+            pub fn $name(mut self, value: f64) -> Self {
+                self.settings.$field = value;
+                self
+            }
+        )*
+    };
+}
+
+impl ThermalSettingsBuilder {
+    builder_setters! {
+        /// Relative humidity as a percentage in `[0, 100]`.
+        relative_humidity => relative_humidity_percentage,
+        /// Emissivity of the object in `(0, 1]`.
+        emissivity => emissivity,
+        /// Reflected apparent temperature in celicius.
+        reflected_apparent_temperature => reflected_apparent_temperature,
+        /// Temperature of the IR window in celicius.
+        ir_window_temperature => ir_window_temperature,
+        /// Transmission of the IR window in `(0, 1]`.
+        ir_window_transmission => ir_window_transmission,
+        planck_r1 => planck_r1,
+        planck_b => planck_b,
+        planck_f => planck_f,
+        planck_o => planck_o,
+        planck_r2 => planck_r2,
+        /// Atmospheric temperature in celicius.
+        atmospheric_temperature => atmospheric_temperature,
+        atmospheric_transmission_alpha_1 => atmospheric_transmission_alpha_1,
+        atmospheric_transmission_alpha_2 => atmospheric_transmission_alpha_2,
+        atmospheric_transmission_beta_1 => atmospheric_transmission_beta_1,
+        atmospheric_transmission_beta_2 => atmospheric_transmission_beta_2,
+        atmospheric_transmission_x => atmospheric_transmission_x,
+    }
+
+    /// Coefficients of the water vapour pressure model; see
+    /// [`DEFAULT_WATER_VAPOR_SERIES`].
+    pub fn water_vapor_series(mut self, coeffs: [f64; 4]) -> Self {
+        self.settings.water_vapor_series = coeffs;
+        self
+    }
+
+    /// Object distance recorded along with the parameters.
+    /// See [`ThermalSettings::effective_distance`].
+    pub fn embedded_distance(mut self, value: f64) -> Self {
+        self.settings.embedded_distance = valid_distance(value);
+        self
+    }
+
+    /// Validate the parameters and construct the settings.
+    pub fn build(self) -> Result<ThermalSettings> {
+        let s = self.settings;
+        check_emissivity(s.emissivity)?;
+        check_temperature(s.reflected_apparent_temperature)?;
+        check_relative_humidity(s.relative_humidity_percentage)?;
+        ensure!(
+            s.ir_window_transmission > 0. && s.ir_window_transmission <= 1.,
+            "IR window transmission must be in (0, 1]: found {}",
+            s.ir_window_transmission
+        );
+        check_water_vapor_series(&s.water_vapor_series)?;
+        ensure!(s.planck_r2 != 0., "planck_r2 must be non-zero");
+        ensure!(s.planck_b != 0., "planck_b must be non-zero");
+        Ok(s)
+    }
+}
+
+fn check_emissivity(emissivity: f64) -> Result<()> {
+    ensure!(
+        emissivity > 0. && emissivity <= 1.,
+        "emissivity must be in (0, 1]: found {}",
+        emissivity
+    );
+    Ok(())
+}
+
+fn check_relative_humidity(humidity: f64) -> Result<()> {
+    ensure!(
+        (0. ..=100.).contains(&humidity),
+        "relative humidity must be in [0, 100]: found {}",
+        humidity
+    );
+    Ok(())
+}
+
+fn check_water_vapor_series(coeffs: &[f64; 4]) -> Result<()> {
+    ensure!(
+        coeffs.iter().all(|c| c.is_finite()),
+        "water vapour series coefficients must be finite: found {:?}",
+        coeffs
+    );
+    Ok(())
+}
+
+fn check_temperature(temp: f64) -> Result<()> {
+    ensure!(
+        temp.is_finite() && temp > -CELICIUS_OFFSET,
+        "temperature must be above absolute zero: found {}",
+        temp
+    );
+    Ok(())
+}
+
+impl Default for ThermalSettingsBuilder {
+    fn default() -> Self {
+        ThermalSettingsBuilder {
+            settings: ThermalSettings {
+                relative_humidity_percentage: 50.,
+                emissivity: 1.,
+                reflected_apparent_temperature: 20.,
+                ir_window_temperature: defaults::ir_window_temperature(),
+                ir_window_transmission: defaults::ir_window_transmission(),
+                planck_r1: 21106.77,
+                planck_b: 1501.,
+                planck_f: 1.,
+                planck_o: -7340.,
+                planck_r2: 0.012545258,
+                atmospheric_temperature: 20.,
+                atmospheric_transmission_alpha_1: defaults::atmospheric_transmission_alpha_1(),
+                atmospheric_transmission_alpha_2: defaults::atmospheric_transmission_alpha_2(),
+                atmospheric_transmission_beta_1: defaults::atmospheric_transmission_beta_1(),
+                atmospheric_transmission_beta_2: defaults::atmospheric_transmission_beta_2(),
+                atmospheric_transmission_x: defaults::atmospheric_transmission_x(),
+                water_vapor_series: DEFAULT_WATER_VAPOR_SERIES,
+                embedded_distance: None,
+                no_window: false,
+            },
+        }
+    }
+}
+
+/// The distance independent part of the atmospheric model.
+/// See [`ThermalSettings::atmospheric_model`].
+#[derive(Debug, Clone)]
+pub struct AtmosphericModel {
+    emissivity: f64,
+    ir_window_transmission: f64,
+
+    atmospheric_transmission_alpha_1: f64,
+    atmospheric_transmission_alpha_2: f64,
+    atmospheric_transmission_beta_1: f64,
+    atmospheric_transmission_beta_2: f64,
+    atmospheric_transmission_x: f64,
+
+    /// Square root of water vapour pressure.
+    h2o_sqrt: f64,
+    /// Raw radiance of reflected objects.
+    refl: f64,
+    /// Raw radiance of the atmosphere.
+    atm: f64,
+    /// Raw radiance of the IR window.
+    wind: f64,
+}
+
+impl AtmosphericModel {
+    // tau1<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))
+    //  +(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
+    fn atmospheric_affine1(&self, val: f64) -> f64 {
+        self.atmospheric_transmission_alpha_1 + self.atmospheric_transmission_beta_1 * val
+    }
+
+    fn atmospheric_affine2(&self, val: f64) -> f64 {
+        self.atmospheric_transmission_alpha_2 + self.atmospheric_transmission_beta_2 * val
+    }
+
+    /// Transmission through the atmosphere (`tau1` and
+    /// `tau2`, which are equal). The terms with a zero weight
+    /// are skipped, as `ATX` is often `0` or `1`.
+    fn transmission_at_factor(&self, dist_factor: f64) -> f64 {
+        let tau1 = || (-dist_factor * self.atmospheric_affine1(self.h2o_sqrt)).exp();
+        let tau2 = || (-dist_factor * self.atmospheric_affine2(self.h2o_sqrt)).exp();
+        let x = self.atmospheric_transmission_x;
+        if x == 1. {
+            tau1()
+        } else if x == 0. {
+            tau2()
+        } else {
+            x * tau1() + (1. - x) * tau2()
+        }
+    }
+
+    /// Transmission through the atmosphere (`tau`, in
+    /// `(0, 1]` for a valid model) for an object at the given
+    /// distance.
+    pub fn atmospheric_transmission(&self, distance: f64) -> f64 {
+        self.transmission_at_factor((distance / 2.).sqrt())
+    }
+
+    /// Construct a transform to compute adjusted sensor
+    /// values from the raw sensor values, for an object at
+    /// the given distance.
+    ///
+    /// `ATX` is not a weight in `[0, 1]` (FLIR cameras record
+    /// `1.9`), so a corrupt value may make the transmission
+    /// through the atmosphere non-positive. The transform
+    /// then returns `NaN`s.
+    pub fn transform_at_distance(&self, distance: f64) -> impl Fn(f64) -> f64 {
+        //   emiss.wind<-1-IRT
+        let emiss_wind = 1. - self.ir_window_transmission;
+
+        //   refl.wind<-0 # anti-reflective coating on window
+        let refl_wind = 0.;
+
+        //   tau1<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
+        //   tau2<-ATX*exp(-sqrt(OD/2)*(ATA1+ATB1*sqrt(h2o)))+(1-ATX)*exp(-sqrt(OD/2)*(ATA2+ATB2*sqrt(h2o)))
+        //   # transmission through atmosphere - equations from Minkina and Dudzik's Infrared Thermography Book
+        //   # Note: for this script, we assume the thermal window is at the mid-point (OD/2) between the source
+        //   # and the camera sensor
+        let dist_factor = (distance / 2.).sqrt();
+
+        let tau = self.transmission_at_factor(dist_factor);
+
+        //   raw.refl1.attn<-(1-E)/E*raw.refl1   # attn = the attenuated radiance (in raw units)
+        let refl1_attn = (1. - self.emissivity) / self.emissivity * self.refl;
+
+        //   raw.atm1.attn<-(1-tau1)/E/tau1*raw.atm1 # attn = the attenuated radiance (in raw units)
+        let atm1_attn = (1. - tau) / tau / self.emissivity * self.atm;
+
+        //   raw.wind.attn<-emiss.wind/E/tau1/IRT*raw.wind
+        let wind_attn =
+            emiss_wind / self.emissivity / tau / self.ir_window_transmission * self.wind;
+
+        //   raw.refl2.attn<-refl.wind/E/tau1/IRT*raw.refl2
+        let refl2_attn =
+            refl_wind / self.emissivity / tau / self.ir_window_transmission * self.refl;
+
+        //   raw.atm2.attn<-(1-tau2)/E/tau1/IRT/tau2*raw.atm2
+        let atm2_attn =
+            (1. - tau) / self.emissivity / tau / self.ir_window_transmission / tau * self.atm;
+
+        let coeffs = if tau > 0. {
+            [
+                -atm1_attn - atm2_attn - wind_attn - refl1_attn - refl2_attn,
+                1. / self.emissivity / tau / self.ir_window_transmission / tau,
+            ]
+        } else {
+            [f64::NAN; 2]
+        };
+
+        move |raw| power_series_at(&coeffs, raw)
+    }
 }
 
 impl From<FlirCameraParams> for ThermalSettings {
     fn from(params: FlirCameraParams) -> Self {
+        (&params).into()
+    }
+}
+
+/// Same as the conversion from `FlirCameraParams`, but keeps
+/// the params (eg. for the camera info).
+impl From<&FlirCameraParams> for ThermalSettings {
+    fn from(params: &FlirCameraParams) -> Self {
         let FlirCameraParams {
             temperature_params,
             extra_params,
@@ -224,10 +1122,23 @@ impl From<FlirCameraParams> for ThermalSettings {
             atmospheric_transmission_beta_2: temperature_params.atmospheric_transmission_beta_2
                 as f64,
             atmospheric_transmission_x: temperature_params.atmospheric_transmission_x as f64,
+            water_vapor_series: DEFAULT_WATER_VAPOR_SERIES,
+            embedded_distance: valid_distance(temperature_params.object_distance as f64),
+            no_window: false,
         }
     }
 }
 
+/// Cameras record `0.0` (or garbage) when the distance is
+/// not known.
+pub(crate) fn valid_distance(distance: f64) -> Option<f64> {
+    if distance.is_finite() && distance > 0. {
+        Some(distance)
+    } else {
+        None
+    }
+}
+
 #[inline]
 fn power_series_at(coeffs: &[f64], x: f64) -> f64 {
     let mut pow = 1.;
@@ -239,27 +1150,454 @@ fn power_series_at(coeffs: &[f64], x: f64) -> f64 {
     sum
 }
 
-mod serde_helpers {
+/// Defaults for the parameters that are not recorded by all
+/// cameras (or not output by all exiftool versions). These
+/// are the defaults of `raw2temp` in the Thermimage R
+/// library; exiftool itself has no fallbacks, and simply
+/// omits tags missing in the file.
+///
+/// A window transmission of `1` means there is no window,
+/// so its temperature is then irrelevant. The atmospheric
+/// coefficients are the typical values recorded by FLIR
+/// cameras.
+mod defaults {
+    pub fn ir_window_temperature() -> f64 {
+        20.
+    }
+    pub fn ir_window_transmission() -> f64 {
+        1.
+    }
+    pub fn atmospheric_transmission_alpha_1() -> f64 {
+        0.006569
+    }
+    pub fn atmospheric_transmission_alpha_2() -> f64 {
+        0.01262
+    }
+    pub fn atmospheric_transmission_beta_1() -> f64 {
+        -0.002276
+    }
+    pub fn atmospheric_transmission_beta_2() -> f64 {
+        -0.00667
+    }
+    pub fn atmospheric_transmission_x() -> f64 {
+        1.9
+    }
+    pub fn water_vapor_series() -> [f64; 4] {
+        super::DEFAULT_WATER_VAPOR_SERIES
+    }
+}
+
+pub(crate) mod serde_helpers {
     use lazy_static::lazy_static;
     use regex::Regex;
     use serde::*;
     lazy_static! {
-        static ref RE: Regex = Regex::new(r"^\d*.\d*").unwrap();
+        static ref RE: Regex = Regex::new(r"^-?\d*\.?\d+").unwrap();
     }
 
+    /// Accepts a float, or a string that begins with a float
+    /// (eg. `"20.0 C"` or `"-5 C"` as output by exiftool).
+    /// Non human-readable formats (eg. bincode) only contain
+    /// the floats written by `Serialize`.
     pub fn float_with_suffix<'de, D>(de: D) -> Result<f64, D::Error>
     where
         D: Deserializer<'de>,
     {
-        use serde::de::Error;
-        let str_rep = <String as Deserialize>::deserialize(de)?;
-        let val = RE
-            .find(&str_rep)
-            .ok_or(Error::custom("unexpected format: must begin with float"))?
-            .as_str()
-            .parse()
-            .map_err(Error::custom)?;
-
-        Ok(val)
+        if !de.is_human_readable() {
+            return f64::deserialize(de);
+        }
+        de.deserialize_any(FloatWithSuffix)?
+            .ok_or_else(|| <D::Error as de::Error>::custom("unexpected null: expected a float"))
+    }
+
+    pub fn distance_with_suffix<'de, D>(de: D) -> Result<Option<f64>, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        let val = if de.is_human_readable() {
+            de.deserialize_any(FloatWithSuffix)?
+        } else {
+            Option::<f64>::deserialize(de)?
+        };
+        Ok(val.and_then(super::valid_distance))
+    }
+
+    struct FloatWithSuffix;
+
+    impl<'de> de::Visitor<'de> for FloatWithSuffix {
+        type Value = Option<f64>;
+
+        fn expecting(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "a float, or a string beginning with a float")
+        }
+
+        fn visit_str<E: de::Error>(self, v: &str) -> Result<Self::Value, E> {
+            let val = RE
+                .find(v)
+                .ok_or_else(|| E::custom("unexpected format: must begin with float"))?
+                .as_str()
+                .parse()
+                .map_err(E::custom)?;
+            Ok(Some(val))
+        }
+
+        fn visit_f64<E: de::Error>(self, v: f64) -> Result<Self::Value, E> {
+            Ok(Some(v))
+        }
+
+        fn visit_i64<E: de::Error>(self, v: i64) -> Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_u64<E: de::Error>(self, v: u64) -> Result<Self::Value, E> {
+            Ok(Some(v as f64))
+        }
+
+        fn visit_unit<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_none<E: de::Error>(self) -> Result<Self::Value, E> {
+            Ok(None)
+        }
+
+        fn visit_some<D: Deserializer<'de>>(self, de: D) -> Result<Self::Value, D::Error> {
+            de.deserialize_any(self)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{
+        apply_lut, power_series_at, Temperature, TemperatureUnit, ThermalSettings,
+        DEFAULT_WATER_VAPOR_SERIES,
+    };
+    use crate::stats::Stats;
+
+    #[test]
+    fn builder_validates_ranges() {
+        assert!(ThermalSettings::builder().build().is_ok());
+        assert!(ThermalSettings::builder().emissivity(0.).build().is_err());
+        assert!(ThermalSettings::builder().emissivity(1.2).build().is_err());
+        assert!(ThermalSettings::builder()
+            .relative_humidity(101.)
+            .build()
+            .is_err());
+        assert!(ThermalSettings::builder().planck_r2(0.).build().is_err());
+        assert!(ThermalSettings::builder()
+            .ir_window_transmission(0.)
+            .build()
+            .is_err());
+    }
+
+    #[test]
+    fn invalid_raw_values_are_nan() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        let temp = settings.try_planck_raw_to_temp(18000.).unwrap();
+
+        // A dead pixel: below the offset (PO = -7340).
+        assert_eq!(settings.try_planck_raw_to_temp(0.), None);
+        assert!(settings.raw_to_temp(1.0, 0.).is_nan());
+
+        // A saturated pixel: pinned at the top of the range,
+        // but still a (clipped) temperature.
+        let saturated = u16::MAX as f64;
+        let saturated_temp = settings.try_planck_raw_to_temp(saturated).unwrap();
+        assert!(saturated_temp.is_finite() && saturated_temp > temp);
+        assert!(settings.raw_to_temp(1.0, saturated).is_finite());
+
+        let mut stats = Stats::default();
+        stats += settings.raw_to_temp(1.0, 0.);
+        stats += settings.raw_to_temp(1.0, 18000.);
+        stats += settings.raw_to_temp(1.0, saturated);
+        assert_eq!(stats.count(), 2.);
+        assert!(stats.mean().is_finite());
+    }
+
+    #[test]
+    fn overrides_validate_ranges() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        let raw = 18000.;
+        let temp = settings.raw_to_temp(1.0, raw);
+
+        let settings = settings.with_emissivity(0.95).unwrap();
+        assert!(settings.raw_to_temp(1.0, raw) != temp);
+        assert!(settings.with_emissivity(1.5).is_err());
+
+        let settings = ThermalSettings::builder().build().unwrap();
+        assert!(settings.with_reflected_temperature(-300.).is_err());
+        let settings = ThermalSettings::builder().build().unwrap();
+        assert!(settings.with_reflected_temperature(35.).is_ok());
+    }
+
+    #[test]
+    fn lut_matches_transform() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        let raw = ndarray::array![[0., 12000.], [17000.5, 65535.]];
+        let lut = settings.build_lut(5.);
+        let temps = apply_lut(&lut, &raw);
+        let expected = settings.temperatures(5., &raw.mapv(f64::trunc));
+        for (t, e) in temps.iter().zip(expected.iter()) {
+            assert!(t == e || (t.is_nan() && e.is_nan()));
+        }
+    }
+
+    #[test]
+    fn no_window() {
+        let settings = ThermalSettings::builder()
+            .ir_window_transmission(0.98)
+            .ir_window_temperature(60.)
+            .build()
+            .unwrap();
+        let without_window = ThermalSettings::builder().build().unwrap();
+
+        let raw = 17000.;
+        let biased = settings.raw_to_temp(5., raw);
+        let expected = without_window.raw_to_temp(5., raw);
+        assert!((biased - expected).abs() > 0.1);
+        assert_eq!(settings.no_window().raw_to_temp(5., raw), expected);
+
+        // No change if the window transmission is already 1.
+        assert_eq!(
+            without_window.clone().no_window().raw_to_temp(5., raw),
+            expected
+        );
+    }
+
+    /// Reference temperatures computed with
+    /// `scripts/raw2temp-reference.py`: a line by line
+    /// transcription of `raw2temp` from the Thermimage R
+    /// library, independent of the code here.
+    #[test]
+    fn matches_thermimage_reference() {
+        fn check(settings: &ThermalSettings, distance: f64, expected: &[(f64, f64)]) {
+            for &(raw, temp) in expected {
+                let computed = settings.raw_to_temp(distance, raw);
+                assert!(
+                    (computed - temp).abs() < 1e-4,
+                    "raw {}: expected {}, got {}",
+                    raw,
+                    temp,
+                    computed
+                );
+            }
+        }
+
+        // The defaults of `raw2temp`.
+        let settings = ThermalSettings::builder().build().unwrap();
+        check(
+            &settings,
+            1.,
+            &[
+                (12000., -18.823243),
+                (15000., 4.849754),
+                (17000., 17.395805),
+                (18109., 23.654941),
+                (20000., 33.468325),
+                (25000., 55.763463),
+            ],
+        );
+
+        // All attenuation terms in play.
+        let settings = ThermalSettings::builder()
+            .emissivity(0.95)
+            .reflected_apparent_temperature(-5.)
+            .atmospheric_temperature(25.)
+            .ir_window_temperature(30.)
+            .ir_window_transmission(0.9)
+            .relative_humidity(70.)
+            .planck_r1(17096.453)
+            .planck_b(1428.)
+            .planck_f(1.)
+            .planck_o(-321.)
+            .planck_r2(0.046642166)
+            .build()
+            .unwrap();
+        check(
+            &settings,
+            10.,
+            &[
+                (2000., -16.214042),
+                (5000., 59.179384),
+                (8000., 106.16013),
+                (12000., 155.130259),
+                (16000., 196.452904),
+            ],
+        );
+    }
+
+    #[test]
+    fn float_with_suffix() {
+        #[derive(serde_derive::Deserialize)]
+        struct Value(#[serde(deserialize_with = "super::serde_helpers::float_with_suffix")] f64);
+
+        let parse = |s: &str| serde_json::from_str::<Value>(s).map(|v| v.0).ok();
+        assert_eq!(parse(r#""20.0 C""#), Some(20.));
+        assert_eq!(parse(r#""20 C""#), Some(20.));
+        assert_eq!(parse(r#""-5.0 C""#), Some(-5.));
+        assert_eq!(parse(r#""-12 C""#), Some(-12.));
+        assert_eq!(parse(r#""0.5""#), Some(0.5));
+        assert_eq!(parse("-3.5"), Some(-3.5));
+        assert_eq!(parse(r#""C""#), None);
+        assert_eq!(parse(r#""-""#), None);
+    }
+
+    #[test]
+    fn optional_fields_default() {
+        let json = r#"{
+            "RelativeHumidity": "50.0 %",
+            "Emissivity": 0.95,
+            "ReflectedApparentTemperature": "20.0 C",
+            "PlanckR1": 21106.77,
+            "PlanckB": 1501,
+            "PlanckF": 1,
+            "PlanckO": -7340,
+            "PlanckR2": 0.012545258,
+            "AtmosphericTemperature": "20.0 C"
+        }"#;
+        let settings: ThermalSettings = serde_json::from_str(json).unwrap();
+        let expected = ThermalSettings::builder().emissivity(0.95).build().unwrap();
+        assert_eq!(
+            settings.raw_to_temp(5., 17000.),
+            expected.raw_to_temp(5., 17000.)
+        );
+    }
+
+    #[test]
+    fn planck_only() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        let full = settings.temperature_transform(0.);
+        let planck = settings.planck_only_transform();
+        assert!((full(17000.) - planck(17000.)).abs() < 1e-9);
+
+        let other = settings.clone().with_emissivity(0.5).unwrap();
+        let full = other.temperature_transform(10.);
+        assert!((full(17000.) - planck(17000.)).abs() > 1.);
+    }
+
+    #[test]
+    fn atmospheric_transmission_x() {
+        let settings = ThermalSettings::builder()
+            .atmospheric_transmission_alpha_1(0.01)
+            .atmospheric_transmission_alpha_2(0.02)
+            .atmospheric_transmission_beta_1(-0.002)
+            .atmospheric_transmission_beta_2(-0.003)
+            .build()
+            .unwrap();
+        let model = |x: f64| {
+            let mut settings = settings.clone();
+            settings.atmospheric_transmission_x = x;
+            settings.atmospheric_model()
+        };
+        let general = |x: f64, dist_factor: f64| {
+            let m = model(0.5);
+            x * (-dist_factor * m.atmospheric_affine1(m.h2o_sqrt)).exp()
+                + (1. - x) * (-dist_factor * m.atmospheric_affine2(m.h2o_sqrt)).exp()
+        };
+        for &x in [0., 0.3, 1., 1.9].iter() {
+            let tau = model(x).transmission_at_factor(5.);
+            assert!((tau - general(x, 5.)).abs() < 1e-12);
+        }
+
+        // A non-positive transmission gives NaNs.
+        let corrupt = ThermalSettings::builder()
+            .atmospheric_transmission_alpha_1(1.)
+            .atmospheric_transmission_alpha_2(0.)
+            .atmospheric_transmission_beta_1(0.)
+            .atmospheric_transmission_beta_2(0.)
+            .atmospheric_transmission_x(10.)
+            .build()
+            .unwrap();
+        assert!(corrupt.raw_to_temp(50., 17000.).is_nan());
+    }
+
+    #[test]
+    fn atmospheric_transmission() {
+        let settings = ThermalSettings::builder()
+            .relative_humidity(80.)
+            .build()
+            .unwrap();
+        assert_eq!(settings.atmospheric_transmission(0.), 1.);
+        let (near, far) = (
+            settings.atmospheric_transmission(10.),
+            settings.atmospheric_transmission(1000.),
+        );
+        assert!(0. < far && far < near && near < 1.);
+    }
+
+    #[test]
+    fn radiance() {
+        let settings = ThermalSettings::builder().emissivity(0.9).build().unwrap();
+        let radiance_t = settings.radiance_transform(10.);
+        let temp_t = settings.temperature_transform(10.);
+        for &raw in [15000., 17000., 19000.].iter() {
+            let radiance = radiance_t(raw);
+            assert_eq!(radiance, settings.raw_transform(10.)(raw));
+            let expected = settings.planck_temp_to_raw(temp_t(raw));
+            assert!((radiance - expected).abs() < 1e-6);
+        }
+    }
+
+    #[test]
+    fn temperature_display() {
+        let temp = Temperature(100.);
+        assert_eq!(temp.as_fahrenheit(), 212.);
+        assert_eq!((temp - 0.5).to_string(), "99.5 °C");
+        assert_eq!(
+            format!("{:.2}", temp.display(TemperatureUnit::Fahrenheit)),
+            "212.00 °F"
+        );
+        assert_eq!(temp - Temperature(20.), 80.);
+    }
+
+    #[test]
+    fn sanity_check() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        assert!(settings.sanity_check().is_empty());
+
+        let mut corrupt = settings;
+        corrupt.emissivity = 0.;
+        corrupt.relative_humidity_percentage = 1000.;
+        corrupt.planck_r2 = 0.;
+        let params: Vec<_> = corrupt.sanity_check().iter().map(|w| w.parameter).collect();
+        assert_eq!(params, ["emissivity", "relative humidity", "planck_r2"]);
+    }
+
+    #[test]
+    fn water_vapor_series() {
+        let settings = ThermalSettings::builder().build().unwrap();
+        let temp = settings.raw_to_temp(50., 17000.);
+
+        // A constant series evaluating to the same pressure
+        // at the (default) atmospheric temperature of 20 C.
+        let c0 = power_series_at(&DEFAULT_WATER_VAPOR_SERIES, 20.);
+        let same = settings
+            .clone()
+            .with_water_vapor_series([c0, 0., 0., 0.])
+            .unwrap();
+        assert!((same.raw_to_temp(50., 17000.) - temp).abs() < 1e-9);
+
+        let drier = settings
+            .clone()
+            .with_water_vapor_series([0., 0., 0., 0.])
+            .unwrap();
+        assert_ne!(drier.raw_to_temp(50., 17000.), temp);
+        assert!(settings
+            .with_water_vapor_series([f64::NAN, 0., 0., 0.])
+            .is_err());
+    }
+
+    #[test]
+    fn temperature_units() {
+        assert_eq!(TemperatureUnit::Celsius.convert(20.), 20.);
+        assert_eq!(TemperatureUnit::Fahrenheit.convert(-40.), -40.);
+        assert_eq!(TemperatureUnit::Fahrenheit.convert(100.), 212.);
+        assert_eq!(TemperatureUnit::Kelvin.convert(0.), 273.15);
+        assert_eq!(
+            "K".parse::<TemperatureUnit>().unwrap(),
+            TemperatureUnit::Kelvin
+        );
+        assert!("x".parse::<TemperatureUnit>().is_err());
     }
 }
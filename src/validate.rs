@@ -0,0 +1,44 @@
+//! Check the accuracy of the temperature conversion against
+//! the DJI Thermal SDK.
+//!
+//! DJI R-JPEGs also carry FLIR compatible records, so the
+//! same file can be converted both by this crate (as a FLIR
+//! image) and by the SDK. The differences quantify how well
+//! the conversion matches that of DJI for a dataset.
+//!
+//! Requires the `dji` feature.
+use anyhow::{ensure, Context, Result};
+use img_parts::jpeg::Jpeg;
+use ndarray::Zip;
+use std::{fs::read, path::Path};
+
+use crate::{dji::RJpeg, image::Dimensions, stats::Stats, ThermalImage};
+
+/// Parse the R-JPEG at `path` both as a FLIR image and with
+/// the DJI SDK, and return the stats of the per-pixel
+/// absolute difference (in celicius) between the two
+/// conversions. The FLIR conversion uses the distance of the
+/// DJI measurement params, so that both use the same
+/// parameters.
+pub fn compare_flir_dji<P: AsRef<Path>>(path: P) -> Result<Stats> {
+    let bytes = read(path)?;
+    let thermal = ThermalImage::try_from_rjpeg(&Jpeg::from_bytes(bytes.clone().into())?)
+        .context("flir parsing failed")?;
+    let rjpeg = RJpeg::try_from_bytes(bytes).context("dji rjpeg parsing failed")?;
+
+    let t_dji = rjpeg.temperatures()?;
+    let dims = thermal.dimensions();
+    ensure!(
+        dims == Dimensions::from_shape(t_dji.dim()),
+        "dimensions differ: {:?} (flir) vs {:?} (dji)",
+        dims,
+        rjpeg.dimensions()?
+    );
+
+    let temp_t = thermal.settings.temperature_transform(rjpeg.distance()?);
+    let mut stats = Stats::default();
+    Zip::from(&thermal.image)
+        .and(&t_dji)
+        .for_each(|&raw, &dji| stats += (temp_t(raw) - dji as f64).abs());
+    Ok(stats)
+}